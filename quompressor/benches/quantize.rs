@@ -0,0 +1,55 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Nearest-palette-color lookup on the kitchen example, with a linear scan
+// and with the k-d tree. Run with `cargo bench --bench quantize`.
+//
+// The image is blurred the way `from_image` does by default, which is where
+// most of the unique colors to look up come from.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use quompressor::node::quantization;
+use quompressor::{ColorMetric, DynamicPaletteView};
+
+const KITCHEN: &str = "examples/kitchen-2048x2048-python-ex1.png";
+
+fn nearest_color(c: &mut Criterion) {
+	let img = image::imageops::blur(
+		&image::open(KITCHEN).expect("kitchen example not found").into_rgba8(),
+		1.
+	);
+	let mut group = c.benchmark_group("nearest_color");
+	group.sample_size(10);
+	for metric in [ColorMetric::Srgb, ColorMetric::OkLab] {
+		let generator = quantization::PaletteAlgorithm::MedianCut.generator(0, Some(256), metric, Default::default());
+		let palette: DynamicPaletteView = quantization::generate_palette(&img, generator.as_ref(), Default::default());
+		// Both searches must agree before their speed is worth comparing.
+		let mut linear = quantization::PaletteLookup::linear(&palette, metric);
+		let mut indexed = quantization::PaletteLookup::new(&palette, metric);
+		assert!(img.pixels().all(|pix| linear.nearest(pix) == indexed.nearest(pix)));
+		group.bench_function(format!("linear/{:?}", metric), |b| b.iter(|| {
+			let mut lookup = quantization::PaletteLookup::linear(&palette, metric);
+			img.pixels().map(|pix| lookup.nearest(pix)).collect::<Vec<_>>()
+		}));
+		group.bench_function(format!("kdtree/{:?}", metric), |b| b.iter(|| {
+			let mut lookup = quantization::PaletteLookup::new(&palette, metric);
+			img.pixels().map(|pix| lookup.nearest(pix)).collect::<Vec<_>>()
+		}));
+	}
+	group.finish();
+}
+
+criterion_group!(benches, nearest_color);
+criterion_main!(benches);
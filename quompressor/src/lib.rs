@@ -15,26 +15,38 @@
 extern crate image;
 use image::error::ImageError;
 
-use node::error::AnalyzeError;
-use node::error::DrawError;
-use pyo3::types::PyBool;
-
-
-mod node;
+pub mod node;
+#[cfg(feature = "python")]
+mod python;
 
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Write};
 
 use node::*;
 
-use quantization::palette::{DynamicPaletteView};
+// The public API: the quadtree, its palettes, the settings it is encoded
+// with and the errors of the lower level functions. The modules of `node`
+// hold the rest.
+pub use node::QuadtreeNode;
+pub use node::qim::{embedded_palette, QimFlags};
+pub use node::quality::QualityFloor;
+pub use node::edges::EdgeMap;
+pub use node::filter::PreFilter;
+pub use node::refit::Refit;
+pub use node::split::{ErrorLimit, SplitCriterion};
+pub use node::trim::{Survivor, TrimPolicy};
+pub use node::error::{AnalyzeError, DecodeError, DrawError, EncodeError, UnknownOption};
 
-use pyo3::prelude::*;
-use pyo3::types::PyLong;
-use pyo3::types::PyFloat;
-use pyo3::exceptions::PyRuntimeError;
+pub use quantization::{PaletteAlgorithm, QuantizeOptions};
+pub use quantization::dither::Dither;
+pub use quantization::gamma::Blending;
+pub use quantization::metric::ColorMetric;
+pub use quantization::alpha::AlphaMode;
+pub use quantization::builtin::BuiltinPalette;
+pub use quantization::palette::{Color, DynamicPaletteView, Palette};
+pub use quantization::palette_file::PaletteFormat;
 
 #[derive(Debug)]
 pub struct ImageLoadGenericError; // ImageError type not recognized
@@ -126,159 +138,734 @@ impl fmt::Display for QIMFileWriteError {
     }
 }
 
-pub struct TreeWithPalette {
-	tree: node::QuadtreeNode<DynamicPaletteView>,
-	palette: DynamicPaletteView
+#[derive(Debug)]
+pub struct PaletteFileOpenInputError;
+
+impl Error for PaletteFileOpenInputError {}
+
+impl fmt::Display for PaletteFileOpenInputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not open input palette file")
+    }
 }
 
-/// Lib
-// DONE
-pub fn generate_quadtree(
-	path: &str,
-	dedup: u32,
-	blur: f32,
-	sensitivity: usize,
-	trim: usize
-) -> Result<TreeWithPalette, Box<dyn Error + 'static>> {
-	let source = match image::open(path) {
-		Ok(i) => i,
+#[derive(Debug)]
+pub struct PaletteFileWriteError;
+
+impl Error for PaletteFileWriteError {}
+
+impl fmt::Display for PaletteFileWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not write palette file")
+    }
+}
+
+#[derive(Debug)]
+pub struct QIMReferenceWithoutPaletteFileError;
+
+impl Error for QIMReferenceWithoutPaletteFileError {}
+
+impl fmt::Display for QIMReferenceWithoutPaletteFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "A QIM file can only refer to a palette taken from a palette file")
+    }
+}
+
+#[derive(Debug)]
+pub struct PaletteSourceConflictError;
+
+impl Error for PaletteSourceConflictError {}
+
+impl fmt::Display for PaletteSourceConflictError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "A palette file and a built-in palette can not be used together")
+    }
+}
+
+#[derive(Debug)]
+pub struct OutputSizeError;
+
+impl Error for OutputSizeError {}
+
+impl fmt::Display for OutputSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The output image must be at least one pixel wide and high")
+    }
+}
+
+#[derive(Debug)]
+pub struct RgbaBufferSizeError;
+
+impl Error for RgbaBufferSizeError {}
+
+impl fmt::Display for RgbaBufferSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The RGBA buffer does not hold 4 bytes for each pixel of the given dimensions")
+    }
+}
+
+#[derive(Debug)]
+pub struct TargetConflictError;
+
+impl Error for TargetConflictError {}
+
+impl fmt::Display for TargetConflictError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Only one of a target size, a target quality and pruning can be used")
+    }
+}
+
+#[derive(Debug)]
+pub struct PruneLambdaError;
+
+impl Error for PruneLambdaError {}
+
+impl fmt::Display for PruneLambdaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The pruning lambda must be a number of at least 0")
+    }
+}
+
+pub struct TreeWithPalette<P: Palette = DynamicPaletteView> {
+	tree: node::QuadtreeNode<P>,
+	palette: P,
+	// Criterion the tree was actually mounted with.
+	split: SplitCriterion,
+	// Quality reached, when searching for a `target_quality`.
+	quality: Option<f64>
+}
+
+impl<P: Palette> TreeWithPalette<P> {
+	pub fn tree(&self) -> &node::QuadtreeNode<P> {
+		&self.tree
+	}
+
+	pub fn palette(&self) -> &P {
+		&self.palette
+	}
+
+	pub fn into_parts(self) -> (node::QuadtreeNode<P>, P) {
+		(self.tree, self.palette)
+	}
+}
+
+// A quadtree encoded in memory, by `encode_image` and the like.
+pub struct EncodedImage<P: Palette = DynamicPaletteView> {
+	pub tree: node::QuadtreeNode<P>,
+	pub palette: P,
+	// The QIM data of the tree.
+	pub qim: Vec<u8>,
+	pub report: EncodeReport,
+}
+
+// What an encoding ended up using, for `im2qim_report` and `im2im_report`.
+#[derive(Clone, Copy, Debug)]
+pub struct EncodeReport {
+	// Split criterion of the quadtree; when searching for a
+	// `target_size` or `target_quality`, the one that was settled on.
+	pub split: SplitCriterion,
+	// Size of the QIM output in bytes.
+	pub size: usize,
+	// Quality reached against the source, in the measure of
+	// `target_quality`, when one is set.
+	pub quality: Option<f64>,
+}
+
+// Settings for turning an image into a quadtree.
+//
+// The defaults match the ones of the CLI and of the Python `compress` function.
+#[derive(Clone, Debug)]
+pub struct EncodeOptions {
+	// Color distance threshold for palette deduplication (`PaletteAlgorithm::Dedup` only).
+	pub dedup: u32,
+	// Amount of Gaussian blur applied before quantization. Unless set, 1, or
+	// 0 with a `prefilter`, which stands in for it (see `blur_amount`).
+	pub blur: Option<f32>,
+	// Edge-preserving noise filter applied before palette generation and
+	// quantization.
+	pub prefilter: PreFilter,
+	// Fraction (out of 16384) of a square that must share a color to stop
+	// splitting it. Unless set, 16128, or 16384 with `prune`.
+	pub sensitivity: Option<usize>,
+	// Color error, measured with `metric`, a square may have before it is
+	// split; takes precedence over `sensitivity`.
+	pub max_error: Option<ErrorLimit>,
+	// How much more readily squares along edges of the source are split
+	// (see `EdgeMap`); 0 treats them like any other.
+	pub edge_weight: f32,
+	// Statistic of the source pixels whose nearest palette color each leaf
+	// of the finished tree is given, instead of its most common one (see
+	// `QuadtreeNode::refit`).
+	pub refit: Option<Refit>,
+	// Policy the finished tree is trimmed with, if any (see
+	// `QuadtreeNode::trim`). Its `metric` and `blending` are used as given.
+	pub trim: Option<TrimPolicy>,
+	// Byte budget for the QIM output. When set, the finest split setting of
+	// the same kind as `sensitivity` or `max_error` that fits is searched for
+	// (see `QuadtreeNode::mount_within`); the other settings are kept.
+	pub target_size: Option<usize>,
+	// Lowest quality the output may have. When set, the coarsest split
+	// setting of the same kind as `sensitivity` or `max_error` that reaches
+	// it is searched for (see `QuadtreeNode::mount_to_quality`), giving the
+	// smallest output. Exclusive with `target_size`.
+	pub target_quality: Option<QualityFloor>,
+	// Lagrangian multiplier for a rate-distortion pruning pass after `trim`
+	// (see `QuadtreeNode::prune`), at least 0; meant to be paired with a
+	// `sensitivity` of 16384, its default, so that it is the only knob.
+	// Exclusive with the targets.
+	pub prune: Option<f64>,
+	// Strategy used to pick the palette colors.
+	pub palette_algo: PaletteAlgorithm,
+	// Upper bound on the number of palette colors; the least used colors
+	// are merged into the others to fit.
+	pub max_colors: Option<usize>,
+	// How color differences are measured, both when building the palette and
+	// when mapping pixels onto it.
+	pub metric: ColorMetric,
+	// Dithering applied when mapping pixels onto the palette.
+	pub dither: Dither,
+	// How pixel alpha is treated, both when building the palette and when
+	// mapping pixels onto it.
+	pub alpha: AlphaMode,
+	// Space in which palette colors are averaged and gradients interpolated;
+	// QIM output records it for rendering.
+	pub blending: Blending,
+	// Palette file to encode against, instead of generating a palette
+	// (in which case the palette generation settings above are ignored).
+	pub palette_file: Option<String>,
+	// Whether QIM output refers to `palette_file` by hash instead of
+	// embedding the palette.
+	pub reference_palette: bool,
+	// Standard palette to encode against, instead of generating a palette.
+	// Exclusive with `palette_file`.
+	pub builtin_palette: Option<BuiltinPalette>,
+}
+
+impl Default for EncodeOptions {
+	fn default() -> Self {
+		EncodeOptions {
+			dedup: 256,
+			blur: None,
+			prefilter: PreFilter::None,
+			sensitivity: None,
+			max_error: None,
+			edge_weight: 0.,
+			refit: None,
+			trim: None,
+			target_size: None,
+			target_quality: None,
+			prune: None,
+			palette_algo: PaletteAlgorithm::Dedup,
+			max_colors: None,
+			metric: ColorMetric::Srgb,
+			dither: Dither::None,
+			alpha: AlphaMode::Full,
+			blending: Blending::Srgb,
+			palette_file: None,
+			reference_palette: false,
+			builtin_palette: None,
+		}
+	}
+}
+
+impl EncodeOptions {
+	// The amount of blur encoding applies, `blur` resolved to its default.
+	pub fn blur_amount(&self) -> f32 {
+		self.blur.unwrap_or(if self.prefilter == PreFilter::None { 1. } else { 0. })
+	}
+}
+
+fn open_image(path: &str) -> Result<image::RgbaImage, Box<dyn Error + 'static>> {
+	match image::open(path) {
+		Ok(i) => Ok(i.into_rgba8()),
 		Err(e) => {
 			match e {
-				ImageError::Decoding(_) => {
-					return Err(ImageLoadDecodingError.into());
-				},
-				ImageError::Limits(_) => {
-					return Err(ImageLoadLimitsError.into());
-				},
-				ImageError::IoError(_) => {
-					return Err(ImageLoadIOError.into());
-				},
-				_ => {
-					return Err(ImageLoadGenericError.into());
-				}
-			};
+				ImageError::Decoding(_) => Err(ImageLoadDecodingError.into()),
+				ImageError::Limits(_) => Err(ImageLoadLimitsError.into()),
+				ImageError::IoError(_) => Err(ImageLoadIOError.into()),
+				_ => Err(ImageLoadGenericError.into())
+			}
 		}
-	}.into_rgba8();
+	}
+}
+
+// Reads a palette file, in the format its extension indicates
+// (see `PaletteFormat::from_path`).
+pub fn load_palette(path: &str) -> Result<DynamicPaletteView, Box<dyn Error + 'static>> {
+	let mut source_data = Vec::new();
+	match File::open(path) {
+		Ok(mut f) => {
+			match f.read_to_end(&mut source_data) {
+				Ok(_) => {
+					match PaletteFormat::from_path(path).read(&source_data) {
+						Ok(colors) => Ok(DynamicPaletteView::from(colors)),
+						Err(e) => Err(e.into())
+					}
+				},
+				Err(_) => Err(PaletteFileOpenInputError.into())
+			}
+		},
+		Err(_) => Err(PaletteFileOpenInputError.into())
+	}
+}
 
-	let palette = quantization::generate_palette::
-		<quantization::palette::DynamicPaletteView>(&source, dedup);
+// Writes `colors` to a palette file, in the format its extension indicates.
+pub fn save_palette(path: &str, colors: &[Color]) -> Result<String, Box<dyn Error + 'static>> {
+	let data = PaletteFormat::from_path(path).write(colors)?;
+	match File::create(path) {
+		Ok(mut f) => {
+			match f.write_all(&data) {
+				Ok(_) => Ok(path.to_string()),
+				Err(_) => Err(PaletteFileWriteError.into())
+			}
+		},
+		Err(_) => Err(PaletteFileWriteError.into())
+	}
+}
 
+// Extracts the palette embedded in the QIM file `input` into the palette file `output`.
+//
+// All the colors the file stores are kept, as given by the length indicator
+// of its palette; only the padding up to the size of the color space is left out.
+pub fn export_palette(input: &str, output: &str) -> Result<String, Box<dyn Error + 'static>> {
+	let mut source_data = Vec::new();
+	match File::open(input) {
+		Ok(mut f) => {
+			match f.read_to_end(&mut source_data) {
+				Ok(_) => save_palette(output, &embedded_palette(&source_data)?),
+				Err(_) => Err(QIMFileOpenInputError.into())
+			}
+		},
+		Err(_) => Err(QIMFileOpenInputError.into())
+	}
+}
+
+// Computes one palette over all the `inputs` images and writes it as a
+// palette file to `output`, to be used through `EncodeOptions::palette_file`.
+pub fn generate_shared_palette(
+	inputs: &[&str],
+	output: &str,
+	options: &EncodeOptions
+) -> Result<String, Box<dyn Error + 'static>> {
+	let sources = inputs.iter()
+		.map(|path| open_image(path).map(|source| options.prefilter.apply_owned(source)))
+		.collect::<Result<Vec<_>, _>>()?;
+	let generator = options.palette_algo.generator(
+		options.dedup,
+		options.max_colors,
+		options.metric,
+		options.blending
+	);
+	let palette: DynamicPaletteView = quantization::generate_batch_palette(
+		&sources,
+		generator.as_ref(),
+		options.alpha
+	);
+	save_palette(output, &palette.colors)
+}
+
+fn check_palette_sources(options: &EncodeOptions) -> Result<(), Box<dyn Error + 'static>> {
+	if options.palette_file.is_some() && options.builtin_palette.is_some() {
+		return Err(PaletteSourceConflictError.into());
+	}
+	if options.reference_palette && options.palette_file.is_none() {
+		return Err(QIMReferenceWithoutPaletteFileError.into());
+	}
+	Ok(())
+}
+
+// Turns `source` into a quadtree whose colors are taken from `palette`.
+// `filtered` is `source` through `options.prefilter`; it is what gets
+// quantized, while edges and errors are measured on `source`.
+fn mount_quadtree<P: Palette>(
+	source: &image::RgbaImage,
+	filtered: &image::RgbaImage,
+	palette: P,
+	options: &EncodeOptions
+) -> Result<TreeWithPalette<P>, Box<dyn Error + 'static>> {
 	let mut tree: QuadtreeNode<_> = Default::default();
 	
-	match tree.from_image(&source, &palette, sensitivity, blur, true) {
-		Ok(()) => (),
-		// TODO: Add support for non-square/non-power-of-two images
-		Err(e) => {
-			match e {
-				AnalyzeError::NonPowerOfTwo => {
-					return Err(AnalyzeError::NonPowerOfTwo.into());
-				},
-				AnalyzeError::NonSquare => {
-					return Err(AnalyzeError::NonSquare.into());
-				}
-			}	
-		}
+	let quantize = QuantizeOptions { metric: options.metric, dither: options.dither, alpha: options.alpha };
+	let split = match options.max_error {
+		None => SplitCriterion::Majority(options.sensitivity.unwrap_or(match options.prune {
+			// Pruning works best from a tree split as finely as possible.
+			Some(_) => 16384,
+			None => 16128, // (16384 * s) / (s + 1) with s = 63
+		})),
+		Some(limit) => SplitCriterion::Error(limit, options.metric),
+	};
+	let edges = (options.edge_weight > 0.).then(|| EdgeMap::new(source, options.edge_weight));
+	let targets = [options.target_size.is_some(), options.target_quality.is_some(), options.prune.is_some()];
+	if targets.iter().filter(|t| **t).count() > 1 {
+		return Err(TargetConflictError.into());
 	}
-	for _ in 0..trim {
-		tree.trim(6);
+	if options.prune.is_some_and(|lambda| lambda < 0. || lambda.is_nan()) {
+		return Err(PruneLambdaError.into());
 	}
-	Ok(TreeWithPalette{tree, palette})
-} 
+	let (split, quality) = match (options.target_size, options.target_quality) {
+		(Some(_), Some(_)) => unreachable!("checked above"),
+		(Some(budget), None) => {
+			let palettified = node::image::palettify(filtered, &palette, options.blur_amount(), &quantize)?;
+			let fitted = tree.mount_within(
+				&palettified,
+				&palette,
+				split,
+				edges.as_ref(),
+				options.trim,
+				qim_flags(options),
+				budget
+			)?;
+			(fitted, None)
+		},
+		(None, Some(floor)) => {
+			let palettified = node::image::palettify(filtered, &palette, options.blur_amount(), &quantize)?;
+			let (split, quality) = tree.mount_to_quality(
+				&palettified,
+				&palette,
+				split,
+				edges.as_ref(),
+				options.trim,
+				source,
+				options.blending,
+				floor
+			)?;
+			(split, Some(quality))
+		},
+		(None, None) => {
+			// TODO: Add support for non-square/non-power-of-two images
+			tree.from_image(filtered, &palette, split, options.blur_amount(), true, &quantize, edges.as_ref())?;
+			if let Some(ref policy) = options.trim {
+				tree.trim(&palette, policy);
+			}
+			if let Some(lambda) = options.prune {
+				tree.prune(source, &palette, options.metric, lambda)?;
+			}
+			(split, None)
+		}
+	};
+	// Refitting leaves doesn't change the size of the tree, but might make
+	// it miss a quality floor, in which case it is undone.
+	let quality = match (options.refit, options.target_quality) {
+		(None, _) => quality,
+		(Some(refit), None) => {
+			tree.refit(source, &palette, refit, &quantize, options.blending)?;
+			quality
+		},
+		(Some(refit), Some(floor)) => {
+			let mut refitted = tree.clone();
+			refitted.refit(source, &palette, refit, &quantize, options.blending)?;
+			let refitted_quality = refitted.quality_against(&palette, source, options.blending, floor)?;
+			if floor.is_met(refitted_quality) {
+				tree = refitted;
+				Some(refitted_quality)
+			} else {
+				quality
+			}
+		}
+	};
+	Ok(TreeWithPalette{tree, palette, split, quality})
+}
+
+// A built-in palette is handed over as a `DynamicPaletteView` of the same
+// width; `generate_quadtree_with` keeps its own palette type.
+pub fn generate_quadtree(
+	path: &str,
+	options: &EncodeOptions
+) -> Result<TreeWithPalette, Box<dyn Error + 'static>> {
+	generate_quadtree_from_image(&open_image(path)?, options)
+}
+
+// Same as `generate_quadtree`, but from an image already in memory.
+pub fn generate_quadtree_from_image(
+	source: &image::RgbaImage,
+	options: &EncodeOptions
+) -> Result<TreeWithPalette, Box<dyn Error + 'static>> {
+	check_palette_sources(options)?;
+	let filtered = options.prefilter.apply(source);
+
+	let palette = match (&options.palette_file, options.builtin_palette) {
+		(Some(palette_file), _) => load_palette(palette_file)?,
+		(None, Some(builtin)) => with_builtin_palette!(builtin, palette =>
+			DynamicPaletteView::from(quantization::palette_colors(&palette))),
+		(None, None) => {
+			let generator = options.palette_algo.generator(
+				options.dedup,
+				options.max_colors,
+				options.metric,
+				options.blending
+			);
+			quantization::generate_palette(&filtered, generator.as_ref(), options.alpha)
+		}
+	};
+	mount_quadtree(source, &filtered, palette, options)
+}
 
-pub fn generate_img(
+// Same as `generate_quadtree`, but always encodes against the given fixed
+// `palette`; the palette settings of `options` are ignored.
+pub fn generate_quadtree_with<P: Palette>(
+	path: &str,
+	palette: P,
+	options: &EncodeOptions
+) -> Result<TreeWithPalette<P>, Box<dyn Error + 'static>> {
+	generate_quadtree_from_image_with(&open_image(path)?, palette, options)
+}
+
+// Same as `generate_quadtree_with`, but from an image already in memory.
+pub fn generate_quadtree_from_image_with<P: Palette>(
+	source: &image::RgbaImage,
+	palette: P,
+	options: &EncodeOptions
+) -> Result<TreeWithPalette<P>, Box<dyn Error + 'static>> {
+	let filtered = options.prefilter.apply(source);
+	mount_quadtree(source, &filtered, palette, options)
+}
+
+// `blending` is the space gradients are interpolated in.
+pub fn generate_img<P: Palette>(
 	width: u32,
-	tree: QuadtreeNode<DynamicPaletteView>,
-	palette: DynamicPaletteView,
-	output: &str
+	tree: QuadtreeNode<P>,
+	palette: P,
+	output: &str,
+	blending: Blending
 ) -> Result<String, Box<dyn Error + 'static>>{
 	let mut output_buf = image::RgbaImage::new(width, width);
-	match tree.to_image(&mut output_buf, &palette, None, None, true) {
-		Ok(_) => {
-			match output_buf.save(output) {
-				Ok(_) => Ok(output.to_string()),
-				Err(e) => Err(e.into())
-			}
+	tree.to_image(&mut output_buf, &palette, None, None, true, blending)?;
+	output_buf.save(output)?;
+	Ok(output.to_string())
+}
+
+fn qim_flags(options: &EncodeOptions) -> QimFlags {
+	QimFlags { external_palette: options.reference_palette, blending: options.blending, ..Default::default() }
+}
+
+// Serializes the tree into QIM data, along with what it was encoded with.
+fn qim_data<P: Palette>(
+	tree_with_palette: &TreeWithPalette<P>,
+	options: &EncodeOptions
+) -> Result<(Vec<u8>, EncodeReport), Box<dyn Error + 'static>> {
+	// the only error that can occur here is a color in the quadtree out
+	// of range of the palette, but since the quadtree is generated
+	// programmatically from an image, that should not happen.
+	// If it does happen, there is a bug in the program to be fixed.
+	match tree_with_palette.tree.to_qim_with(&tree_with_palette.palette, qim_flags(options)) {
+		Ok(qim_stream) => {
+			let report = EncodeReport {
+				split: tree_with_palette.split,
+				size: qim_stream.len(),
+				quality: tree_with_palette.quality
+			};
+			Ok((qim_stream, report))
 		},
-		Err(e) => {
-			match e {
-				DrawError::NonSquare => {
-					Err(DrawError::NonSquare.into())
-				},
-				DrawError::NonPowerOfTwo => {
-					Err(DrawError::NonPowerOfTwo.into())
-				},
-				DrawError::ColorOutOfRange => {
-					Err(DrawError::ColorOutOfRange.into())
-				}
+		Err(_) => Err(QIMSerializationError.into())
+	}
+}
+
+fn write_qim<P: Palette>(
+	tree_with_palette: &TreeWithPalette<P>,
+	output: &str,
+	options: &EncodeOptions
+) -> Result<EncodeReport, Box<dyn Error + 'static>> {
+	let (qim_stream, report) = qim_data(tree_with_palette, options)?;
+	let out_fh = File::create(output);
+	match out_fh {
+		Ok(mut f) => {
+			match f.write_all(&qim_stream) {
+				Ok(_) => Ok(report),
+				Err(_) => Err(QIMFileWriteError.into())
 			}
-		}
+		},
+		Err(_) => Err(QIMFileOpenOutputError.into())
 	}
+}
 
+// Encodes an image held in memory, without touching the filesystem (unless
+// `options.palette_file` is set). Built-in palettes are handed over as a
+// `DynamicPaletteView`, as by `generate_quadtree`.
+pub fn encode_image(
+	source: &image::RgbaImage,
+	options: &EncodeOptions
+) -> Result<EncodedImage, Box<dyn Error + 'static>> {
+	let tree_with_palette = generate_quadtree_from_image(source, options)?;
+	let (qim, report) = qim_data(&tree_with_palette, options)?;
+	let (tree, palette) = tree_with_palette.into_parts();
+	Ok(EncodedImage { tree, palette, qim, report })
+}
 
+// Same as `encode_image`, but always encodes against the given fixed
+// `palette` (see `generate_quadtree_with`).
+pub fn encode_image_with<P: Palette>(
+	source: &image::RgbaImage,
+	palette: P,
+	options: &EncodeOptions
+) -> Result<EncodedImage<P>, Box<dyn Error + 'static>> {
+	let tree_with_palette = generate_quadtree_from_image_with(source, palette, options)?;
+	let (qim, report) = qim_data(&tree_with_palette, options)?;
+	let (tree, palette) = tree_with_palette.into_parts();
+	Ok(EncodedImage { tree, palette, qim, report })
+}
 
+// Same as `encode_image`, for an image of any color type.
+pub fn encode_dynamic_image(
+	source: &image::DynamicImage,
+	options: &EncodeOptions
+) -> Result<EncodedImage, Box<dyn Error + 'static>> {
+	encode_image(&source.to_rgba8(), options)
+}
 
+// Same as `encode_image`, for raw 8-bit RGBA pixels, row by row.
+pub fn encode_rgba(
+	pixels: &[u8],
+	width: u32,
+	height: u32,
+	options: &EncodeOptions
+) -> Result<EncodedImage, Box<dyn Error + 'static>> {
+	match image::RgbaImage::from_raw(width, height, pixels.to_vec()) {
+		Some(source) if pixels.len() == 4 * width as usize * height as usize => encode_image(&source, options),
+		_ => Err(RgbaBufferSizeError.into())
+	}
 }
 
 pub fn im2qim(
 	input: &str,
 	output: &str,
-	dedup: u32,
-	blur: f32,
-	sensitivity: usize,
-	trim: usize
+	options: &EncodeOptions
 ) -> Result<String, Box<dyn Error + 'static>> {
-	match generate_quadtree(input, dedup, blur, sensitivity, trim) {
-		Ok(tree_with_palette) => {
-			// the only error that can occur here is a color in the quadtree out
-			// of range of the palette, but since the quadtree is generated
-			// programmatically from an image, that should not happen.
-			// If it does happen, there is a bug in the program to be fixed.
-			match tree_with_palette.tree.to_qim(&tree_with_palette.palette) {
-				Ok(qim_stream) => {
-					let out_fh = File::create(output);
-					match out_fh {
-						Ok(mut f) => {
-							match f.write_all(&qim_stream) {
-								Ok(_) => Ok(output.to_string()),
-								Err(_) => {
-									return Err(QIMFileWriteError.into());
-								}
-							}
-						},
-						Err(_) => {
-							return Err(QIMFileOpenOutputError.into());
-						}
-					}
-				},
-				Err(_) => {
-					Err(QIMSerializationError.into())
-				}
-			}
-		},
-		Err(e) => {
-			Err(e)
-		} 
+	im2qim_report(input, output, options).map(|_| output.to_string())
+}
+
+// Same as `im2qim`, but tells the settings the output was encoded with,
+// which are worth knowing when `target_size` is set.
+pub fn im2qim_report(
+	input: &str,
+	output: &str,
+	options: &EncodeOptions
+) -> Result<EncodeReport, Box<dyn Error + 'static>> {
+	check_palette_sources(options)?;
+	match options.builtin_palette {
+		Some(builtin) => with_builtin_palette!(builtin, palette =>
+			write_qim(&generate_quadtree_with(input, palette, options)?, output, options)),
+		None => write_qim(&generate_quadtree(input, options)?, output, options)
+	}
+}
+
+// Renders the tree into the image `output`, reporting what it was encoded
+// with, its size being the one of its QIM data.
+fn render_report<P: Palette>(
+	tree_with_palette: TreeWithPalette<P>,
+	width: u32,
+	output: &str,
+	options: &EncodeOptions
+) -> Result<EncodeReport, Box<dyn Error + 'static>> {
+	let (_, report) = qim_data(&tree_with_palette, options)?;
+	generate_img(width, tree_with_palette.tree, tree_with_palette.palette, output, options.blending)?;
+	Ok(report)
+}
+
+// Encodes `input` and renders the result straight into the image `output`.
+pub fn im2im(
+	input: &str,
+	output: &str,
+	width: u32,
+	options: &EncodeOptions
+) -> Result<String, Box<dyn Error + 'static>> {
+	im2im_report(input, output, width, options).map(|_| output.to_string())
+}
+
+// Same as `im2im`, but tells the settings the output was encoded with, as
+// `im2qim_report` does.
+pub fn im2im_report(
+	input: &str,
+	output: &str,
+	width: u32,
+	options: &EncodeOptions
+) -> Result<EncodeReport, Box<dyn Error + 'static>> {
+	check_palette_sources(options)?;
+	match options.builtin_palette {
+		Some(builtin) => with_builtin_palette!(builtin, palette =>
+			render_report(generate_quadtree_with(input, palette, options)?, width, output, options)),
+		None => render_report(generate_quadtree(input, options)?, width, output, options)
+	}
+}
+
+// Draws QIM data into a `width` by `width` image; `width` must be a power
+// of two. `palette` is only needed for QIM data referring to an external
+// palette (see `decode_qim_with_palette`).
+fn draw_qim(
+	qim: &[u8],
+	width: u32,
+	palette: Option<DynamicPaletteView>
+) -> Result<image::RgbaImage, Box<dyn Error + 'static>> {
+	if !width.is_power_of_two() {
+		return Err(DrawError::NonPowerOfTwo.into());
 	}
+	let (tree, palette) = match palette {
+		Some(p) => QuadtreeNode::from_qim_with_palette(qim, p)?,
+		None => QuadtreeNode::from_qim(qim)?
+	};
+	let blending = QimFlags::of_qim(qim)?.blending;
+	let mut img = image::RgbaImage::new(width, width);
+	tree.to_image(&mut img, &palette, None, None, true, blending)?;
+	Ok(img)
 }
 
+// Decodes QIM data held in memory into an image.
+//
+// QIM data only holds a quadtree over a square, not the size of the image it
+// was encoded from, so the output size can't come from the data: `width` is
+// the side of the square image to draw, which must be a power of two (any
+// other, zero included, is a `DrawError::NonPowerOfTwo`). Drawing at the
+// side the source was encoded at gives back that size.
+pub fn decode_qim(qim: &[u8], width: u32) -> Result<image::RgbaImage, Box<dyn Error + 'static>> {
+	draw_qim(qim, width, None)
+}
+
+// Same as `decode_qim`, for QIM data referring to an external palette;
+// `palette` is used in place of the one embedded otherwise.
+pub fn decode_qim_with_palette(
+	qim: &[u8],
+	width: u32,
+	palette: DynamicPaletteView
+) -> Result<image::RgbaImage, Box<dyn Error + 'static>> {
+	draw_qim(qim, width, Some(palette))
+}
+
+// Renders QIM data held in memory into a PNG file of any size, returned as
+// bytes. The square quadtree is drawn at the next power of two and then
+// resampled to `width` by `height`, stretching it if they differ.
+pub fn render_qim(qim: &[u8], width: u32, height: u32) -> Result<Vec<u8>, Box<dyn Error + 'static>> {
+	if width == 0 || height == 0 {
+		return Err(OutputSizeError.into());
+	}
+	let side = width.max(height).next_power_of_two();
+	let mut img = decode_qim(qim, side)?;
+	if width != side || height != side {
+		img = image::imageops::resize(&img, width, height, image::imageops::FilterType::Triangle);
+	}
+	let mut png = Vec::new();
+	image::DynamicImage::ImageRgba8(img).write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)?;
+	Ok(png)
+}
+
+// `palette_file` is the palette to decode QIM files referring to an
+// external palette with; it is not needed for other QIM files.
 pub fn qim2im(
 	input: &str,
 	output: &str,
-	width: u32
+	width: u32,
+	palette_file: Option<&str>
 ) -> Result<String, Box<dyn Error + 'static>> {
 	let mut source_data = Vec::new();
 	match File::open(input) {
 		Ok(mut f) => {
 			match f.read_to_end(&mut source_data) {
 				Ok(_) => {
-					match QuadtreeNode::from_qim(&source_data) {
-							Ok((t, p)) => {
-								match generate_img(width, t, p, output) {
-									Ok(_) => Ok(output.to_string()),
-									Err(e) => Err(e.into())
-								}
-							},
-							Err(e) => Err(e.into())
+					let palette = match palette_file {
+						Some(path) => Some(load_palette(path)?),
+						None => None
+					};
+					match draw_qim(&source_data, width, palette)?.save(output) {
+						Ok(_) => Ok(output.to_string()),
+						Err(e) => Err(e.into())
 					}
 				},
 				Err(_) => Err(QIMFileOpenInputError.into())
@@ -288,155 +875,222 @@ pub fn qim2im(
 	}
 }
 
-/// Python FFIs
-#[pyfunction]
-fn compress(
-	input: String,
-	output: String,
-	dedup_: Option<&PyLong>,
-	blur_: Option<&PyFloat>,
-	sensitivity_: Option<&PyLong>,
-	trim_: Option<&PyLong>,
-	width_: Option<&PyLong>,
-	to_qim_: Option<&PyBool>,
-	from_qim_: Option<&PyBool> 
-) -> PyResult<String> {
-	// TODO: Instead of PyResult<String>,
-	// Consider PyResult<PyCompressionResult>.. `PyCompressionResult` being a custom python class  
-	let (dedup, blur, sensitivity, trim, width, from_qim, to_qim) = (
-		match dedup_ {
-			None => 256,
-			Some(d) => {
-				let native_t = d.extract()?;
-				native_t
-			}
-		} as u32,
-		match blur_ {
-			None => 1.0,
-			Some(b) => {
-				let native_t = b.extract()?;
-				native_t
-			}
-		} as f32,
-		match sensitivity_ {
-			None => 16128, // formula as below with s = 63
-			Some(s) => {
-				let native_t: i32 = s.extract()?;				
-				(16384 * native_t) / (native_t + 1)
-			}
-		} as usize,
-		match trim_ {
-			None => 0,
-			Some(t) => {
-				let native_t = t.extract()?;
-				native_t
+#[cfg(test)]
+mod tests {
+	use super::node::QuadtreeNode;
+	use super::node::quantization::{self, PaletteAlgorithm, QuantizeOptions};
+	use super::node::quantization::dither::Dither;
+	use super::node::quantization::metric::ColorMetric;
+	use super::node::quantization::palette::DynamicPaletteView;
+	use super::node::split::{ErrorLimit, SplitCriterion};
+
+	// Sizes and fingerprints of the QIM outputs of `encoding_matches_golden_output`.
+	const GOLDEN: [(usize, u64); 6] = [
+		(11679, 5723498153026935913), (4037, 12068360582778938461),
+		(26969, 15380467927159389523), (12974, 9024256055699346043),
+		(29603, 11863735089477880732), (24778, 13981722892570230661),
+	];
+
+	// A noisy image with gradients, hard edges and a transparent band.
+	fn test_image() -> image::RgbaImage {
+		let mut state = 0x2545f491u32;
+		image::RgbaImage::from_fn(256, 256, |x, y| {
+			state ^= state << 13;
+			state ^= state >> 17;
+			state ^= state << 5;
+			let noise = (state % 24) as u8;
+			if (x / 32 + y / 32) % 3 == 0 {
+				image::Rgba([(x / 2) as u8 + noise / 2, y as u8, 200 - noise, 255])
+			} else {
+				image::Rgba([40 + noise, (x ^ y) as u8, 90, if x < 16 { 0 } else { 255 }])
 			}
-		} as usize,
-		match width_ {
-			None => 512,
-			Some(w) => {
-				let native_t = w.extract()?;
-				native_t
+		})
+	}
+
+	fn test_palette(img: &image::RgbaImage) -> DynamicPaletteView {
+		let generator = PaletteAlgorithm::MedianCut.generator(0, Some(32), ColorMetric::Srgb, Default::default());
+		quantization::generate_palette(img, generator.as_ref(), Default::default())
+	}
+
+	const SPLITS: [SplitCriterion; 2] = [
+		SplitCriterion::Majority(16128),
+		SplitCriterion::Error(ErrorLimit::Rms(4.), ColorMetric::OkLab),
+	];
+
+	fn encode(img: &image::RgbaImage, palette: &DynamicPaletteView, dither: Dither, split: SplitCriterion) -> Vec<u8> {
+		let mut tree: QuadtreeNode<DynamicPaletteView> = Default::default();
+		let quantize = QuantizeOptions { dither, ..Default::default() };
+		tree.from_image(img, palette, split, 1., true, &quantize, None).unwrap();
+		tree.to_qim(palette).unwrap()
+	}
+
+	#[cfg(feature = "parallel")]
+	fn encode_on(threads: usize, img: &image::RgbaImage, palette: &DynamicPaletteView, dither: Dither, split: SplitCriterion) -> Vec<u8> {
+		let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+		pool.install(|| encode(img, palette, dither, split))
+	}
+
+	// FNV-1a, to keep the expected outputs short.
+	fn fingerprint(data: &[u8]) -> u64 {
+		data.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+	}
+
+	// The outputs recorded here must come out of both the sequential and the
+	// parallel build, which `cargo test` and `cargo test --features parallel` check.
+	#[test]
+	fn encoding_matches_golden_output() {
+		let img = test_image();
+		let palette = test_palette(&img);
+		let mut fingerprints = vec![];
+		for dither in [Dither::None, Dither::Bayer, Dither::FloydSteinberg] {
+			for split in SPLITS {
+				let qim = encode(&img, &palette, dither, split);
+				fingerprints.push((qim.len(), fingerprint(&qim)));
 			}
-		} as u32,
-		match from_qim_ {
-			Some(q) => {
-				let native_t = q.extract()?;
-				native_t
-			},
-			None => false
-		},
-		match to_qim_ {
-			Some(q) => {
-				let native_t = q.extract()?;
-				native_t
-			},
-			None => false
 		}
-	);
+		assert_eq!(fingerprints, GOLDEN);
+	}
 
-	if from_qim && to_qim {
-		return Err(PyRuntimeError::new_err("can not enable `from_qim` and `to_qim` together as they are mutually exclusive parameters"));
+	// Squares split into four solid leaves must not come back as gradients.
+	#[test]
+	fn four_solid_leaves_survive_a_round_trip() {
+		let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255], [255, 255, 255, 255]];
+		let source = image::RgbaImage::from_fn(2, 2, |x, y| image::Rgba(colors[(y * 2 + x) as usize]));
+		let options = super::EncodeOptions { blur: Some(0.), dedup: 0, ..Default::default() };
+		let encoded = super::encode_image(&source, &options).unwrap();
+		assert!(encoded.tree.sections.is_some());
+		assert_eq!(super::decode_qim(&encoded.qim, 2).unwrap(), source);
 	}
 
-	if from_qim && !to_qim {
-		// `input` is QIM format and `output` is PNG format.
-		// Read QIM file data, recover precomputed quadtree and palette object (not costly) and
-		// write PNG image.
-		let in_file = input.clone();
-		let out_file = output.clone();
+	// Decoding QIM output renders the same image as the tree it was encoded from.
+	#[test]
+	fn encoding_round_trips_through_qim() {
+		let img = test_image();
+		for blending in [super::Blending::Srgb, super::Blending::Linear] {
+			let options = super::EncodeOptions { blending, ..Default::default() };
+			let encoded = super::encode_image(&img, &options).unwrap();
+			let mut rendered = image::RgbaImage::new(256, 256);
+			encoded.tree.to_image(&mut rendered, &encoded.palette, None, None, true, blending).unwrap();
+			assert_eq!(super::decode_qim(&encoded.qim, 256).unwrap(), rendered, "{:?}", blending);
+		}
+	}
 
-		if (input.ends_with(".qim") || input.ends_with(".QIM")) && (output.ends_with(".png") || output.ends_with(".PNG")) {
-			match qim2im(input.as_str(), output.as_str(), width) {
-				Ok(o) => {
-					return Ok(o);
-				},
-				Err(e) => {
-					return Err(PyRuntimeError::new_err(e.to_string()));
-				}
-			};
+	// Linear light is opted into; every default blends in sRGB, as QIM
+	// version 1 files are rendered.
+	#[test]
+	fn blending_defaults_agree() {
+		assert_eq!(super::EncodeOptions::default().blending, super::Blending::Srgb);
+		assert_eq!(super::Blending::default(), super::Blending::Srgb);
+		assert_eq!(super::TrimPolicy::default().blending, super::Blending::Srgb);
+	}
+
+	// Pruning splits as finely as possible unless told otherwise, and takes no negative lambda.
+	#[test]
+	fn prune_settings() {
+		let img = test_image();
+		let options = super::EncodeOptions { prune: Some(64.), ..Default::default() };
+		assert_eq!(super::encode_image(&img, &options).unwrap().report.split, SplitCriterion::Majority(16384));
+		let options = super::EncodeOptions { sensitivity: Some(9000), ..options };
+		assert_eq!(super::encode_image(&img, &options).unwrap().report.split, SplitCriterion::Majority(9000));
+		for lambda in [-1., f64::NAN] {
+			let options = super::EncodeOptions { prune: Some(lambda), ..Default::default() };
+			assert!(super::encode_image(&img, &options).is_err_and(|e| e.is::<super::PruneLambdaError>()));
 		}
-		
-		return Err(
-			PyRuntimeError::new_err(
-				format!(
-					"input={}, output={} :  Wrong file extensions for config : from_qim={}, to_qim={}",
-					in_file.as_str(), out_file.as_str(), from_qim, to_qim
-				)
-			)
-		);
 	}
 
-	if !from_qim && to_qim {
-		// `input` is PNG format and `output` is QIM format.
-		// Generate quadtree and palette from input, serialize them into QIM format and write
-		// it on disk for later. Can be interesting to have a DB of precompressed artifacts,
-		// when we do `offline` computation (in a distributed queuing system).
-		let in_file = input.clone();
-		let out_file = output.clone();
+	// Pre-filters stand in for the blur unless one is asked for.
+	#[test]
+	fn prefilters_drop_the_default_blur() {
+		let img = test_image();
+		let prefilter = super::PreFilter::Median { radius: 1 };
+		let encode = |blur| super::encode_image(&img, &super::EncodeOptions { blur, prefilter, ..Default::default() }).unwrap().qim;
+		assert_eq!(super::EncodeOptions::default().blur_amount(), 1.);
+		assert_eq!(encode(None), encode(Some(0.)));
+		assert_ne!(encode(None), encode(Some(1.)));
+	}
 
-		if (input.ends_with(".png") || input.ends_with(".PNG")) && (output.ends_with(".qim") || output.ends_with(".QIM")) {
-			match im2qim(input.as_str(), output.as_str(), dedup, blur, sensitivity, trim) {
-				Ok(o) => {
-					return Ok(o)
-				},
-				Err(e) => {
-					return Err(PyRuntimeError::new_err(e.to_string()));
-				}
-			};
+	#[test]
+	fn rgba_buffers_must_match_the_size() {
+		let options = super::EncodeOptions::default();
+		for len in [0, 15, 17, 64] {
+			let encoded = super::encode_rgba(&vec![255; len], 2, 2, &options);
+			assert!(encoded.is_err_and(|e| e.is::<super::RgbaBufferSizeError>()), "{} bytes", len);
 		}
-		return Err(
-			PyRuntimeError::new_err(
-				format!(
-					"input={}, output={} :  Wrong file extensions for config : from_qim={}, to_qim={}",
-					in_file.as_str(), out_file.as_str(), from_qim, to_qim
-				)
-			)
+		let encoded = super::encode_rgba(&[255; 16], 2, 2, &options).unwrap();
+		assert_eq!(super::decode_qim(&encoded.qim, 2).unwrap(), image::RgbaImage::from_pixel(2, 2, image::Rgba([255; 4])));
+	}
+
+	// QIM data doesn't record a size: it can be drawn at any power of two,
+	// down to the single pixel of the root color.
+	#[test]
+	fn qim_data_decodes_at_any_size() {
+		let img = test_image();
+		let encoded = super::encode_image(&img, &Default::default()).unwrap();
+		let large = super::decode_qim(&encoded.qim, 512).unwrap();
+		assert_eq!(large.dimensions(), (512, 512));
+		assert_eq!(super::decode_qim(&encoded.qim, 1).unwrap().get_pixel(0, 0).0, encoded.palette.colors[encoded.tree.color as usize].0);
+		assert!(super::decode_qim(&encoded.qim[..encoded.qim.len() / 2], 256).is_err());
+		assert!(super::decode_qim(b"not a QIM file", 256).is_err());
+		for width in [0, 3, 100] {
+			assert!(matches!(
+				super::decode_qim(&encoded.qim, width).map_err(|e| e.downcast::<super::DrawError>().map(|e| *e)),
+				Err(Ok(super::DrawError::NonPowerOfTwo))
+			), "{}", width);
+		}
+	}
+
+	#[test]
+	fn external_palettes_are_needed_to_decode() {
+		let img = test_image();
+		let palette = test_palette(&img);
+		let mut tree: QuadtreeNode<DynamicPaletteView> = Default::default();
+		tree.from_image(&img, &palette, Default::default(), 1., true, &Default::default(), None).unwrap();
+		let flags = super::QimFlags { external_palette: true, ..Default::default() };
+		let qim = tree.to_qim_with(&palette, flags).unwrap();
+		let decode_error = |e: Box<dyn std::error::Error>| e.downcast_ref::<super::DecodeError>().map(|e| e.to_string());
+		assert_eq!(
+			super::decode_qim(&qim, 64).map_err(decode_error).unwrap_err(),
+			Some(super::DecodeError::ExternalPalette.to_string())
+		);
+		let copy = || DynamicPaletteView::from(palette.colors.to_vec());
+		let mut rendered = image::RgbaImage::new(64, 64);
+		tree.to_image(&mut rendered, &palette, None, None, true, Default::default()).unwrap();
+		assert_eq!(super::decode_qim_with_palette(&qim, 64, copy()).unwrap(), rendered);
+		let mut other = palette.colors.to_vec();
+		other.swap(0, 1);
+		assert_eq!(
+			super::decode_qim_with_palette(&qim, 64, DynamicPaletteView::from(other)).map_err(decode_error).unwrap_err(),
+			Some(super::DecodeError::PaletteMismatch.to_string())
 		);
 	}
 
-	// Else, default case :
-	// `input` is PNG format and `output` is PNG format.
-	// Generate quadtree and palette from input, keep them in mem and write PNG image out of it. 
-	match generate_quadtree(input.as_str(), dedup, blur, sensitivity, trim) {
-		Ok(tree_with_palette) => {
-			match generate_img(width, tree_with_palette.tree, tree_with_palette.palette, output.as_str()) {
-				Ok(o) => {
-					return Ok(o)
-				},
-				Err(e) => {
-					return Err(PyRuntimeError::new_err(e.to_string()));
-				}
-			}
-		},
-		Err(e) => {
-			Err(PyRuntimeError::new_err(e.to_string()))
-		} 
+	#[test]
+	fn rendering_resamples_to_the_requested_size() {
+		let encoded = super::encode_image(&test_image(), &Default::default()).unwrap();
+		for (width, height) in [(256, 256), (100, 100), (300, 40)] {
+			let png = super::render_qim(&encoded.qim, width, height).unwrap();
+			let img = image::load_from_memory_with_format(&png, image::ImageFormat::Png).unwrap();
+			assert_eq!((img.width(), img.height()), (width, height));
+		}
+		for (width, height) in [(0, 10), (10, 0)] {
+			assert!(super::render_qim(&encoded.qim, width, height).is_err_and(|e| e.is::<super::OutputSizeError>()));
+		}
 	}
-}
 
-#[pymodule]
-fn quompressor(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
-	m.add_function(wrap_pyfunction!(compress, m)?)?;
-	Ok(())
+	// A pool of one thread runs everything in order, like the sequential path.
+	#[cfg(feature = "parallel")]
+	#[test]
+	fn parallel_encoding_is_deterministic() {
+		let img = test_image();
+		let palette = test_palette(&img);
+		for dither in [Dither::None, Dither::Bayer, Dither::FloydSteinberg] {
+			for split in SPLITS {
+				assert_eq!(
+					encode_on(1, &img, &palette, dither, split),
+					encode_on(8, &img, &palette, dither, split),
+					"{:?}, {:?}", dither, split
+				);
+			}
+		}
+	}
 }
@@ -1,11 +1,11 @@
 use image::error::ImageError;
 
-mod node;
-
-use node::QuadtreeNode;
-use node::quantization;
-use node::error::DrawError;
+use quompressor::EncodeOptions;
+use quompressor::{AnalyzeError, DecodeError, DrawError, EncodeError};
+use quompressor::{Blending, ErrorLimit, PaletteFormat, PreFilter, QualityFloor, Refit, TrimPolicy};
+use quompressor::node::quantization;
 
+use std::error::Error;
 use std::fs::File;
 use std::io::{Read, Write};
 
@@ -14,6 +14,173 @@ fn exit(msg: &str, code: i32) -> ! {
     std::process::exit(code);
 }
 
+fn open_image(path: &str) -> image::RgbaImage {
+    match image::open(path) {
+        Ok(i) => i,
+        Err(e) => {
+            let (msg, code) = match e {
+                ImageError::Decoding(_) => ("Invalid image data", 4),
+                ImageError::Limits(_) => ("Computation limits exceeded", 5),
+                ImageError::IoError(_) => ("File not found or could not be read", 3),
+                _ => ("An error occurred", 10)
+            };
+            exit(msg, code);
+        }
+    }.into_rgba8()
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut fh = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => exit("File not found or could not be read", 3)
+    };
+    match fh.read_to_end(&mut data) {
+        Ok(_) => (),
+        Err(_) => exit("Could not read from input file", 3)
+    }
+    data
+}
+
+fn write_file(path: &str, data: &[u8]) {
+    let mut out_fh = match File::create(path) {
+        Ok(f) => f,
+        Err(_) => exit("Could not open output file", 3)
+    };
+    match out_fh.write_all(data) {
+        Ok(_) => (),
+        Err(_) => exit("Could not write to output file", 3)
+    }
+}
+
+// Exits with the message and code a library error is reported with.
+fn fail(e: Box<dyn Error>) -> ! {
+    let (msg, code) = if let Some(e) = e.downcast_ref::<DecodeError>() {
+        match e {
+            DecodeError::ExternalPalette => ("Input refers to a palette file; pass it with --palette", 2),
+            DecodeError::PaletteMismatch => ("Palette file does not match the one the input refers to", 4),
+            _ => ("Invalid image data", 4)
+        }
+    } else if let Some(e) = e.downcast_ref::<DrawError>() {
+        match e {
+            DrawError::NonSquare |
+            DrawError::NonPowerOfTwo => ("Invalid output dimensions", 2),
+            DrawError::ColorOutOfRange => ("Invalid image data", 4)
+        }
+    } else if let Some(e) = e.downcast_ref::<EncodeError>() {
+        match e {
+            EncodeError::OverBudget => ("Target size is too small for any quadtree with this palette", 4),
+            EncodeError::QualityUnreachable => ("Target quality is out of reach with this palette", 4),
+            // The quadtree is generated programmatically from an image, so
+            // this is a bug in the program to be fixed.
+            _ => ("Failure to serialize to QIM", 10)
+        }
+    } else if let Some(e) = e.downcast_ref::<AnalyzeError>() {
+        match e {
+            AnalyzeError::NonSquare |
+            AnalyzeError::NonPowerOfTwo |
+            AnalyzeError::EdgeMapMismatch => ("Input image has invalid dimensions", 4),
+            // As with serialization, this is a bug in the program.
+            AnalyzeError::ColorOutOfRange => ("Failure to quantize the input image", 10)
+        }
+    } else {
+        exit(&e.to_string(), 10)
+    };
+    exit(msg, code)
+}
+
+// Reads a palette file in the format its extension indicates.
+fn load_palette(path: &str) -> quompressor::DynamicPaletteView {
+    match PaletteFormat::from_path(path).read(&read_file(path)) {
+        Ok(colors) => colors.into(),
+        Err(_) => exit("Invalid palette file", 4)
+    }
+}
+
+// Writes a palette file in the format its extension indicates.
+fn save_palette(path: &str, colors: &[quantization::palette::Color]) {
+    match PaletteFormat::from_path(path).write(colors) {
+        Ok(data) => write_file(path, &data),
+        Err(_) => exit("Too many colors for the palette file format", 2)
+    }
+}
+
+// Reads the `--prefilter` option.
+fn prefilter(matches: &clap::ArgMatches) -> PreFilter {
+    match matches.value_of("prefilter").unwrap_or("none").parse() {
+        Ok(f) => f,
+        Err(_) => exit("Unknown pre-filter", 2)
+    }
+}
+
+// Reads the palette generation settings shared by `--into` and
+// `palette generate` (dedup threshold, generator, color count, metric, alpha
+// handling, blending space and pre-filter) into an `EncodeOptions`, leaving
+// every other option at its default.
+fn palette_settings(matches: &clap::ArgMatches) -> EncodeOptions {
+    EncodeOptions {
+		dedup: match matches.value_of("dedup").unwrap_or("256").parse() {
+			Ok(n) => n,
+			Err(_) => exit("Non-numeric value for dedup", 2)
+		},
+		palette_algo: match matches.value_of("palette-algo").unwrap_or("dedup").parse::<quantization::PaletteAlgorithm>() {
+			Ok(a) => a,
+			Err(_) => exit("Unknown palette generation strategy", 2)
+		},
+		max_colors: match matches.value_of("colors").map(str::parse::<usize>) {
+			None => None,
+			Some(Ok(n)) => Some(n),
+			Some(Err(_)) => exit("Non-numeric value for colors", 2)
+		},
+		metric: match matches.value_of("metric").unwrap_or("srgb").parse::<quantization::metric::ColorMetric>() {
+			Ok(m) => m,
+			Err(_) => exit("Unknown color metric", 2)
+		},
+		alpha: match matches.value_of("alpha").unwrap_or("full").parse::<quantization::alpha::AlphaMode>() {
+			Ok(a) => a,
+			Err(_) => exit("Unknown alpha handling mode", 2)
+		},
+		blending: match matches.value_of("blend").unwrap_or("srgb").parse::<Blending>() {
+			Ok(b) => b,
+			Err(_) => exit("Unknown blending space", 2)
+		},
+		prefilter: prefilter(matches),
+		..Default::default()
+	}
+}
+
+// Parses a byte count such as `4096`, `200KB` or `1.5MiB`
+// (KB and MB are powers of 1000, KiB and MiB powers of 1024).
+fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let factor = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1.,
+        "k" | "kb" => 1e3,
+        "kib" => 1024.,
+        "m" | "mb" => 1e6,
+        "mib" => 1048576.,
+        _ => return None
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if n >= 0. => Some((n * factor) as usize),
+        _ => None
+    }
+}
+
+// Prints the settings a target size or quality was met with.
+fn report_fit(report: &quompressor::EncodeReport, options: &EncodeOptions) {
+    if options.target_size.is_none() && options.target_quality.is_none() {
+        return;
+    }
+    let trim = options.trim.map_or("off".to_string(), |policy| policy.to_string());
+    eprintln!("Fitted in {} bytes with {}, blur {}, trim {}", report.size, report.split, options.blur_amount(), trim);
+    if let (Some(quality), Some(floor)) = (report.quality, options.target_quality) {
+        eprintln!("Reached {}", floor.describe(quality));
+    }
+}
+
 fn main() {
     let cli_matches = clap::App::new("quompressor")
         .version("0.1.0")
@@ -22,110 +189,213 @@ fn main() {
         .arg_from_usage("-i, --into 'Convert the input file from PNG or JFIF to QIM'")
         .arg_from_usage("-f, --from 'Convert the input file from QIM to PNG'")
         .arg_from_usage("-d, --dedup=[N] 'Color distance threshold for palette deduplication (--into only); defaults to 256'")
-		.arg_from_usage("-b, --blur=[N] 'Amount of precompression blur (--into only); defaults to 1'")
+		.arg_from_usage("-b, --blur=[N] 'Amount of precompression blur (--into only); defaults to 1, or 0 with --prefilter'")
+		.arg_from_usage("--prefilter=[FILTER] 'Edge-preserving noise filter applied before palette generation and quantization: none, bilateral[:RADIUS[:SIGMA]], median[:RADIUS] or guided[:RADIUS[:SIGMA]]; defaults to none (--into only)'")
 		.arg_from_usage("-s, --sensitivity=[N] 'Noise sensitivity as a fraction S/(S+1) (--into only); defaults to 63/64'")
-		.arg_from_usage("-t, --trim=[N] 'Number of times to trim output (--into only); defaults to 0'")
+		.arg_from_usage("--target-size=[SIZE] 'Byte budget such as 200KB or 1MiB; the finest --sensitivity (or --max-error) that fits is searched for (--into only)'")
+		.arg_from_usage("--target-quality=[FLOOR] 'Lowest quality to reach, psnr:N (dB) or ssim:N; the coarsest --sensitivity (or --max-error) that does is searched for (--into only)'")
+		.arg_from_usage("--prune=[LAMBDA] 'Rate-distortion pruning: drop detail costing more than LAMBDA squared --metric distance units per bit; splits finely unless --sensitivity or --max-error is given (--into only)'")
+		.arg_from_usage("-e, --max-error=[LIMIT] 'Split squares by color error instead of --sensitivity: rms:N (root mean square) or max:N (worst pixel), in --metric distance units (--into only)'")
+		.arg_from_usage("--edge-weight=[W] 'How much more readily squares along sharp edges get split; a hard edge halves the error a square 2W pixels wide may hide (--into only); defaults to 0'")
+		.arg_from_usage("--refit=[STAT] 'Recolor each leaf with the palette color nearest to the mean or median of its source pixels, keeping the file size (--into only)'")
+		.arg_from_usage("-t, --trim=[POLICY] 'Trim policy (--into only): `default`, or settings such as depth=6,majority=2,distance=N,keep=parent|majority|average; or 0 for no trimming and 1 for the default policy'")
+		.arg_from_usage("--palette-algo=[NAME] 'Palette generation strategy: dedup, octree or median-cut (--into only); defaults to dedup'")
+		.arg_from_usage("-c, --colors=[N] 'Maximum number of palette colors; the least used ones are merged into the others (--into only); no limit by default'")
+		.arg_from_usage("-m, --metric=[NAME] 'Color distance metric: srgb, cielab, oklab or ciede2000 (--into only); defaults to srgb'")
+		.arg_from_usage("--dither=[NAME] 'Dithering: none, floyd-steinberg, atkinson, bayer or tree[:BLOCK] (--into only); defaults to none'")
+		.arg_from_usage("--alpha=[MODE] 'Alpha handling: full, or binary[:THRESHOLD] for hard-edged sprites (--into only); defaults to full'")
+		.arg_from_usage("--blend=[SPACE] 'Space colors are averaged and gradients interpolated in: srgb or linear (--into only); defaults to srgb'")
 		.arg_from_usage("-w, --width=[N] 'Output image width (and, for now, also height) (--from only); must be a power of two; defaults to 512'")
+		.arg(clap::Arg::from_usage("-p, --palette=[FILE] 'Palette file (.qpal, .gpl, .act or .pal) to encode with instead of generating a palette (--into), or to decode QIM files referring to it (--from)'")
+			.alias("palette-file"))
+		.arg_from_usage("--builtin-palette=[NAME] 'Encode against a standard palette: mono, gray2, gray4, gray8, cga, ega, web-safe or gameboy (--into only)'")
+		.arg_from_usage("--reference-palette 'Refer to the palette file by hash instead of embedding the palette (--into only); requires --palette'")
         .arg_from_usage("<INPUT> 'Path to input file`")
 		.arg_from_usage("[OUTPUT] 'Path to output file; defaults to INPUT with a modified file extension`")
+		.subcommand_negates_reqs(true)
+		.subcommand(clap::App::new("palette")
+			.about("Manages palette files shared by several images")
+			.subcommand_required(true)
+			.subcommand(clap::App::new("generate")
+				.about("Computes one palette over a set of images and saves it as a palette file")
+				.arg_from_usage("-o, --output=<FILE> 'Path to output palette file; the format (.qpal, .gpl, .act or .pal) follows the extension'")
+				.arg_from_usage("--prefilter=[FILTER] 'Edge-preserving noise filter applied before palette generation: none, bilateral[:RADIUS[:SIGMA]], median[:RADIUS] or guided[:RADIUS[:SIGMA]]; defaults to none'")
+				.arg_from_usage("-d, --dedup=[N] 'Color distance threshold for palette deduplication; defaults to 256'")
+				.arg_from_usage("--palette-algo=[NAME] 'Palette generation strategy: dedup, octree or median-cut; defaults to dedup'")
+				.arg_from_usage("-c, --colors=[N] 'Maximum number of palette colors; no limit by default'")
+				.arg_from_usage("-m, --metric=[NAME] 'Color distance metric: srgb, cielab, oklab or ciede2000; defaults to srgb'")
+				.arg_from_usage("--alpha=[MODE] 'Alpha handling: full, or binary[:THRESHOLD]; defaults to full'")
+				.arg_from_usage("--blend=[SPACE] 'Space colors are averaged in: srgb or linear; defaults to srgb'")
+				.arg_from_usage("<INPUTS>... 'Paths to input images'"))
+			.subcommand(clap::App::new("export")
+				.about("Extracts the palette of a QIM file into a palette file")
+				.arg_from_usage("<INPUT> 'Path to input QIM file'")
+				.arg_from_usage("<OUTPUT> 'Path to output palette file; the format (.qpal, .gpl, .act or .pal) follows the extension'")))
         .get_matches();
 
+    if let Some(("palette", palette_matches)) = cli_matches.subcommand() {
+        match palette_matches.subcommand() {
+            Some(("generate", matches)) => {
+                let options = palette_settings(matches);
+                let sources = matches.values_of("INPUTS").unwrap()
+                    .map(|path| options.prefilter.apply_owned(open_image(path)))
+                    .collect::<Vec<_>>();
+                let generator = options.palette_algo.generator(options.dedup, options.max_colors, options.metric, options.blending);
+                let palette: quompressor::DynamicPaletteView =
+					quantization::generate_batch_palette(&sources, generator.as_ref(), options.alpha);
+                eprintln!("{} colors in generated palette", palette.colors.len());
+                save_palette(matches.value_of("output").unwrap(), &palette.colors);
+            },
+            Some(("export", matches)) => {
+                let colors = match quompressor::embedded_palette(&read_file(matches.value_of("INPUT").unwrap())) {
+					Ok(colors) => colors,
+					Err(DecodeError::ExternalPalette) => exit("Input refers to a palette file instead of embedding its palette", 2),
+					Err(_) => exit("Invalid image data", 4)
+				};
+                save_palette(matches.value_of("OUTPUT").unwrap(), &colors);
+            },
+            _ => unreachable!("a palette subcommand is required")
+        }
+        return;
+    }
+
     let (into, from) = (cli_matches.is_present("into"), cli_matches.is_present("from"));
     match (into, from) {
         (true, true) => exit("Only one of -i/--into and -f/--from must be present", 2),
         (true, false) => {
             let path = cli_matches.value_of("INPUT").unwrap();
-            let source = match image::open(path) {
-                Ok(i) => i,
-                Err(e) => {
-                    let (msg, code) = match e {
-                        ImageError::Decoding(_) => ("Invalid image data", 4),
-                        ImageError::Limits(_) => ("Computation limits exceeded", 5),
-                        ImageError::IoError(_) => ("File not found or could not be read", 3),
-                        _ => ("An error occurred", 10)
-                    };
-                    exit(msg, code);
-                }
-            }.into_rgba8();
-            let (dedup, blur, sensitivity, trim) = (
-				match cli_matches.value_of("dedup").unwrap_or("256").parse() {
-					Ok(n) => n,
-					Err(_) => exit("Non-numeric value for dedup", 2)
-				},
-				match cli_matches.value_of("blur").unwrap_or("1").parse() {
+            let source = open_image(path);
+            let (blur, sensitivity, trim, dither) = (
+				cli_matches.value_of("blur").map(|b| match b.parse() {
 					Ok(n) => n,
 					Err(_) => exit("Non-numeric value for blur", 2)
-				},
-				match cli_matches.value_of("sensitivity").unwrap_or("63").parse::<usize>() {
+				}),
+				cli_matches.value_of("sensitivity").map(|s| match s.parse::<usize>() {
 					Ok(n) => (16384 * n) / (n + 1),
 					Err(_) => exit("Non-numeric value for sensitivity", 2)
+				}),
+				// Numbers used to count trim passes; trimming now runs to a fixed
+				// point, so only 0 and 1 are left to turn it off or on.
+				match cli_matches.value_of("trim").map(|t| (t, t.parse::<usize>())) {
+					None | Some((_, Ok(0))) => None,
+					Some((_, Ok(1))) => Some(TrimPolicy::default()),
+					Some((_, Ok(_))) => exit("Trim no longer counts passes: use 0, 1 or a policy such as depth=6", 2),
+					Some((t, Err(_))) => match t.parse::<TrimPolicy>() {
+						Ok(policy) => Some(policy),
+						Err(_) => exit("Invalid trim policy", 2)
+					}
 				},
-				match cli_matches.value_of("trim").unwrap_or("0").parse::<usize>() {
-					Ok(n) => n,
-					Err(_) => exit("Non-numeric value for trim", 2)
+				match cli_matches.value_of("dither").unwrap_or("none").parse::<quantization::dither::Dither>() {
+					Ok(d) => d,
+					Err(_) => exit("Unknown dithering method", 2)
 				}
 			);
+			let reference_palette = cli_matches.is_present("reference-palette");
 
-            let palette = quantization::generate_palette::
-				<quantization::palette::DynamicPaletteView>(&source, dedup);
-			eprintln!("{} colors in generated palette", palette.colors.len());
-			let mut tree: QuadtreeNode<_> = Default::default();
+			let builtin_palette = match cli_matches.value_of("builtin-palette")
+				.map(str::parse::<quantization::builtin::BuiltinPalette>) {
+				None => None,
+				Some(Ok(b)) => Some(b),
+				Some(Err(_)) => exit("Unknown built-in palette", 2)
+			};
 
-			match tree.from_image(&source, &palette, sensitivity, blur, true) {
-				Ok(()) => (),
-				Err(_) => exit("Input image has invalid dimensions", 4)
-			}
-			for _ in 0..trim {
-				tree.trim(6);
-			}
-            // `.expect()` is valid here, because the only error that can occur here
-			// is a color in the quadtree out of range of the palette, but since the
-			// quadtree is generated programmatically from an image, that should not
-			// happen. If it does happen, there is a bug in the program to be fixed.
-			let qim_data = tree.to_qim(&palette).expect("failure to serialize to QIM");
-			let mut out_fh = match File::create(cli_matches.value_of("OUTPUT")
-				.unwrap_or(&(path.rsplitn(2, '.').last().unwrap().to_string() + ".qim"))) {
-				Ok(f) => f,
-				Err(_) => exit("Could not open output file", 3)
+			let settings = palette_settings(&cli_matches);
+			let (metric, blending) = (settings.metric, settings.blending);
+			let max_error = match cli_matches.value_of("max-error").map(str::parse::<ErrorLimit>) {
+				None => None,
+				Some(Ok(limit)) => Some(limit),
+				Some(Err(_)) => exit("Invalid error limit, expected rms:N or max:N", 2)
 			};
-			match out_fh.write_all(&qim_data) {
-				Ok(_) => (),
-				Err(_) => exit("Could not write to output file", 3)
-			}
+			let refit = match cli_matches.value_of("refit").map(str::parse::<Refit>) {
+				None => None,
+				Some(Ok(r)) => Some(r),
+				Some(Err(_)) => exit("Unknown leaf refit statistic, expected mean or median", 2)
+			};
+			let edge_weight = match cli_matches.value_of("edge-weight").map(str::parse::<f32>) {
+				None => 0.,
+				Some(Ok(w)) if w >= 0. => w,
+				Some(_) => exit("Invalid edge weight", 2)
+			};
+			let (target_size, target_quality, prune) = match (
+				cli_matches.value_of("target-size"),
+				cli_matches.value_of("target-quality"),
+				cli_matches.value_of("prune")
+			) {
+				(None, None, None) => (None, None, None),
+				(Some(size), None, None) => match parse_size(size) {
+					Some(n) => (Some(n), None, None),
+					None => exit("Invalid target size", 2)
+				},
+				(None, Some(floor), None) => match floor.parse::<QualityFloor>() {
+					Ok(f) => (None, Some(f), None),
+					Err(_) => exit("Invalid target quality, expected psnr:N or ssim:N", 2)
+				},
+				(None, None, Some(lambda)) => match lambda.parse::<f64>() {
+					Ok(l) if l >= 0. => (None, None, Some(l)),
+					_ => exit("Invalid pruning lambda", 2)
+				},
+				_ => exit("Only one of --target-size, --target-quality and --prune may be present", 2)
+			};
+			let options = EncodeOptions {
+				blur,
+				sensitivity,
+				max_error,
+				edge_weight,
+				refit,
+				trim: trim.map(|policy| TrimPolicy { metric, blending, ..policy }),
+				target_size,
+				target_quality,
+				prune,
+				dither,
+				reference_palette,
+				..settings
+			};
+			let (qim_data, report) = match (cli_matches.value_of("palette"), builtin_palette) {
+				(Some(_), Some(_)) => exit("Only one of --palette and --builtin-palette may be present", 2),
+				(None, _) if reference_palette => exit("--reference-palette requires --palette", 2),
+				(Some(palette_path), None) => {
+					match quompressor::encode_image_with(&source, load_palette(palette_path), &options) {
+						Ok(encoded) => (encoded.qim, encoded.report),
+						Err(e) => fail(e)
+					}
+				},
+				(None, Some(builtin)) => quompressor::with_builtin_palette!(builtin, palette =>
+					match quompressor::encode_image_with(&source, palette, &options) {
+						Ok(encoded) => (encoded.qim, encoded.report),
+						Err(e) => fail(e)
+					}),
+				(None, None) => {
+					match quompressor::encode_image(&source, &options) {
+						Ok(encoded) => {
+							eprintln!("{} colors in generated palette", encoded.palette.colors.len());
+							(encoded.qim, encoded.report)
+						},
+						Err(e) => fail(e)
+					}
+				}
+			};
+			report_fit(&report, &options);
+			write_file(
+				cli_matches.value_of("OUTPUT")
+					.unwrap_or(&(path.rsplitn(2, '.').last().unwrap().to_string() + ".qim")),
+				&qim_data
+			);
         },
         (false, true) => {
             let input_path = cli_matches.value_of("INPUT").unwrap();
-			let mut source_data = Vec::new();
-			let mut source_fh = match File::open(input_path) {
-				Ok(f) => f,
-				Err(_) => exit("File not found or could not be read", 3)
-			};
-			match source_fh.read_to_end(&mut source_data) {
-				Ok(_) => (),
-				Err(_) => exit("Could not read from input file", 3)
-			}
-			let (tree, palette): (_, quantization::palette::DynamicPaletteView) =
-				match QuadtreeNode::from_qim(&source_data) {
-				Ok((t, p)) => (t, p),
-				Err(_) => exit("Invalid image data", 4)
-			};
+			let source_data = read_file(input_path);
 			let width = match cli_matches.value_of("width").unwrap_or("512").parse() {
 				Ok(n) => n,
 				Err(_) => exit("Non-numeric value for width", 2)
 			};
-            let mut output = image::RgbaImage::new(width, width);
-
-			match tree.to_image(&mut output, &palette, None, None, true) {
-				Ok(_) => (),
-				Err(e) => {
-					let (msg, code) = match e {
-						DrawError::NonSquare |
-						DrawError::NonPowerOfTwo => ("Invalid output dimensions", 2),
-						DrawError::ColorOutOfRange => ("Invalid image data", 4)
-					};
-					exit(msg, code)
-				}
-			}
+			let decoded = match cli_matches.value_of("palette") {
+				Some(palette_path) => quompressor::decode_qim_with_palette(&source_data, width, load_palette(palette_path)),
+				None => quompressor::decode_qim(&source_data, width)
+			};
+			let output = match decoded {
+				Ok(img) => img,
+				Err(e) => fail(e)
+			};
 			match output.save(cli_matches.value_of("OUTPUT")
 				.unwrap_or(&(input_path.rsplitn(2, '.').last().unwrap().to_string() + ".png"))) {
 				Ok(_) => (),
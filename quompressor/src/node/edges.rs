@@ -0,0 +1,78 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+// Edge strength of every pixel of an image, from 0 on flat areas to 1 on a
+// hard step from black to white, and how much `mount` favors splitting
+// squares along them.
+//
+// Strengths are Sobel gradient magnitudes, the larger of those of luma
+// (composited over black) and alpha. A hard edge crossing a square `s`
+// pixels wide gives it a mean strength of about `2 / s`; the error a
+// square may hide is divided by `1 + weight * mean strength`, so with a
+// weight `w` such a square tolerates half its usual error at `s = 2 * w`,
+// and flat areas are left alone.
+#[derive(Clone, Debug)]
+pub struct EdgeMap {
+	strengths: Vec<f32>,
+	row_len: usize,
+	pub weight: f32,
+}
+
+impl EdgeMap {
+	pub fn new(img: &image::RgbaImage, weight: f32) -> Self {
+		let (width, height) = (img.width() as i64, img.height() as i64);
+		let channels = img.pixels()
+			.map(|p| {
+				let luma = 0.2126 * p.0[0] as f32 + 0.7152 * p.0[1] as f32 + 0.0722 * p.0[2] as f32;
+				[luma * p.0[3] as f32 / 255., p.0[3] as f32]
+			})
+			.collect::<Vec<_>>();
+		// Pixels past the borders repeat those on them.
+		let at = |x: i64, y: i64, chan: usize|
+			channels[(y.clamp(0, height - 1) * width + x.clamp(0, width - 1)) as usize][chan];
+		let mut strengths = Vec::with_capacity(channels.len());
+		for y in 0..height {
+			for x in 0..width {
+				let magnitude = (0..2).map(|chan| {
+					let gx = at(x + 1, y - 1, chan) + 2. * at(x + 1, y, chan) + at(x + 1, y + 1, chan)
+						- at(x - 1, y - 1, chan) - 2. * at(x - 1, y, chan) - at(x - 1, y + 1, chan);
+					let gy = at(x - 1, y + 1, chan) + 2. * at(x, y + 1, chan) + at(x + 1, y + 1, chan)
+						- at(x - 1, y - 1, chan) - 2. * at(x, y - 1, chan) - at(x + 1, y - 1, chan);
+					gx.hypot(gy)
+				})
+				.fold(0., f32::max);
+				// A step from 0 to 255 gives a magnitude of 4 * 255.
+				strengths.push((magnitude / 1020.).min(1.));
+			}
+		}
+		EdgeMap { strengths, row_len: width as usize, weight }
+	}
+
+	// Number of pixels of the image the map was made from.
+	pub fn pixels(&self) -> usize {
+		self.strengths.len()
+	}
+
+	// Edge strength of the pixel at `(x, y)`.
+	pub fn at(&self, x: usize, y: usize) -> f32 {
+		self.strengths[y * self.row_len + x]
+	}
+
+	// Factor the error tolerated in a square is scaled by, given the mean
+	// edge strength over it.
+	pub(crate) fn tolerance(weight: f32, mean_strength: f64) -> f64 {
+		1. / (1. + weight as f64 * mean_strength)
+	}
+}
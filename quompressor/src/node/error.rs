@@ -22,6 +22,10 @@ pub enum AnalyzeError {
 	NonSquare,
 	// The image buffer's dimensions are not powers of two.
 	NonPowerOfTwo,
+	// The edge map is not the size of the image.
+	EdgeMapMismatch,
+	// A pixel was quantized to a color outside the range of the palette.
+	ColorOutOfRange,
 }
 
 impl fmt::Display for AnalyzeError {
@@ -31,6 +35,10 @@ impl fmt::Display for AnalyzeError {
                 write!(f, "the image buffer's dimensions are not equal; the image is not a square."),
             AnalyzeError::NonPowerOfTwo =>
                 write!(f, "the image buffer's dimensions are not powers of two."),
+            AnalyzeError::EdgeMapMismatch =>
+                write!(f, "the edge map is not the size of the image."),
+            AnalyzeError::ColorOutOfRange =>
+                write!(f, "a pixel was quantized to a color outside the range of the palette."),
         }
     }
 }
@@ -40,6 +48,8 @@ impl error::Error for AnalyzeError {
         match *self {
             AnalyzeError::NonSquare => None,
             AnalyzeError::NonPowerOfTwo => None,
+            AnalyzeError::EdgeMapMismatch => None,
+            AnalyzeError::ColorOutOfRange => None,
         }
     }
 }
@@ -49,6 +59,17 @@ impl error::Error for AnalyzeError {
 pub enum EncodeError {
 // A color specified in the quadtree is outside the range of the palette.
 	ColorOutOfRange,
+	// The palette has more colors than the palette file format can hold.
+	TooManyColors,
+	// Even a quadtree of a single node doesn't fit in the byte budget.
+	OverBudget,
+	// Even the finest quadtree falls short of the quality floor.
+	QualityUnreachable,
+	// The image to mount is not a square whose side is a power of two, or
+	// its edge map or source image is not the size of it.
+	InvalidImage,
+	// A pixel of the image to mount has a color outside the range of the palette.
+	PixelOutOfRange,
 }
 
 impl fmt::Display for EncodeError {
@@ -56,6 +77,16 @@ impl fmt::Display for EncodeError {
         match *self {
             EncodeError::ColorOutOfRange =>
                 write!(f, "a color specified in the quadtree is outside the range of the palette."),
+            EncodeError::TooManyColors =>
+                write!(f, "the palette has more colors than the palette file format can hold."),
+            EncodeError::OverBudget =>
+                write!(f, "even a quadtree of a single node doesn't fit in the byte budget."),
+            EncodeError::QualityUnreachable =>
+                write!(f, "even the finest quadtree falls short of the quality floor."),
+            EncodeError::InvalidImage =>
+                write!(f, "the image to mount is not a square whose side is a power of two, or doesn't match its edge map or source."),
+            EncodeError::PixelOutOfRange =>
+                write!(f, "a pixel of the image to mount has a color outside the range of the palette."),
         }
     }
 }
@@ -64,6 +95,11 @@ impl error::Error for EncodeError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
             EncodeError::ColorOutOfRange => None,
+            EncodeError::TooManyColors => None,
+            EncodeError::OverBudget => None,
+            EncodeError::QualityUnreachable => None,
+            EncodeError::InvalidImage => None,
+            EncodeError::PixelOutOfRange => None,
         }
     }
 }
@@ -75,6 +111,16 @@ pub enum DecodeError {
 	InsufficientData,
 	// There was no valid QIM file header.
 	MissingHeader,
+	// The QIM data refers to an external palette, which was not supplied.
+	ExternalPalette,
+	// The supplied palette is not the one the QIM data refers to.
+	PaletteMismatch,
+	// A line of a text palette file could not be read as a color.
+	MalformedPalette,
+	// The palette file holds no colors.
+	EmptyPalette,
+	// The quadtree is deeper than that of any image a `u32` can give the size of.
+	TooDeep,
 }
 
 impl fmt::Display for DecodeError {
@@ -84,6 +130,16 @@ impl fmt::Display for DecodeError {
                 write!(f, "a node number was exepcted but not found."),
 			DecodeError::MissingHeader =>
                 write!(f, "there was no valid QIM file header."),
+			DecodeError::ExternalPalette =>
+                write!(f, "the QIM data refers to an external palette, which was not supplied."),
+			DecodeError::PaletteMismatch =>
+                write!(f, "the supplied palette is not the one the QIM data refers to."),
+			DecodeError::MalformedPalette =>
+                write!(f, "a line of a text palette file could not be read as a color."),
+			DecodeError::EmptyPalette =>
+                write!(f, "the palette file holds no colors."),
+			DecodeError::TooDeep =>
+                write!(f, "the quadtree is deeper than that of any image a `u32` can give the size of."),
         }
     }
 }
@@ -92,7 +148,12 @@ impl error::Error for DecodeError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
             DecodeError::InsufficientData => None,
-			DecodeError::MissingHeader => None
+			DecodeError::MissingHeader => None,
+			DecodeError::ExternalPalette => None,
+			DecodeError::PaletteMismatch => None,
+			DecodeError::MalformedPalette => None,
+			DecodeError::EmptyPalette => None,
+			DecodeError::TooDeep => None
         }
     }
 }
@@ -139,3 +200,77 @@ impl error::Error for DrawError {
         }
     }
 }
+
+// An encoder option was given by a name that means nothing to the encoder.
+#[derive(Debug)]
+pub enum UnknownOption {
+	// No palette generation strategy goes by the given name.
+	PaletteAlgorithm(String),
+	// No color metric goes by the given name.
+	ColorMetric(String),
+	// No dithering method goes by the given name.
+	Dither(String),
+	// No built-in palette goes by the given name.
+	BuiltinPalette(String),
+	// No alpha handling mode goes by the given name.
+	AlphaMode(String),
+	// No color blending space goes by the given name.
+	Blending(String),
+	// The given string is not a valid error limit.
+	ErrorLimit(String),
+	// The given string is not a valid quality floor.
+	QualityFloor(String),
+	// The given string is not a valid trim policy.
+	TrimPolicy(String),
+	// The given string is not a valid leaf refit statistic.
+	Refit(String),
+	// The given string is not a valid pre-filter.
+	PreFilter(String),
+}
+
+impl fmt::Display for UnknownOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnknownOption::PaletteAlgorithm(ref name) =>
+                write!(f, "no palette generation strategy goes by the name `{}`.", name),
+            UnknownOption::ColorMetric(ref name) =>
+                write!(f, "no color metric goes by the name `{}`.", name),
+            UnknownOption::Dither(ref name) =>
+                write!(f, "no dithering method goes by the name `{}`.", name),
+            UnknownOption::BuiltinPalette(ref name) =>
+                write!(f, "no built-in palette goes by the name `{}`.", name),
+            UnknownOption::AlphaMode(ref name) =>
+                write!(f, "no alpha handling mode goes by the name `{}`.", name),
+            UnknownOption::Blending(ref name) =>
+                write!(f, "no color blending space goes by the name `{}`.", name),
+            UnknownOption::ErrorLimit(ref name) =>
+                write!(f, "`{}` is not an error limit; expected `rms:N` or `max:N`.", name),
+            UnknownOption::QualityFloor(ref name) =>
+                write!(f, "`{}` is not a quality floor; expected `psnr:N` or `ssim:N`.", name),
+            UnknownOption::TrimPolicy(ref name) =>
+                write!(f, "`{}` is not a trim policy; expected `default` or settings such as `depth=6,keep=average`.", name),
+            UnknownOption::Refit(ref name) =>
+                write!(f, "`{}` is not a leaf refit statistic; expected `mean` or `median`.", name),
+            UnknownOption::PreFilter(ref name) =>
+                write!(f, "`{}` is not a pre-filter; expected `none`, `bilateral[:R[:S]]`, `median[:R]` or `guided[:R[:S]]`.", name),
+        }
+    }
+}
+
+impl error::Error for UnknownOption {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            UnknownOption::PaletteAlgorithm(_) => None,
+            UnknownOption::ColorMetric(_) => None,
+            UnknownOption::Dither(_) => None,
+            UnknownOption::BuiltinPalette(_) => None,
+            UnknownOption::AlphaMode(_) => None,
+            UnknownOption::Blending(_) => None,
+            UnknownOption::ErrorLimit(_) => None,
+            UnknownOption::QualityFloor(_) => None,
+            UnknownOption::TrimPolicy(_) => None,
+            UnknownOption::Refit(_) => None,
+            UnknownOption::PreFilter(_) => None,
+        }
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use std::borrow::Cow;
+
+use super::error::UnknownOption;
+
+// Noise filters applied to an image before its palette is generated and it
+// is quantized. Unlike the Gaussian `blur`, they keep edges sharp.
+//
+// Channels, alpha included, are filtered as they are; pixels past the
+// borders repeat those on them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PreFilter {
+	#[default]
+	None,
+	// Averages the pixels within `radius`, weighted by closeness (a Gaussian
+	// of standard deviation `radius / 2`) and by similarity: colors further
+	// apart than about `sigma` (RGBA distance in bytes) hardly mix.
+	Bilateral { radius: u32, sigma: f32 },
+	// Takes, channel by channel, the median of the pixels within `radius`,
+	// which removes speckles up to about its size.
+	Median { radius: u32 },
+	// Guided filter (He et al.), each channel guiding itself over windows of
+	// `radius`: areas whose deviation is well below `sigma` (in bytes) are
+	// flattened, the others, edges among them, kept.
+	Guided { radius: u32, sigma: f32 },
+}
+
+impl PreFilter {
+	// The filtered image; `None` hands `img` back as it is, without a copy.
+	pub fn apply<'a>(&self, img: &'a image::RgbaImage) -> Cow<'a, image::RgbaImage> {
+		match *self {
+			PreFilter::None => Cow::Borrowed(img),
+			PreFilter::Bilateral { radius, sigma } => Cow::Owned(bilateral(img, radius as i64, sigma)),
+			PreFilter::Median { radius } => Cow::Owned(median(img, radius as i64)),
+			PreFilter::Guided { radius, sigma } => Cow::Owned(guided(img, radius as i64, sigma)),
+		}
+	}
+
+	// Same as `apply`, for an image that isn't needed unfiltered afterwards.
+	pub fn apply_owned(&self, img: image::RgbaImage) -> image::RgbaImage {
+		match *self {
+			PreFilter::None => img,
+			_ => self.apply(&img).into_owned(),
+		}
+	}
+}
+
+// The pixel at `(x, y)`, clamped to the image.
+fn clamped(img: &image::RgbaImage, x: i64, y: i64) -> &image::Rgba<u8> {
+	img.get_pixel(
+		x.clamp(0, img.width() as i64 - 1) as u32,
+		y.clamp(0, img.height() as i64 - 1) as u32
+	)
+}
+
+fn bilateral(img: &image::RgbaImage, radius: i64, sigma: f32) -> image::RgbaImage {
+	let spatial_sigma = (radius as f32 / 2.).max(0.5);
+	let spatial = (-radius..=radius)
+		.flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+		.map(|(dx, dy)| (dx, dy, (-((dx * dx + dy * dy) as f32) / (2. * spatial_sigma.powi(2))).exp()))
+		.collect::<Vec<_>>();
+	// Range weights by squared RGBA distance, which is at most 4 * 255^2.
+	let range = (0..=4 * 255 * 255)
+		.map(|d2| (-(d2 as f32) / (2. * sigma.max(f32::EPSILON).powi(2))).exp())
+		.collect::<Vec<_>>();
+	image::RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+		let center = img.get_pixel(x, y);
+		let mut sums = [0f32; 4];
+		let mut total = 0.;
+		for (dx, dy, weight) in spatial.iter() {
+			let c = clamped(img, x as i64 + dx, y as i64 + dy);
+			let d2 = (0..4).map(|chan| (c.0[chan] as i32 - center.0[chan] as i32).pow(2)).sum::<i32>();
+			let weight = weight * range[d2 as usize];
+			for (sum, v) in sums.iter_mut().zip(c.0) {
+				*sum += weight * v as f32;
+			}
+			total += weight;
+		}
+		image::Rgba(sums.map(|sum| (sum / total).round() as u8))
+	})
+}
+
+fn median(img: &image::RgbaImage, radius: i64) -> image::RgbaImage {
+	let mut window = Vec::with_capacity(((2 * radius + 1) * (2 * radius + 1)) as usize);
+	image::RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+		image::Rgba([0, 1, 2, 3].map(|chan| {
+			window.clear();
+			for dy in -radius..=radius {
+				for dx in -radius..=radius {
+					window.push(clamped(img, x as i64 + dx, y as i64 + dy).0[chan]);
+				}
+			}
+			let mid = window.len() / 2;
+			*window.select_nth_unstable(mid).1
+		}))
+	})
+}
+
+// Means over the windows of `radius` around every value of a `width` by
+// `height` plane, windows being cut at the borders.
+fn box_mean(plane: &[f64], width: usize, height: usize, radius: usize) -> Vec<f64> {
+	// Summed-area table, one row and column larger than the plane.
+	let mut table = vec![0.; (width + 1) * (height + 1)];
+	for y in 0..height {
+		let mut row_sum = 0.;
+		for x in 0..width {
+			row_sum += plane[y * width + x];
+			table[(y + 1) * (width + 1) + x + 1] = table[y * (width + 1) + x + 1] + row_sum;
+		}
+	}
+	let mut means = Vec::with_capacity(plane.len());
+	for y in 0..height {
+		let (top, bottom) = (y.saturating_sub(radius), (y + radius + 1).min(height));
+		for x in 0..width {
+			let (left, right) = (x.saturating_sub(radius), (x + radius + 1).min(width));
+			let sum = table[bottom * (width + 1) + right] - table[top * (width + 1) + right]
+				- table[bottom * (width + 1) + left] + table[top * (width + 1) + left];
+			means.push(sum / ((bottom - top) * (right - left)) as f64);
+		}
+	}
+	means
+}
+
+fn guided(img: &image::RgbaImage, radius: i64, sigma: f32) -> image::RgbaImage {
+	let (width, height) = (img.width() as usize, img.height() as usize);
+	let radius = radius as usize;
+	let epsilon = (sigma as f64).powi(2);
+	let mut out = img.clone();
+	for chan in 0..4 {
+		let plane = img.pixels().map(|p| p.0[chan] as f64).collect::<Vec<_>>();
+		let squares = plane.iter().map(|v| v * v).collect::<Vec<_>>();
+		let mean = box_mean(&plane, width, height, radius);
+		let mean_sq = box_mean(&squares, width, height, radius);
+		// Per window, `a * I + b` is the linear fit of the channel onto itself.
+		let a = mean.iter().zip(&mean_sq)
+			.map(|(m, sq)| {
+				let variance = (sq - m * m).max(0.);
+				variance / (variance + epsilon)
+			})
+			.collect::<Vec<_>>();
+		let b = mean.iter().zip(&a).map(|(m, a)| m - a * m).collect::<Vec<_>>();
+		let (mean_a, mean_b) = (box_mean(&a, width, height, radius), box_mean(&b, width, height, radius));
+		for (ind, pixel) in out.pixels_mut().enumerate() {
+			pixel.0[chan] = (mean_a[ind] * plane[ind] + mean_b[ind]).round().clamp(0., 255.) as u8;
+		}
+	}
+	out
+}
+
+impl std::str::FromStr for PreFilter {
+	type Err = UnknownOption;
+
+	// Accepts `none`, `bilateral[:RADIUS[:SIGMA]]`, `median[:RADIUS]` and
+	// `guided[:RADIUS[:SIGMA]]`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let err = || UnknownOption::PreFilter(s.to_string());
+		let mut parts = s.split(':');
+		let name = parts.next().unwrap_or_default();
+		let radius = match parts.next() {
+			None => None,
+			Some(r) => match r.parse::<u32>() {
+				Ok(r) if r > 0 => Some(r),
+				_ => return Err(err()),
+			},
+		};
+		let sigma = match parts.next() {
+			None => None,
+			Some(v) => match v.parse::<f32>() {
+				Ok(v) if v > 0. => Some(v),
+				_ => return Err(err()),
+			},
+		};
+		if parts.next().is_some() {
+			return Err(err());
+		}
+		match (name, radius, sigma) {
+			("none", None, None) => Ok(PreFilter::None),
+			("bilateral", _, _) => Ok(PreFilter::Bilateral { radius: radius.unwrap_or(2), sigma: sigma.unwrap_or(24.) }),
+			("median", _, None) => Ok(PreFilter::Median { radius: radius.unwrap_or(1) }),
+			("guided", _, _) => Ok(PreFilter::Guided { radius: radius.unwrap_or(4), sigma: sigma.unwrap_or(20.) }),
+			_ => Err(err()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn gray(v: u8) -> image::Rgba<u8> {
+		image::Rgba([v, v, v, 255])
+	}
+
+	// Black on the left half, white on the right one.
+	fn edge() -> image::RgbaImage {
+		image::RgbaImage::from_fn(8, 8, |x, _| if x < 4 { gray(0) } else { gray(255) })
+	}
+
+	fn all_filters() -> [PreFilter; 3] {
+		["bilateral", "median", "guided"].map(|s| s.parse().unwrap())
+	}
+
+	#[test]
+	fn filters_parse_with_defaults() {
+		assert_eq!("none".parse::<PreFilter>().unwrap(), PreFilter::None);
+		assert_eq!(all_filters(), [
+			PreFilter::Bilateral { radius: 2, sigma: 24. },
+			PreFilter::Median { radius: 1 },
+			PreFilter::Guided { radius: 4, sigma: 20. },
+		]);
+		assert_eq!("bilateral:3:10".parse::<PreFilter>().unwrap(), PreFilter::Bilateral { radius: 3, sigma: 10. });
+		assert_eq!("guided:2".parse::<PreFilter>().unwrap(), PreFilter::Guided { radius: 2, sigma: 20. });
+		for bad in ["", "none:1", "median:2:3", "bilateral:0", "guided:2:0", "guided:2:-1", "bilateral:1:2:3", "blur"] {
+			assert!(bad.parse::<PreFilter>().is_err(), "{}", bad);
+		}
+	}
+
+	#[test]
+	fn no_filter_copies_nothing() {
+		let img = edge();
+		assert!(matches!(PreFilter::None.apply(&img), Cow::Borrowed(_)));
+		assert_eq!(PreFilter::None.apply_owned(img.clone()), img);
+	}
+
+	#[test]
+	fn flat_images_are_left_alone() {
+		let img = image::RgbaImage::from_pixel(5, 3, image::Rgba([30, 60, 90, 200]));
+		for filter in all_filters() {
+			assert_eq!(filter.apply_owned(img.clone()), img, "{:?}", filter);
+		}
+	}
+
+	#[test]
+	fn edges_stay_sharp() {
+		let img = edge();
+		for filter in all_filters() {
+			let filtered = filter.apply(&img);
+			for (before, after) in img.pixels().zip(filtered.pixels()) {
+				assert!((0..4).all(|chan| before.0[chan].abs_diff(after.0[chan]) <= 8), "{:?}: {:?} to {:?}", filter, before, after);
+			}
+		}
+	}
+
+	#[test]
+	fn median_removes_speckles() {
+		let mut img = image::RgbaImage::from_pixel(5, 5, gray(100));
+		img.put_pixel(2, 2, gray(255));
+		img.put_pixel(0, 4, gray(0));
+		assert_eq!(PreFilter::Median { radius: 1 }.apply_owned(img), image::RgbaImage::from_pixel(5, 5, gray(100)));
+	}
+
+	// Noise well below `sigma` is flattened by both edge-preserving filters.
+	#[test]
+	fn faint_noise_is_smoothed() {
+		let img = image::RgbaImage::from_fn(8, 8, |x, y| gray(if (x + y) % 2 == 0 { 96 } else { 104 }));
+		for filter in [PreFilter::Bilateral { radius: 2, sigma: 24. }, PreFilter::Guided { radius: 2, sigma: 20. }] {
+			let filtered = filter.apply_owned(img.clone());
+			assert!(filtered.pixels().all(|p| (98..=102).contains(&p.0[0])), "{:?}", filter);
+		}
+	}
+}
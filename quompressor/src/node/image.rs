@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::edges::EdgeMap;
 use super::error::*;
+use super::quantization::QuantizeOptions;
+use super::quantization::gamma::{self, Blending};
 use super::quantization::palette::{Color, Palette};
 
 fn color_lerp(a: Color, b: Color, n: f64) -> Color {
@@ -24,43 +27,102 @@ fn color_lerp(a: Color, b: Color, n: f64) -> Color {
 	])
 }
 
+// Bilinear interpolation between the colors at the corners of a square
+// (top left, top right, bottom left, bottom right), at `(x, y)` in `0..1`.
+//
+// In `Blending::Linear`, the color channels are interpolated on linear
+// light and rounded only once, at the end.
+fn color_bilerp(corners: &[Color], x: f64, y: f64, blending: Blending) -> Color {
+	match blending {
+		Blending::Srgb => color_lerp(
+			color_lerp(corners[0], corners[1], x),
+			color_lerp(corners[2], corners[3], x),
+			y
+		),
+		Blending::Linear => {
+			let expanded = corners.iter()
+				.map(|c| [
+					gamma::linear(c.0[0]) as f64,
+					gamma::linear(c.0[1]) as f64,
+					gamma::linear(c.0[2]) as f64,
+					c.0[3] as f64,
+				])
+				.collect::<Vec<_>>();
+			let lerp = |a: f64, b: f64, n: f64| (b - a) * n + a;
+			let chan = |ind: usize| lerp(
+				lerp(expanded[0][ind], expanded[1][ind], x),
+				lerp(expanded[2][ind], expanded[3][ind], x),
+				y
+			);
+			image::Rgba([
+				gamma::srgb(chan(0) as f32),
+				gamma::srgb(chan(1) as f32),
+				gamma::srgb(chan(2) as f32),
+				chan(3) as u8,
+			])
+		}
+	}
+}
+
+// Blurs `img` by `blur` and maps its pixels onto the entries of `palette`,
+// giving the square of color numbers `mount` expects.
+pub fn palettify<P: Palette>(
+	img: &image::RgbaImage,
+	palette: &P,
+	blur: f32,
+	quantize: &QuantizeOptions
+) -> Result<Vec<u32>, AnalyzeError> {
+	// Validate image size
+	if img.width() != img.height() {
+		return Err(AnalyzeError::NonSquare);
+	}
+	if !img.width().is_power_of_two() {
+		return Err(AnalyzeError::NonPowerOfTwo);
+	}
+	let img_tr = if blur == 0. { img.to_owned() } else { image::imageops::blur(img, blur) };
+	Ok(super::quantization::quantize_to_palette(
+		&img_tr,
+		palette,
+		quantize
+	))
+}
+
 impl<P: Palette + Default> super::QuadtreeNode<P> {
     // Analyzes a traditional image into a quadtree, "rounding" pixel colors
 	// to the nearest entries in the palette.
 	//
-	// See documentation on `mount` for the meaning of `sensitivity`.
+	// See documentation on `mount` for the meaning of `split`.
 	//
 	// `blur` is the amount of Gaussian blur to apply to the image before
 	// quadtreeifying (to remove noise).
 	//
-	// `gradient` indicates whether or not to generate the quadtree in a way
-	// such that the resultant restored image will be of higher quality
-	// (in theory) if `gradient` is passed as `true` to `to_image`.
+	// `gradient` indicates whether or not squares may be mounted as gradient
+	// leaves (see `mount`), which `to_image` renders as gradients when its
+	// own `gradient` is `true`.
+	//
+	// `quantize` decides how pixels are mapped to palette entries (see
+	// `quantize_to_palette`).
+	//
+	// `edges`, the edge map of `img` (before blurring), makes squares along
+	// edges split more readily (see `EdgeMap`).
+	#[allow(clippy::wrong_self_convention, clippy::too_many_arguments)]
     pub fn from_image(
         &mut self,
         img: &image::RgbaImage,
         palette: &P,
-        sensitivity: usize,
+        split: super::split::SplitCriterion,
         blur: f32,
-        gradient: bool
+        gradient: bool,
+        quantize: &QuantizeOptions,
+        edges: Option<&EdgeMap>
     ) -> Result<(), AnalyzeError> {
-        // Validate image size
-        if img.width() != img.height() {
-            return Err(AnalyzeError::NonSquare);
+        let palettified = palettify(img, palette, blur, quantize)?;
+        // `palettify` makes a valid square, so only the edge map can be off.
+        match self.mount(&palettified, palette, None, None, split, gradient, edges) {
+            Ok(_) => Ok(()),
+            Err(MountError::InvalidSize) => Err(AnalyzeError::EdgeMapMismatch),
+            Err(MountError::ColorOutOfRange) => Err(AnalyzeError::ColorOutOfRange)
         }
-        if !img.width().is_power_of_two() {
-			return Err(AnalyzeError::NonPowerOfTwo);
-		}
-        let img_tr = if blur == 0. { img.to_owned() } else { image::imageops::blur(img, blur) };
-        let palettified = super::quantization::quantize_to_palette(
-            &img_tr,
-            palette
-        );
-        match self.mount(&palettified, palette, None, None, sensitivity, gradient) {
-            Ok(_) => (),
-            Err(_) => unreachable!("error in mounting")
-        }
-        Ok(())
     }
 
     // Attempts to generate an image into the supplied buffer
@@ -74,16 +136,18 @@ impl<P: Palette + Default> super::QuadtreeNode<P> {
 	// use; `None` should be passed by outside callers (unless you
 	// **really** know what you're doing).
 	//
-	// `gradient` indicates whether leaf nodes will be presented as
-	// solid squares of color or bilinear gradients between the leaf
-	// nodes below the relevant branch.
+	// `gradient` indicates whether gradient leaves will be presented as
+	// bilinear gradients between their corner colors, or as solid squares
+	// of their color like other leaves; `blending` is the space those
+	// gradients are interpolated in.
 	pub fn to_image(
 		&self,
 		img: &mut image::RgbaImage,
 		palette: &P,
 		size: Option<u32>,
 		start_pos: Option<(u32, u32)>,
-		gradient: bool
+		gradient: bool,
+		blending: Blending
 	) -> Result<(), DrawError> {
 		// Check input validity
 		if img.width() != img.height() {
@@ -97,57 +161,76 @@ impl<P: Palette + Default> super::QuadtreeNode<P> {
 		// Draw current node
 		let curr_size = size.unwrap_or_else(|| img.width());
 		let curr_pos = start_pos.unwrap_or((0, 0));
-		match palette.to_rgba(self.color) {
-			Ok(c) => image::imageops::replace(
-				img,
-				&image::RgbaImage::from_pixel(curr_size, curr_size, c),
-				curr_pos.0.into(),
-				curr_pos.1.into(),
-			),
-			Err(_) => return Err(DrawError::ColorOutOfRange),
+		// Gradient leaves shrunk to a pixel are drawn in their color.
+		match (self.gradient, gradient) {
+			(Some(corners), true) if curr_size > 1 => {
+				let corner_colors = corners.iter()
+					.map(|c| palette.to_rgba(*c))
+					.try_fold(Vec::new(), |mut l, n| match n {
+						Ok(c) => { l.push(c); Ok(l) },
+						Err(_) => Err(DrawError::ColorOutOfRange)
+					})?;
+				for row in curr_pos.1..(curr_pos.1 + curr_size) {
+					for col in curr_pos.0..(curr_pos.0 + curr_size) {
+						let x_n = ((col - curr_pos.0) as f64) / curr_size as f64;
+						let y_n = ((row - curr_pos.1) as f64) / curr_size as f64;
+						let imm_c = color_bilerp(&corner_colors, x_n, y_n, blending);
+						img.put_pixel(col, row, imm_c);
+					}
+				}
+			},
+			_ => match palette.to_rgba(self.color) {
+				Ok(c) => image::imageops::replace(
+					img,
+					&image::RgbaImage::from_pixel(curr_size, curr_size, c),
+					curr_pos.0.into(),
+					curr_pos.1.into(),
+				),
+				Err(_) => return Err(DrawError::ColorOutOfRange),
+			}
 		}
 
 		// Recursion
 		if curr_size > 1 {
 			if let Some(ref sects) = self.sections {
-				if gradient && sects.iter().all(|s| s.sections.is_none()) {
-					for row in curr_pos.1..(curr_pos.1 + curr_size) {
-						for col in curr_pos.0..(curr_pos.0 + curr_size) {
-							let sect_colors = sects.iter()
-								.map(|s| palette.to_rgba(s.color))
-								.fold(Ok(Vec::new()), |v, n| match (v, n) {
-									(Ok(mut l), Ok(c)) => { l.push(c); Ok(l) },
-									_ => Err(DrawError::ColorOutOfRange)
-								})?;
-							let x_n = ((col - curr_pos.0) as f64) / curr_size as f64;
-							let y_n = ((row - curr_pos.1) as f64) / curr_size as f64;
-							let imm_c = color_lerp(
-								color_lerp(sect_colors[0], sect_colors[1], x_n),
-								color_lerp(sect_colors[2], sect_colors[3], x_n),
-								y_n
-							);
-							img.put_pixel(col, row, imm_c);
-						}
-					}
-				} else {
-					let positions = [
-						(curr_pos.0, curr_pos.1),
-						(curr_pos.0 + curr_size / 2, curr_pos.1),
-						(curr_pos.0, curr_pos.1 + curr_size / 2),
-						(curr_pos.0 + curr_size / 2, curr_pos.1 + curr_size / 2),
-					];
-					for (ind, section) in sects.iter().enumerate() {
-						section.to_image(
-							img,
-							palette,
-							Some(curr_size / 2),
-							Some(positions[ind]),
-							gradient
-						)?;
-					}
+				let positions = [
+					(curr_pos.0, curr_pos.1),
+					(curr_pos.0 + curr_size / 2, curr_pos.1),
+					(curr_pos.0, curr_pos.1 + curr_size / 2),
+					(curr_pos.0 + curr_size / 2, curr_pos.1 + curr_size / 2),
+				];
+				for (ind, section) in sects.iter().enumerate() {
+					section.to_image(
+						img,
+						palette,
+						Some(curr_size / 2),
+						Some(positions[ind]),
+						gradient,
+						blending
+					)?;
 				}
 			}
 		}
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::node::QuadtreeNode;
+	use crate::node::quantization::palette::DynamicPaletteView;
+	use crate::node::split::SplitCriterion;
+
+	#[test]
+	fn from_image_rejects_a_mismatched_edge_map() {
+		let palette = DynamicPaletteView::from(vec![image::Rgba([0, 0, 0, 255]), image::Rgba([255; 4])]);
+		let img = image::RgbaImage::from_fn(8, 8, |x, _| if x < 4 { palette.colors[0] } else { palette.colors[1] });
+		let edges = EdgeMap::new(&image::RgbaImage::new(4, 4), 1.);
+		let mut tree = QuadtreeNode::<DynamicPaletteView>::default();
+		let result = tree.from_image(&img, &palette, SplitCriterion::default(), 0., true, &Default::default(), Some(&edges));
+		assert!(matches!(result, Err(AnalyzeError::EdgeMapMismatch)));
+		let edges = EdgeMap::new(&img, 1.);
+		assert!(tree.from_image(&img, &palette, SplitCriterion::default(), 0., true, &Default::default(), Some(&edges)).is_ok());
+	}
+}
@@ -13,115 +13,374 @@
 // limitations under the License.
 
 
+pub mod edges;
 pub mod error;
+pub mod filter;
 pub mod quantization;
+pub mod prune;
+pub mod quality;
+pub mod refit;
+pub mod split;
+pub mod trim;
+
+use edges::EdgeMap;
+use split::{Histogram, SplitCriterion, SplitJudge};
+use split::{merge_histograms, most_common};
 
 // Node in a quadtree for storing an image
 //
 // May contain subnodes (branch node) or no subnodes and just a color (leaf node)
 //
+// Leaf nodes may also be gradient leaves, interpolating between the colors
+// of their four corners (top left, top right, bottom left, bottom right)
+// when rendered, instead of being filled with their color.
+//
 // It must always contain a color, such that tree descent
 // can stop at any level and give a meaningful preview, among other
 // possible reasons.
-#[derive(Clone, Debug, Default)]
+#[derive(Debug, Default)]
 pub struct QuadtreeNode<P: quantization::palette::Palette + Default> {
     pub color: u32,
     pub sections: Option<Box<[QuadtreeNode<P>; 4]>>,
+    pub gradient: Option<[u32; 4]>,
     _pal: std::marker::PhantomData<P>
 }
 
+// Not derived, which would require the palette type to be `Clone` too.
+impl<P: quantization::palette::Palette + Default> Clone for QuadtreeNode<P> {
+	fn clone(&self) -> Self {
+		QuadtreeNode {
+			color: self.color,
+			sections: self.sections.clone(),
+			gradient: self.gradient,
+			_pal: std::marker::PhantomData,
+		}
+	}
+}
+
+// A square mounted into a quadtree, along with what its parent needs to
+// know about it: its palette histogram, the sum of its edge strengths, and
+// the colors (most common ones) of its quadrants, which are the gradient
+// corners of its parent.
+struct Mounted<P: quantization::palette::Palette + Default> {
+	node: QuadtreeNode<P>,
+	histogram: Histogram,
+	edges: f64,
+	quadrant_colors: [u32; 4],
+}
+
+// Side from which squares mount their quadrants on separate threads with
+// the `parallel` feature; smaller ones aren't worth the overhead.
+#[cfg(feature = "parallel")]
+const PARALLEL_MOUNT_SIZE: usize = 128;
+
+// Runs `f` on each of the four sections of a square `size` pixels wide.
+#[cfg(not(feature = "parallel"))]
+fn for_each_section<N>(sections: &mut [N; 4], _size: usize, f: impl Fn(usize, &mut N)) {
+	sections.iter_mut().enumerate().for_each(|(ind, s)| f(ind, s))
+}
+
+// Same as above, but large enough squares handle their sections
+// concurrently. Sections don't share anything, so the result is the same.
+#[cfg(feature = "parallel")]
+fn for_each_section<N: Send>(sections: &mut [N; 4], size: usize, f: impl Fn(usize, &mut N) + Sync) {
+	use rayon::prelude::*;
+
+	if size >= PARALLEL_MOUNT_SIZE {
+		sections.par_iter_mut().enumerate().for_each(|(ind, s)| f(ind, s))
+	} else {
+		sections.iter_mut().enumerate().for_each(|(ind, s)| f(ind, s))
+	}
+}
+
 impl<P: quantization::palette::Palette + Default> QuadtreeNode<P> {
+	// Whether this node or any node below it is a gradient leaf.
+	pub fn has_gradient_leaves(&self) -> bool {
+		match self.sections {
+			Some(ref sects) => sects.iter().any(|s| s.has_gradient_leaves()),
+			None => self.gradient.is_some(),
+		}
+	}
+
+	// Whether QIM data of this tree must tell gradient leaves apart: it has
+	// branches whose sections are all flat leaves, which would be read back
+	// as gradient leaves otherwise (see `convert_implicit_gradients`).
+	// Gradient leaves alone don't need it, as they are stored as such
+	// branches.
+	pub fn needs_gradient_flags(&self) -> bool {
+		match self.sections {
+			Some(ref sects) => sects.iter().all(|s| s.sections.is_none() && s.gradient.is_none()) ||
+				sects.iter().any(|s| s.needs_gradient_flags()),
+			None => false,
+		}
+	}
+
+	// Turns every branch whose sections are all (flat) leaves into a
+	// gradient leaf between the colors of those sections, which is how
+	// such branches were rendered before gradient leaves existed.
+	pub fn convert_implicit_gradients(&mut self) {
+		let sects = match self.sections {
+			Some(ref mut sects) => sects,
+			None => return
+		};
+		if sects.iter().all(|s| s.sections.is_none() && s.gradient.is_none()) {
+			self.gradient = Some([0, 1, 2, 3].map(|sect_ind| sects[sect_ind].color));
+			self.sections = None;
+		} else {
+			sects.iter_mut().for_each(|s| s.convert_implicit_gradients());
+		}
+	}
+
 	// Takes a "square" of color numbers to match the given palette
 	// and arranges it into an efficient quadtree.
 	//
 	// The "square" must be a `Vec<u32>` with the length being a power of 4.
 	// This is because powers of 4 are squares of powers of 2.
 	//
-	// `sensitivity` should be a number from 0 to 16384 indicating how much
-	// of a square (fraction out of 16384) must be the same color in order
-	// to disregard subsections.
+	// Every node takes the most common color of its square; `split` decides
+	// whether the square is then divided into subsections (see
+	// `SplitCriterion`).
+	//
+	// With `gradient`, a square that must be split may become a gradient leaf
+	// instead, when its pixels are close enough to one (see `gradient_fits`).
+	//
+	// With `edges`, the edge map of the image, squares along edges are split
+	// more readily (see `EdgeMap`).
 	//
-	// `gradient` has a similar meaning as it does for `from_image`.
+	// The tree is built bottom-up: the histogram of a square is the sum of
+	// those of its quadrants, so every pixel is counted once whatever the
+	// depth of the tree. Quadrants are mounted whether or not their square
+	// gets split, since they don't depend on it; the result is the same as
+	// deciding from the root down.
+	#[allow(clippy::too_many_arguments)]
     pub fn mount(
         &mut self,
         image: &[u32],
         palette: &P,
         size: Option<usize>,
         start_pos: Option<(usize, usize)>,
-        sensitivity: usize,
-        gradient: bool
+        split: SplitCriterion,
+        gradient: bool,
+        edges: Option<&EdgeMap>
     ) -> Result<(), error::MountError> {
         if !image.len().is_power_of_two() || image.len().trailing_zeros() % 2 == 1 {
 			return Err(error::MountError::InvalidSize);
 		}
+		if edges.is_some_and(|e| e.pixels() != image.len()) {
+			return Err(error::MountError::InvalidSize);
+		}
 		// Square root
 		let row_len = image.len() >> (image.len().trailing_zeros() >> 1);
-		// Find most common color in corresponding section.
 		let size = size.unwrap_or(row_len);
 		let start_pos = start_pos.unwrap_or((0, 0));
-        let abundance_map = (start_pos.1..start_pos.1 + size).flat_map(|row| image[
-			(row * row_len + start_pos.0)..(row * row_len + start_pos.0 + size)
-			].iter())
-			.fold(std::collections::HashMap::new(), |mut h, n| {
-				*h.entry(n).or_insert(0) += 1isize;
-				h
-			});
-        let mut abundance_sort = abundance_map.iter()
-			.map(|e| (-e.1, e.0))
-			.collect::<Vec<_>>();
-		abundance_sort.sort();
-		let abundance_res = abundance_sort[0];
-		self.color = **abundance_res.1;
-        // Validate color. This should be validated for every pixel, but
-		// due to recursion that goes down through every pixel, it will be handled.
-		if self.color > 1 << palette.width() {
+		// Validate colors up front, as every pixel gets judged.
+		let out_of_range = (start_pos.1..start_pos.1 + size)
+			.flat_map(|row| image[(row * row_len + start_pos.0)..(row * row_len + start_pos.0 + size)].iter())
+			.any(|c| *c >= 1 << palette.width());
+		if out_of_range {
 			return Err(error::MountError::ColorOutOfRange);
 		}
-        // Recursion
-		if size > 1 && (-abundance_res.0 as usize) < (sensitivity * size * size) / 16384 {
-            self.sections = Some(Default::default());
-			let abundance_four = abundance_sort.iter().chain(std::iter::repeat(&(0, &&0)).take(4)).take(4);
-
-            if gradient && size > 2 && abundance_four.map(|x| if -x.0 as usize > (sensitivity * size * size) / 65536
-					{ -x.0 as usize } else { 0 }).sum::<usize>() > (sensitivity * size * size) / 16384 {
-
-                for sect_ind in 0..4 {
-                    let off = size / 4;
-                    let x_off = (sect_ind & 1) * 6 * off / 2;
-                    let y_off = (sect_ind & 2) * 3 * off / 2;
-                    let mut abundance_sort = ((start_pos.1 + y_off)..(start_pos.1 + y_off + off)).flat_map(|row| image[
-                        (row * row_len + start_pos.0 + x_off)..(row * row_len + start_pos.0 + x_off + off)
-                        ].iter())
-                        .fold(std::collections::HashMap::new(), |mut h, n| {
-                            *h.entry(n).or_insert(0) += 1;
-                            h
-                        })
-                        .into_iter()
-                        .map(|e| (-e.1, e.0))
-                        .collect::<Vec<_>>();
-                    abundance_sort.sort();
-                    self.sections.as_mut().unwrap()[sect_ind].color = *abundance_sort[0].1;
-                }
-            } else {
-                for sect_ind in 0..4 {
-					self.sections.as_mut().unwrap()[sect_ind]
-						.mount(
-							image,
-							palette,
-							Some(size / 2),
-							Some((
-								start_pos.0 + (sect_ind & 1) * (size / 2),
-								start_pos.1 + (sect_ind >> 1) * (size / 2),
-							)),
-							sensitivity,
-							gradient
-						)?;
-				}
-            }
-        }
-        Ok(())
-    }
+		if size == 1 {
+			*self = QuadtreeNode { color: image[start_pos.1 * row_len + start_pos.0], ..Default::default() };
+			return Ok(());
+		}
+		let judge = SplitJudge::new(split, palette, edges.map_or(0., |e| e.weight));
+		*self = Self::mount_square(image, row_len, &judge, edges, size, start_pos, gradient).node;
+		Ok(())
+	}
+
+	// Mounts a square at least two pixels wide.
+	fn mount_square(
+		image: &[u32],
+		row_len: usize,
+		judge: &SplitJudge,
+		edges: Option<&EdgeMap>,
+		size: usize,
+		start_pos: (usize, usize),
+		gradient: bool
+	) -> Mounted<P> {
+		if size == 2 {
+			return Self::mount_pixels(image, row_len, judge, edges, start_pos);
+		}
+		let mut quadrants: [Option<Mounted<P>>; 4] = Default::default();
+		for_each_section(&mut quadrants, size, |sect_ind, quadrant| *quadrant = Some(Self::mount_square(
+			image,
+			row_len,
+			judge,
+			edges,
+			size / 2,
+			(
+				start_pos.0 + (sect_ind & 1) * (size / 2),
+				start_pos.1 + (sect_ind >> 1) * (size / 2),
+			),
+			gradient
+		)));
+		let quadrants = quadrants.map(Option::unwrap);
+		let histogram = merge_histograms(
+			&merge_histograms(&quadrants[0].histogram, &quadrants[1].histogram),
+			&merge_histograms(&quadrants[2].histogram, &quadrants[3].histogram)
+		);
+		let edges = quadrants.iter().map(|quadrant| quadrant.edges).sum::<f64>();
+		let leaf = most_common(&histogram);
+		let quadrant_colors = [0, 1, 2, 3].map(|sect_ind| quadrants[sect_ind].node.color);
+		let mut node = QuadtreeNode { color: leaf.0, ..Default::default() };
+		if judge.must_split(&histogram, leaf, size * size, edges) {
+			// Gradient leaves take the colors found in the corners of the square,
+			// the matching quadrants of its quadrants.
+			let corners = (gradient && size > 2)
+				.then(|| [0, 1, 2, 3].map(|sect_ind| quadrants[sect_ind].quadrant_colors[sect_ind]));
+			match corners {
+				Some(corners) if judge.gradient_fits(&histogram, size * size, edges, &corners) =>
+					node.gradient = Some(corners),
+				_ => node.sections = Some(Box::new(quadrants.map(|quadrant| quadrant.node))),
+			}
+		}
+		Mounted { node, histogram, edges, quadrant_colors }
+	}
+
+	// Mounts a square two pixels wide, counting its pixels directly. Such
+	// squares are too small for gradient leaves.
+	fn mount_pixels(
+		image: &[u32],
+		row_len: usize,
+		judge: &SplitJudge,
+		edges: Option<&EdgeMap>,
+		start_pos: (usize, usize)
+	) -> Mounted<P> {
+		let pixels = [0, 1, 2, 3].map(|sect_ind| (start_pos.0 + (sect_ind & 1), start_pos.1 + (sect_ind >> 1)));
+		let colors = pixels.map(|(x, y)| image[y * row_len + x]);
+		let mut sorted = colors;
+		sorted.sort_unstable();
+		let mut histogram: Histogram = Vec::with_capacity(4);
+		for color in sorted {
+			match histogram.last_mut() {
+				Some(entry) if entry.0 == color => entry.1 += 1,
+				_ => histogram.push((color, 1)),
+			}
+		}
+		let edges = edges.map_or(0., |e| pixels.iter().map(|(x, y)| e.at(*x, *y) as f64).sum::<f64>());
+		let leaf = most_common(&histogram);
+		let mut node = QuadtreeNode { color: leaf.0, ..Default::default() };
+		if judge.must_split(&histogram, leaf, 4, edges) {
+			node.sections = Some(Box::new(colors.map(|color| QuadtreeNode { color, ..Default::default() })));
+		}
+		Mounted { node, histogram, edges, quadrant_colors: colors }
+	}
 }
 
 pub mod image;
-pub mod qim;
\ No newline at end of file
+pub mod qim;
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::quantization::metric::ColorMetric;
+	use super::quantization::palette::DynamicPaletteView;
+	use super::split::ErrorLimit;
+
+	// Sizes and fingerprints of `serialize` for the trees `mount` gave before
+	// histograms were merged bottom-up, counting the colors of every square
+	// from the root down: for each of `test_squares`, each split criterion of
+	// `bottom_up_mount_matches_golden_top_down_trees`, without then with gradients.
+	const GOLDEN: [[(usize, u64); 8]; 5] = [
+		[
+			(30, 4548961379136853290), (30, 4548961379136853290),
+			(30, 4548961379136853290), (30, 4548961379136853290),
+			(30, 4548961379136853290), (30, 4548961379136853290),
+			(30, 4548961379136853290), (30, 4548961379136853290),
+		],
+		[
+			(126, 7632735999093953080), (102, 1273542812054112614),
+			(126, 7632735999093953080), (94, 13504447229515622712),
+			(174, 13343668009772494915), (94, 13504447229515622712),
+			(30, 2231963648389867192), (22, 18426532986695179906),
+		],
+		[
+			(1926, 4175550726921765859), (1926, 4175550726921765859),
+			(126, 10011633261854874269), (94, 14089940649351137121),
+			(5118, 2356252998549781245), (2734, 8286484992990864194),
+			(2502, 4864388301516960599), (22, 5086159314149426047),
+		],
+		[
+			(13398, 1060512719467247258), (13398, 1060512719467247258),
+			(126, 1389066656124995086), (94, 5164822941025303622),
+			(23766, 17272045702676824394), (22790, 9680237322939752720),
+			(11310, 912026249538679775), (22, 9394845644423203984),
+		],
+		[
+			(129606, 13913803823417442171), (129606, 13913803823417442171),
+			(99270, 3478105406649910705), (96222, 10007101293731647834),
+			(131022, 18086037681209179533), (131022, 18086037681209179533),
+			(95454, 8019790814584249229), (22, 6949356667601419702),
+		],
+	];
+
+	// Writes the nodes of a tree in depth-first order: color, corners of
+	// gradient leaves and whether they have sections.
+	fn serialize(node: &QuadtreeNode<DynamicPaletteView>, out: &mut Vec<u8>) {
+		out.extend_from_slice(&node.color.to_le_bytes());
+		match node.gradient {
+			Some(corners) => {
+				out.push(1);
+				corners.iter().for_each(|c| out.extend_from_slice(&c.to_le_bytes()));
+			},
+			None => out.push(0)
+		}
+		out.push(node.sections.is_some() as u8);
+		node.sections.iter().flat_map(|sects| sects.iter()).for_each(|s| serialize(s, out));
+	}
+
+	// FNV-1a, to keep the expected trees short.
+	fn fingerprint(data: &[u8]) -> u64 {
+		data.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+	}
+
+	// Squares of palette indices, from flat areas with a few stray pixels to noise.
+	fn test_squares() -> Vec<Vec<u32>> {
+		let mut state = 0x9e3779b9u32;
+		let mut random = move || {
+			state ^= state << 13;
+			state ^= state >> 17;
+			state ^= state << 5;
+			state
+		};
+		let mut squares = vec![];
+		for (row_len, noise) in [(2, 2), (16, 8), (64, 32), (64, 256), (128, 1024)] {
+			squares.push((0..row_len * row_len).map(|n| {
+				let (x, y) = (n % row_len, n / row_len);
+				if random() % 1024 < noise {
+					random() % 16
+				} else {
+					((x * 4 / row_len) + (y * 4 / row_len) * 4) as u32 % 16
+				}
+			}).collect());
+		}
+		squares
+	}
+
+	#[test]
+	fn bottom_up_mount_matches_golden_top_down_trees() {
+		let palette = DynamicPaletteView::from((0..16u8)
+			.map(|n| ::image::Rgba([n * 16, 255 - n * 9, (n % 4) * 60, 255]))
+			.collect::<Vec<_>>());
+		let splits = [
+			SplitCriterion::Majority(16128),
+			SplitCriterion::Majority(9000),
+			SplitCriterion::Error(ErrorLimit::Rms(20.), ColorMetric::Srgb),
+			SplitCriterion::Error(ErrorLimit::MaxDeviation(60.), ColorMetric::OkLab),
+		];
+		for (square, golden) in test_squares().iter().zip(GOLDEN) {
+			let row_len = (square.len() as f64).sqrt() as usize;
+			let mut trees = vec![];
+			for split in splits {
+				for gradient in [false, true] {
+					let mut tree: QuadtreeNode<DynamicPaletteView> = Default::default();
+					tree.mount(square, &palette, None, None, split, gradient, None).unwrap();
+					let mut data = vec![];
+					serialize(&tree, &mut data);
+					trees.push((data.len(), fingerprint(&data)));
+				}
+			}
+			assert_eq!(trees, golden, "{}x{}", row_len, row_len);
+		}
+	}
+}
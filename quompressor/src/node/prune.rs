@@ -0,0 +1,218 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use super::error::DrawError;
+use super::quantization::metric::{ColorMetric, MetricColor};
+use super::quantization::palette::Palette;
+use super::quantization::palette_colors;
+
+// Color moments of a square of source pixels, in the coordinates of a
+// Euclidean `ColorMetric`, from which the squared error of any flat color
+// over the square follows.
+#[derive(Clone, Copy, Debug, Default)]
+struct Moments {
+	count: f64,
+	sum: [f64; 4],
+	sum_sq: f64,
+}
+
+impl Moments {
+	fn of_square(prepared: &[MetricColor], row_len: usize, start_pos: (usize, usize), size: usize) -> Self {
+		let mut moments = Moments::default();
+		for row in start_pos.1..start_pos.1 + size {
+			for c in prepared[(row * row_len + start_pos.0)..(row * row_len + start_pos.0 + size)].iter() {
+				moments.count += 1.;
+				for (sum, v) in moments.sum.iter_mut().zip(c) {
+					*sum += *v as f64;
+				}
+				moments.sum_sq += c.iter().map(|v| (*v as f64).powi(2)).sum::<f64>();
+			}
+		}
+		moments
+	}
+
+	fn add(&mut self, other: &Moments) {
+		self.count += other.count;
+		for (sum, v) in self.sum.iter_mut().zip(other.sum) {
+			*sum += v;
+		}
+		self.sum_sq += other.sum_sq;
+	}
+
+	// Sum of the squared distances between the pixels and `c`.
+	fn distortion(&self, c: &MetricColor) -> f64 {
+		let dot = self.sum.iter().zip(c).map(|(s, v)| s * *v as f64).sum::<f64>();
+		let norm = c.iter().map(|v| (*v as f64).powi(2)).sum::<f64>();
+		(self.sum_sq - 2. * dot + self.count * norm).max(0.)
+	}
+}
+
+impl<P: Palette + Default> super::QuadtreeNode<P> {
+	// Collapses every subtree whose bits, weighted by `lambda`, cost more
+	// than the distortion it saves against `source`, the image the tree was
+	// mounted from.
+	//
+	// This minimizes `D + lambda * R` bottom-up, where `R` is the size of the
+	// tree in bits and `D` the sum over pixels of the squared `metric`
+	// distance between the source and the color of their leaf (as if leaves
+	// were drawn flat, gradient leaves included). CIEDE2000 isn't a
+	// Euclidean distance, so CIELAB stands in for it. `lambda` is thus in
+	// squared distance units per bit; the larger, the smaller the tree.
+	//
+	// Branches of four leaves, gradient leaves included, count the bit
+	// telling them apart when the tree needs it (see `needs_gradient_flags`),
+	// as they take it in QIM data.
+	//
+	// It only removes nodes, so it works best on a finely split tree, such
+	// as one mounted with `SplitCriterion::Majority(16384)`.
+	pub fn prune(
+		&mut self,
+		source: &image::RgbaImage,
+		palette: &P,
+		metric: ColorMetric,
+		lambda: f64
+	) -> Result<(), DrawError> {
+		if source.width() != source.height() {
+			return Err(DrawError::NonSquare);
+		}
+		if !source.width().is_power_of_two() {
+			return Err(DrawError::NonPowerOfTwo);
+		}
+		let metric = if metric.is_euclidean() { metric } else { ColorMetric::CieLab };
+		let prepared = source.pixels().map(|p| metric.prepare(p)).collect::<Vec<_>>();
+		let colors = palette_colors(palette).iter().map(|c| metric.prepare(c)).collect::<Vec<_>>();
+		let node_bits = 1. + palette.width() as f64;
+		let size = source.width() as usize;
+		let corner_cost = lambda * 4. * node_bits;
+		let flag_cost = if self.needs_gradient_flags() { lambda } else { 0. };
+		self.prune_square(&prepared, &colors, size, size, (0, 0), lambda * node_bits, corner_cost, flag_cost)?;
+		Ok(())
+	}
+
+	// Prunes the subtree of the `size`-wide square at `start_pos`, and returns
+	// its moments and its cost, not counting the node itself (but counting
+	// the corners of a gradient leaf, at `corner_cost`, and the bit telling
+	// branches of four leaves from gradient leaves, at `flag_cost`).
+	#[allow(clippy::too_many_arguments)]
+	fn prune_square(
+		&mut self,
+		prepared: &[MetricColor],
+		colors: &[MetricColor],
+		row_len: usize,
+		size: usize,
+		start_pos: (usize, usize),
+		node_cost: f64,
+		corner_cost: f64,
+		flag_cost: f64
+	) -> Result<(Moments, f64), DrawError> {
+		let color = colors.get(self.color as usize).ok_or(DrawError::ColorOutOfRange)?;
+		let sects = match self.sections {
+			Some(ref mut sects) => sects,
+			None => {
+				let moments = Moments::of_square(prepared, row_len, start_pos, size);
+				let corners = if self.gradient.is_some() { corner_cost + flag_cost } else { 0. };
+				return Ok((moments, moments.distortion(color) + corners));
+			}
+		};
+		let mut moments = Moments::default();
+		let mut split_cost = 0.;
+		for (sect_ind, section) in sects.iter_mut().enumerate() {
+			let (sect_moments, sect_cost) = section.prune_square(
+				prepared,
+				colors,
+				row_len,
+				size / 2,
+				(
+					start_pos.0 + (sect_ind & 1) * (size / 2),
+					start_pos.1 + (sect_ind >> 1) * (size / 2),
+				),
+				node_cost,
+				corner_cost,
+				flag_cost
+			)?;
+			moments.add(&sect_moments);
+			split_cost += sect_cost + node_cost;
+		}
+		if sects.iter().all(|s| s.sections.is_none() && s.gradient.is_none()) {
+			split_cost += flag_cost;
+		}
+		let leaf_cost = moments.distortion(color);
+		if leaf_cost <= split_cost {
+			self.sections = None;
+			Ok((moments, leaf_cost))
+		} else {
+			Ok((moments, split_cost))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::node::QuadtreeNode;
+	use crate::node::quantization::palette::DynamicPaletteView;
+
+	type Node = QuadtreeNode<DynamicPaletteView>;
+
+	fn black_and_red() -> DynamicPaletteView {
+		DynamicPaletteView::from(vec![image::Rgba([0, 0, 0, 255]), image::Rgba([10, 0, 0, 255])])
+	}
+
+	// A black 2x2 square with a red top right pixel, drawn exactly by its leaves.
+	fn exact_tree() -> (image::RgbaImage, Node) {
+		let source = image::RgbaImage::from_fn(2, 2, |x, y| match (x, y) {
+			(1, 0) => image::Rgba([10, 0, 0, 255]),
+			_ => image::Rgba([0, 0, 0, 255]),
+		});
+		let leaf = |color| Node { color, ..Default::default() };
+		let tree = Node { color: 0, sections: Some(Box::new([leaf(0), leaf(1), leaf(0), leaf(0)])), ..Default::default() };
+		(source, tree)
+	}
+
+	// A branch of four flat leaves needs gradient flags, so collapsing saves
+	// four nodes of two bits each and the flag of the branch, and costs a
+	// squared error of 100: it pays off from a lambda of 100 / 9 on.
+	#[test]
+	fn subtrees_collapse_once_their_bits_cost_more_than_their_error() {
+		let (source, tree) = exact_tree();
+		for (lambda, collapsed) in [(0., false), (11., false), (11.2, true), (1000., true)] {
+			let mut pruned = tree.clone();
+			pruned.prune(&source, &black_and_red(), ColorMetric::Srgb, lambda).unwrap();
+			assert_eq!(pruned.sections.is_none(), collapsed, "lambda {}", lambda);
+			assert_eq!(pruned.color, 0);
+		}
+	}
+
+	#[test]
+	fn flat_subtrees_collapse_for_free() {
+		let (_, mut tree) = exact_tree();
+		let source = image::RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, 0, 255]));
+		tree.sections.as_mut().unwrap()[1].color = 0;
+		tree.prune(&source, &black_and_red(), ColorMetric::Ciede2000, 0.).unwrap();
+		assert!(tree.sections.is_none());
+	}
+
+	#[test]
+	fn mismatched_inputs_are_rejected() {
+		let (source, mut tree) = exact_tree();
+		let palette = black_and_red();
+		let wide = image::RgbaImage::new(4, 2);
+		assert!(matches!(tree.prune(&wide, &palette, ColorMetric::Srgb, 1.), Err(DrawError::NonSquare)));
+		let odd = image::RgbaImage::new(3, 3);
+		assert!(matches!(tree.prune(&odd, &palette, ColorMetric::Srgb, 1.), Err(DrawError::NonPowerOfTwo)));
+		tree.sections.as_mut().unwrap()[2].color = 5;
+		assert!(matches!(tree.prune(&source, &palette, ColorMetric::Srgb, 1.), Err(DrawError::ColorOutOfRange)));
+	}
+}
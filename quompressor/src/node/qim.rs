@@ -14,14 +14,120 @@
 
 use bitvec::vec::BitVec;
 
+use super::edges::EdgeMap;
 use super::error::*;
+use super::quantization::gamma::Blending;
 use super::quantization::palette::{DynamicPalette, Palette};
+use super::quantization::palette_colors;
+use super::quantization::palette_file::palette_hash;
+use super::split::{self, SplitCriterion};
+use super::trim::TrimPolicy;
 
-use std::collections::HashMap;
 
 // A `BitVec` variant ideal for encoding and decoding quadtrees.
 type QuadtreeEncodeBitVec = BitVec<bitvec::order::Msb0, u8>;
 
+// Bit of the version 2 flags byte telling that the palette is not embedded,
+// and only referred to by its hash.
+const QIM_FLAG_EXTERNAL_PALETTE: u8 = 0x01;
+// Bit of the version 2 flags byte telling that gradients are interpolated
+// on linear light.
+const QIM_FLAG_LINEAR_LIGHT: u8 = 0x02;
+// Bit of the version 2 flags byte telling that nodes whose four subsections
+// are leaves carry a bit telling whether they are gradient leaves.
+const QIM_FLAG_GRADIENT_LEAVES: u8 = 0x04;
+
+// Depth of the deepest nodes a decoded quadtree may have, those of the
+// pixels of an image 2^31 pixels wide; data asking for more is malformed.
+const MAX_DEPTH: usize = 31;
+
+// Optional features of a QIM file, kept in the flags byte of version 2.
+//
+// Files using none of them are written as version 1, which is what the
+// default value stands for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QimFlags {
+	// The palette is not embedded, only referred to by its hash; the same
+	// palette has to be handed to `from_qim_with_palette` for decoding.
+	pub external_palette: bool,
+	// The space gradients are meant to be rendered in by `to_image`.
+	pub blending: Blending,
+	// Nodes whose four subsections are leaves are marked as gradient leaves
+	// or not. The encoder sets this itself for trees that need it (see
+	// `to_qim_with`).
+	pub gradient_leaves: bool,
+}
+
+impl QimFlags {
+	fn to_byte(self) -> u8 {
+		let mut flags = 0;
+		if self.external_palette {
+			flags |= QIM_FLAG_EXTERNAL_PALETTE;
+		}
+		if self.blending == Blending::Linear {
+			flags |= QIM_FLAG_LINEAR_LIGHT;
+		}
+		if self.gradient_leaves {
+			flags |= QIM_FLAG_GRADIENT_LEAVES;
+		}
+		flags
+	}
+
+	fn from_byte(flags: u8) -> Self {
+		QimFlags {
+			external_palette: flags & QIM_FLAG_EXTERNAL_PALETTE != 0,
+			blending: if flags & QIM_FLAG_LINEAR_LIGHT != 0 { Blending::Linear } else { Blending::Srgb },
+			gradient_leaves: flags & QIM_FLAG_GRADIENT_LEAVES != 0,
+		}
+	}
+
+	// Reads the flags of QIM data, along with the offset of the byte right
+	// after them (the color-space-size byte).
+	fn read_header(source: &[u8]) -> Result<(Self, usize), DecodeError> {
+		if source.len() < 8 || &source[..6] != b"QuadIM" {
+			return Err(DecodeError::MissingHeader);
+		}
+		// Version one has no flags; version two has a flags byte before the palette.
+		match source[6] {
+			1 => Ok((QimFlags::default(), 7)),
+			2 => Ok((QimFlags::from_byte(source[7]), 8)),
+			_ => Err(DecodeError::MissingHeader)
+		}
+	}
+
+	// Reads the flags of QIM data, e.g. to know how to render it.
+	pub fn of_qim(source: &[u8]) -> Result<Self, DecodeError> {
+		Self::read_header(source).map(|(flags, _)| flags)
+	}
+}
+
+// Reads the colors stored in the palette of QIM data, as many as its length
+// indicator gives, without the padding up to the size of the color space.
+pub fn embedded_palette(source: &[u8]) -> Result<Vec<image::Rgba<u8>>, DecodeError> {
+	let (flags, pal_start) = QimFlags::read_header(source)?;
+	if flags.external_palette {
+		return Err(DecodeError::ExternalPalette);
+	}
+	read_palette(source, pal_start).map(|(colors, _)| colors)
+}
+
+// Reads the embedded palette starting with the color-space-size byte at
+// `pal_start`, along with the offset of the tree right after it.
+fn read_palette(source: &[u8], pal_start: usize) -> Result<(Vec<image::Rgba<u8>>, usize), DecodeError> {
+	let indicator = *source.get(pal_start).ok_or(DecodeError::InsufficientData)?;
+	let pal_size = (indicator & 0x1f) + 1;
+	let pal_len = (
+		((indicator >> 5) as f64 + 9.) *
+		(pal_size as f64 - 4.).exp2()
+	) as usize;
+	let colors = source.get(pal_start + 1..pal_start + 1 + 4 * pal_len)
+		.ok_or(DecodeError::InsufficientData)?
+		.chunks_exact(4)
+		.map(|c| image::Rgba([c[0], c[1], c[2], c[3]]))
+		.collect();
+	Ok((colors, pal_start + 1 + 4 * pal_len))
+}
+
 impl<P: Palette + Default> super::QuadtreeNode<P> {
     // Converts the `QuadtreeNode` into a binary data format.
 	//
@@ -31,26 +137,52 @@ impl<P: Palette + Default> super::QuadtreeNode<P> {
 	// numbers for its subsections.
 	//
 	// Palette color numbers are bitwise big-endian.
+	//
+	// Gradient leaves are written as a node with four subsections, the
+	// colors of their corners. With `gradient_leaves`, the subsections of
+	// every node whose four subsections have none are followed by a bit
+	// telling whether it is a gradient leaf; without it, such nodes are all
+	// read back as gradient leaves.
 	pub fn encode(
 		&self,
 		buffer: &mut QuadtreeEncodeBitVec,
-		palette: &P
+		palette: &P,
+		gradient_leaves: bool
 	) -> Result<(), EncodeError> {
-		// Validate color value
-		if self.color >= 1 << palette.width() {
-			return Err(EncodeError::ColorOutOfRange);
-		}
+		let push_color = |buffer: &mut QuadtreeEncodeBitVec, color: u32| {
+			// Validate color value
+			if color >= 1 << palette.width() {
+				return Err(EncodeError::ColorOutOfRange);
+			}
+			for bit_ind in 0..palette.width() {
+				buffer.push(color & (1 << (palette.width() - bit_ind - 1)) != 0);
+			}
+			Ok(())
+		};
 		// Bit to indicate subsections
-		buffer.push(self.sections.is_some());
+		buffer.push(self.sections.is_some() || self.gradient.is_some());
 		// Color number
-		for bit_ind in 0..palette.width() {
-			buffer.push(self.color & (1 << (palette.width() - bit_ind - 1)) != 0);
-		}
-		// Recursion
-		if let Some(ref sects) = self.sections {
-			for section in sects.iter() {
-				section.encode(buffer, palette)?;
-			}
+		push_color(buffer, self.color)?;
+		// Recursion or corners, and the bit telling them apart
+		match (&self.sections, self.gradient) {
+			(Some(sects), _) => {
+				for section in sects.iter() {
+					section.encode(buffer, palette, gradient_leaves)?;
+				}
+				if gradient_leaves && sects.iter().all(|s| s.sections.is_none() && s.gradient.is_none()) {
+					buffer.push(false);
+				}
+			},
+			(None, Some(corners)) => {
+				for corner in corners {
+					buffer.push(false);
+					push_color(buffer, corner)?;
+				}
+				if gradient_leaves {
+					buffer.push(true);
+				}
+			},
+			(None, None) => ()
 		}
 		Ok(())
 	}
@@ -63,51 +195,78 @@ impl<P: Palette + Default> super::QuadtreeNode<P> {
 	//
 	// 0 should be passed for `curr_ind` by outside callers, unless they
 	// know what they're doing and have a good reason otherwise.
+	//
+	// `gradient_leaves` must be the same as given to `encode`.
 	pub fn decode(
 		&mut self,
 		buffer: &QuadtreeEncodeBitVec,
 		palette: &P,
-		mut curr_ind: usize
+		curr_ind: usize,
+		gradient_leaves: bool
 	) -> Result<usize, DecodeError> {
-		// Validate data quantity
-		if buffer.len() - curr_ind < (palette.width()) as usize {
-			return Err(DecodeError::InsufficientData);
-		}
+		self.decode_at(buffer, palette, curr_ind, gradient_leaves, 0)
+	}
+
+	// Same as `decode`, for a node `depth` levels below the root.
+	fn decode_at(
+		&mut self,
+		buffer: &QuadtreeEncodeBitVec,
+		palette: &P,
+		mut curr_ind: usize,
+		gradient_leaves: bool,
+		depth: usize
+	) -> Result<usize, DecodeError> {
+		let read_color = |curr_ind: usize| {
+			// Validate data quantity
+			if buffer.len() < curr_ind + palette.width() as usize {
+				return Err(DecodeError::InsufficientData);
+			}
+			let mut n = 0;
+			for bit_ind in 0..(palette.width()) {
+				n |= (buffer[curr_ind + bit_ind as usize] as u32) << (palette.width() - bit_ind - 1);
+			}
+			Ok(n)
+		};
 		// Extract current node
-		let mut n = 0;
-		for bit_ind in 0..(palette.width()) {
-			n |= (buffer[curr_ind + bit_ind as usize + 1] as u32) << (palette.width() - bit_ind - 1);
-		}
-		self.color = n;
+		let should_recurse = *buffer.get(curr_ind).ok_or(DecodeError::InsufficientData)?;
+		curr_ind += 1;
+		self.color = read_color(curr_ind)?;
+		curr_ind += palette.width() as usize;
 		// Recursion
-		let should_recurse = buffer[curr_ind];
-		curr_ind += 1 + palette.width() as usize;
 		if should_recurse {
-			self.sections = Some(Default::default());
-			for sect_ind in 0..4 {
-				curr_ind = self.sections.as_mut().unwrap()[sect_ind]
-					.decode(buffer, palette, curr_ind)?;
+			if depth == MAX_DEPTH {
+				return Err(DecodeError::TooDeep);
+			}
+			let mut sects: Box<[Self; 4]> = Default::default();
+			for section in sects.iter_mut() {
+				curr_ind = section.decode_at(buffer, palette, curr_ind, gradient_leaves, depth + 1)?;
+			}
+			let is_gradient = gradient_leaves &&
+				sects.iter().all(|s| s.sections.is_none() && s.gradient.is_none()) && {
+					curr_ind += 1;
+					*buffer.get(curr_ind - 1).ok_or(DecodeError::InsufficientData)?
+				};
+			if is_gradient {
+				self.gradient = Some([0, 1, 2, 3].map(|sect_ind| sects[sect_ind].color));
+			} else {
+				self.sections = Some(sects);
 			}
 		}
 		Ok(curr_ind)
 	}
 
-    // Encodes the quadtree and a palette into QIM data.
-	pub fn to_qim(&self, palette: &P) -> Result<Vec<u8>, EncodeError> {
-		let mut ret = Vec::new();
-		// Header (version 1)
-		ret.extend_from_slice(b"QuadIM\x01");
-		let mut palette_vec = palette.get_slice()
-			.map(|x| x.to_owned())
-			.unwrap_or_else(|| (0..palette.width() << 1)
-				.map(|n| palette.to_rgba(n as u32).unwrap())
-				.collect::<Vec<_>>());
-		palette_vec.resize(1 << palette.width(), image::Rgba([0; 4]));
-		let palette_len = std::cmp::max((1 << palette.width()) - palette_vec.iter()
-			.rev()
-			.take_while(|c| **c == image::Rgba([0; 4]))
-			.count(),
-			(9 * (1 << palette.width()) + 15) / 16);
+    // Appends the color-space-size byte and the colors of `palette`.
+	fn push_palette(ret: &mut Vec<u8>, palette: &P) {
+		// Palettes listing their colors keep all of them, transparent black
+		// ones included, so that `embedded_palette` gets them back.
+		let stored = match palette.get_slice() {
+			Some(colors) => colors.len(),
+			None => (1 << palette.width()) - (0..1u32 << palette.width())
+				.rev()
+				.take_while(|n| palette.to_rgba(*n).unwrap() == image::Rgba([0; 4]))
+				.count()
+		};
+		let palette_len = std::cmp::max(stored, (9 * (1usize << palette.width())).div_ceil(16));
 		let approx_len = (palette_len as f64 * 16. / (1 << palette.width()) as f64)
 			.ceil() as u32 * (1 << palette.width()) / 16;
 		// Length indicator
@@ -117,68 +276,306 @@ impl<P: Palette + Default> super::QuadtreeNode<P> {
 		for c in 0..approx_len {
 			ret.extend_from_slice(&palette.to_rgba(c).unwrap().0);
 		}
-		// Quadtree
+	}
+
+	fn push_tree(&self, ret: &mut Vec<u8>, palette: &P, gradient_leaves: bool) -> Result<(), EncodeError> {
 		let mut bit_buf = QuadtreeEncodeBitVec::new();
-		self.encode(&mut bit_buf, palette)?;
+		self.encode(&mut bit_buf, palette, gradient_leaves)?;
 		ret.extend_from_slice(bit_buf.as_slice());
+		Ok(())
+	}
+
+    // Encodes the quadtree and a palette into QIM data.
+	//
+	// This takes version 2 if the tree needs its gradient leaves told apart
+	// from branches of four leaves (see `needs_gradient_flags`), and
+	// version 1 otherwise.
+	pub fn to_qim(&self, palette: &P) -> Result<Vec<u8>, EncodeError> {
+		if self.needs_gradient_flags() {
+			return self.to_qim_with(palette, QimFlags::default());
+		}
+		let mut ret = Vec::new();
+		// Header (version 1)
+		ret.extend_from_slice(b"QuadIM\x01");
+		Self::push_palette(&mut ret, palette);
+		// Quadtree
+		self.push_tree(&mut ret, palette, false)?;
+		Ok(ret)
+	}
+
+	// Same as `to_qim`, but with the given flags, `gradient_leaves` being set
+	// if the tree needs it; this takes version 2, unless no flag is set.
+	pub fn to_qim_with(&self, palette: &P, flags: QimFlags) -> Result<Vec<u8>, EncodeError> {
+		let flags = QimFlags { gradient_leaves: flags.gradient_leaves || self.needs_gradient_flags(), ..flags };
+		if flags == QimFlags::default() {
+			return self.to_qim(palette);
+		}
+		let mut ret = Vec::new();
+		// Header (version 2)
+		ret.extend_from_slice(b"QuadIM\x02");
+		ret.push(flags.to_byte());
+		if flags.external_palette {
+			ret.push(palette.width() - 1);
+			ret.extend_from_slice(&palette_hash(&palette_colors(palette)).to_be_bytes());
+		} else {
+			Self::push_palette(&mut ret, palette);
+		}
+		// Quadtree
+		self.push_tree(&mut ret, palette, flags.gradient_leaves)?;
 		Ok(ret)
 	}
 
-	// "Trims" the tree by removing leaf nodes.
+	// Mounts `image` (see `mount`) into the finest tree, among the criteria
+	// of the same kind as `split`, whose QIM encoding with `flags` fits in
+	// `budget` bytes, after trimming with the `trim` policy, if any. `edges`
+	// is handed to `mount`.
 	//
-	// Only leaf nodes past a depth of `depth` and with color repetition
-	// will be removed.
-	pub fn trim(&mut self, depth: isize) {
-		if let Some(sections) = &mut self.sections {
-			if depth <= 0 && sections.iter().all(|s| s.sections.is_none()) {
-				// Count unique colors
-				let col_f = sections.iter().fold(HashMap::new(),
-					|mut m, e| { *m.entry(e.color).or_insert(0) += 1; m });
-				let freq = col_f.values().collect::<Vec<_>>();
-				if freq.len() == 3 || (freq.len() == 2 && **freq.iter().max().unwrap() == 3) {
-					self.sections = None;
+	// Returns the criterion the tree was mounted with. The search bisects
+	// the levels of `SplitCriterion::at_level`, so it takes about 15 mounts.
+	#[allow(clippy::too_many_arguments)]
+	pub fn mount_within(
+		&mut self,
+		image: &[u32],
+		palette: &P,
+		split: SplitCriterion,
+		edges: Option<&EdgeMap>,
+		trim: Option<TrimPolicy>,
+		flags: QimFlags,
+		budget: usize
+	) -> Result<SplitCriterion, EncodeError> {
+		let attempt = |level: usize| -> Result<Option<Self>, EncodeError> {
+			let mut tree: Self = Default::default();
+			match tree.mount(image, palette, None, None, split.at_level(level), true, edges) {
+				Ok(_) => (),
+				Err(MountError::InvalidSize) => return Err(EncodeError::InvalidImage),
+				Err(MountError::ColorOutOfRange) => return Err(EncodeError::PixelOutOfRange)
+			}
+			if let Some(ref policy) = trim {
+				tree.trim(palette, policy);
+			}
+			let fits = tree.to_qim_with(palette, flags)?.len() <= budget;
+			Ok(fits.then_some(tree))
+		};
+		let (mut low, mut high) = (0, split::MAX_LEVEL);
+		let mut best = match attempt(low)? {
+			Some(tree) => tree,
+			None => return Err(EncodeError::OverBudget)
+		};
+		match attempt(high)? {
+			Some(tree) => {
+				best = tree;
+				low = high;
+			},
+			None => while high - low > 1 {
+				let mid = (low + high) / 2;
+				match attempt(mid)? {
+					Some(tree) => {
+						best = tree;
+						low = mid;
+					},
+					None => high = mid
 				}
-			} else {
-				sections.iter_mut().for_each(|s| s.trim(depth - 1));
 			}
 		}
+		*self = best;
+		Ok(split.at_level(low))
 	}
 }
 
-impl<'a, P: DynamicPalette + Default + std::fmt::Debug> super::QuadtreeNode<P> {
+impl<P: DynamicPalette + Default + std::fmt::Debug> super::QuadtreeNode<P> {
     // Derives a palette and quadtree from the data of a QIM file.
+	//
+	// Fails with `DecodeError::ExternalPalette` if the file refers to a
+	// palette file instead of embedding its palette.
 	pub fn from_qim(source: &[u8]) -> Result<(super::QuadtreeNode<P>, P), DecodeError> {
-		// Verify header (version 1 is required for compatibility)
-		if &source[..6] != b"QuadIM" {
-			return Err(DecodeError::MissingHeader);
+		Self::decode_qim(source, None)
+	}
+
+	// Same as `from_qim`, but files referring to an external palette are
+	// decoded with `palette`, which must be the palette they refer to.
+	// Files embedding their palette are decoded with that one instead.
+	pub fn from_qim_with_palette(
+		source: &[u8],
+		palette: P
+	) -> Result<(super::QuadtreeNode<P>, P), DecodeError> {
+		Self::decode_qim(source, Some(palette))
+	}
+
+	fn decode_qim(
+		source: &[u8],
+		external: Option<P>
+	) -> Result<(super::QuadtreeNode<P>, P), DecodeError> {
+		// Verify header
+		let (flags, pal_start) = QimFlags::read_header(source)?;
+		if source.len() <= pal_start {
+			return Err(DecodeError::InsufficientData);
 		}
-		let pal_size = (source[7] & 0x1f) + 1;
-		let pal_len = (
-			((source[7] >> 5) as f64 + 9.) *
-			(pal_size as f64 - 4.).exp2()
-		) as u32;
-		assert!(pal_len.count_ones() <= 4);
-		// Extract palette
-		let mut pal = vec![];
-		for offset in (0..pal_len).map(|n| n as usize * 4 + 8) {
-			pal.push(image::Rgba([
-				source[offset],
-				source[offset + 1],
-				source[offset + 2],
-				source[offset + 3],
-			]));
-		}
-		pal.resize(1 << pal_size, image::Rgba([0; 4]));
-		let palette = P::from(pal);
+		let pal_size = (source[pal_start] & 0x1f) + 1;
+		let (palette, tree_start) = if flags.external_palette {
+			let palette = match external {
+				Some(p) => p,
+				None => return Err(DecodeError::ExternalPalette)
+			};
+			let hash_bytes = source.get(pal_start + 1..pal_start + 9)
+				.ok_or(DecodeError::InsufficientData)?;
+			let hash = u64::from_be_bytes(hash_bytes.try_into().unwrap());
+			if palette.width() != pal_size || palette_hash(&palette_colors(&palette)) != hash {
+				return Err(DecodeError::PaletteMismatch);
+			}
+			(palette, pal_start + 9)
+		} else {
+			let (mut pal, tree_start) = read_palette(source, pal_start)?;
+			pal.resize(1 << pal_size, image::Rgba([0; 4]));
+			(P::from(pal), tree_start)
+		};
 		// Decode tree
-		let tree_bits = QuadtreeEncodeBitVec::from(&source[8 + 4 * pal_len as usize..]);
+		let tree_bits = QuadtreeEncodeBitVec::from(&source[tree_start..]);
 		let mut tree: super::QuadtreeNode<P> = Default::default();
-		match source[6] {
-			1 => { // Version one. Maybe other decoder in the future ?
-				tree.decode(&tree_bits, &palette, 0)?;
-				Ok((tree, palette))
-			},
-			_ => Err(DecodeError::MissingHeader)
+		tree.decode(&tree_bits, &palette, 0, flags.gradient_leaves)?;
+		// Older files leave gradients implicit.
+		if !flags.gradient_leaves {
+			tree.convert_implicit_gradients();
+		}
+		Ok((tree, palette))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::node::QuadtreeNode;
+	use crate::node::quantization::alpha::TRANSPARENT;
+	use crate::node::quantization::palette::DynamicPaletteView;
+
+	fn two_colors() -> DynamicPaletteView {
+		DynamicPaletteView::from(vec![image::Rgba([0, 0, 0, 255]), image::Rgba([255; 4])])
+	}
+
+	#[test]
+	fn embedded_palette_keeps_trailing_transparent_colors() {
+		let red = image::Rgba([255, 0, 0, 255]);
+		for len in [2, 3, 4, 9, 16] {
+			let mut colors = vec![red; len - 1];
+			colors.push(TRANSPARENT);
+			let palette = DynamicPaletteView::from(colors.clone());
+			let data = QuadtreeNode::<DynamicPaletteView>::default().to_qim(&palette).unwrap();
+			assert_eq!(embedded_palette(&data).unwrap(), colors);
+		}
+	}
+
+	#[test]
+	fn mount_within_rejects_invalid_images() {
+		let palette = two_colors();
+		let mut tree = QuadtreeNode::<DynamicPaletteView>::default();
+		let within = |tree: &mut QuadtreeNode<DynamicPaletteView>, image: &[u32]| tree.mount_within(
+			image, &palette, SplitCriterion::default(), None, None, QimFlags::default(), 1 << 20
+		);
+		assert!(matches!(within(&mut tree, &[0; 8]), Err(EncodeError::InvalidImage)));
+		assert!(matches!(within(&mut tree, &[0, 1, 2, 1]), Err(EncodeError::PixelOutOfRange)));
+		assert!(within(&mut tree, &[0, 1, 1, 0]).is_ok());
+	}
+
+	#[test]
+	fn decoding_truncated_data_fails() {
+		let palette = two_colors();
+		let image = (0..256).map(|n| ((n % 16) * (n / 16) % 3 == 0) as u32).collect::<Vec<_>>();
+		let mut tree = QuadtreeNode::<DynamicPaletteView>::default();
+		tree.mount(&image, &palette, None, None, SplitCriterion::default(), true, None).unwrap();
+		for flags in [QimFlags::default(), QimFlags { gradient_leaves: true, ..Default::default() }] {
+			let data = tree.to_qim_with(&palette, flags).unwrap();
+			assert!(QuadtreeNode::<DynamicPaletteView>::from_qim(&data).is_ok());
+			for len in 0..data.len() {
+				assert!(QuadtreeNode::<DynamicPaletteView>::from_qim(&data[..len]).is_err(), "{} bytes", len);
+			}
+		}
+	}
+
+	// A tree whose two branches of four leaves are solid, and the same tree
+	// with gradient leaves in their place.
+	fn solid_and_gradient_trees() -> (QuadtreeNode<DynamicPaletteView>, QuadtreeNode<DynamicPaletteView>) {
+		let leaf = |color| QuadtreeNode { color, ..Default::default() };
+		let branch = |colors: [u32; 4]| QuadtreeNode { color: 0, sections: Some(Box::new(colors.map(leaf))), ..Default::default() };
+		let gradient = |corners| QuadtreeNode { color: 0, gradient: Some(corners), ..Default::default() };
+		let solid = QuadtreeNode {
+			color: 1,
+			sections: Some(Box::new([branch([0, 1, 1, 0]), leaf(1), branch([1, 0, 0, 0]), leaf(0)])),
+			..Default::default()
+		};
+		let gradients = QuadtreeNode {
+			color: 1,
+			sections: Some(Box::new([gradient([0, 1, 1, 0]), leaf(1), gradient([1, 0, 0, 0]), leaf(0)])),
+			..Default::default()
+		};
+		(solid, gradients)
+	}
+
+	// Telling gradient leaves apart costs one bit per branch of four leaves,
+	// and nothing in files that need no solid ones: both trees take 26 bits
+	// in version 1, after 7 header bytes and 9 palette bytes.
+	#[test]
+	fn gradient_flags_cost_one_bit_per_branch_of_four_leaves() {
+		let palette = two_colors();
+		let (solid, gradients) = solid_and_gradient_trees();
+		let bits = |tree: &QuadtreeNode<DynamicPaletteView>, gradient_leaves| {
+			let mut buffer = QuadtreeEncodeBitVec::new();
+			tree.encode(&mut buffer, &palette, gradient_leaves).unwrap();
+			buffer.len()
+		};
+		assert_eq!(bits(&solid, false), 26);
+		assert_eq!(bits(&gradients, false), 26);
+		assert_eq!(bits(&solid, true), 28);
+		assert_eq!(bits(&gradients, true), 28);
+		let data = gradients.to_qim(&palette).unwrap();
+		assert_eq!((data[6], data.len()), (1, 20));
+		let data = solid.to_qim(&palette).unwrap();
+		assert_eq!((data[6], data.len()), (2, 21));
+	}
+
+	// The nodes of `tree` in depth-first order.
+	fn flatten(tree: &QuadtreeNode<DynamicPaletteView>, out: &mut Vec<(u32, Option<[u32; 4]>, bool)>) {
+		out.push((tree.color, tree.gradient, tree.sections.is_some()));
+		if let Some(ref sects) = tree.sections {
+			sects.iter().for_each(|s| flatten(s, out));
+		}
+	}
+
+	#[test]
+	fn branches_of_four_leaves_round_trip() {
+		let palette = two_colors();
+		let (solid, gradients) = solid_and_gradient_trees();
+		for tree in [solid, gradients] {
+			let mut expected = Vec::new();
+			flatten(&tree, &mut expected);
+			for flags in [QimFlags::default(), QimFlags { gradient_leaves: true, ..Default::default() }] {
+				let data = tree.to_qim_with(&palette, flags).unwrap();
+				let mut decoded = Vec::new();
+				flatten(&QuadtreeNode::<DynamicPaletteView>::from_qim(&data).unwrap().0, &mut decoded);
+				assert_eq!(decoded, expected, "{:?}", flags);
+			}
+		}
+	}
+
+	#[test]
+	fn decoding_garbage_fails() {
+		// A 1-bit palette, then nodes that all have subnodes.
+		let mut deep = b"QuadIM\x01\x00\0\0\0\xff".to_vec();
+		deep.resize(1 << 16, 0xff);
+		assert!(matches!(QuadtreeNode::<DynamicPaletteView>::from_qim(&deep), Err(DecodeError::TooDeep)));
+		let mut state = 0x2545f491u32;
+		for header in [&b"QuadIM\x01"[..], b"QuadIM\x02\x04", b"QuadIM\x02\x07", b"QuadIM\x03"] {
+			for len in [0, 1, 5, 64, 4096] {
+				let mut data = header.to_vec();
+				data.extend((0..len).map(|_| {
+					state ^= state << 13;
+					state ^= state >> 17;
+					state ^= state << 5;
+					state as u8
+				}));
+				// Any result but a panic will do.
+				let _ = QuadtreeNode::<DynamicPaletteView>::from_qim(&data);
+				let _ = QuadtreeNode::<DynamicPaletteView>::from_qim_with_palette(&data, two_colors());
+				let _ = embedded_palette(&data);
+			}
 		}
 	}
 }
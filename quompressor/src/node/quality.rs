@@ -0,0 +1,234 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use super::edges::EdgeMap;
+use super::error::{DrawError, EncodeError, MountError, UnknownOption};
+use super::quantization::gamma::Blending;
+use super::quantization::palette::Palette;
+use super::split::{self, SplitCriterion};
+use super::trim::TrimPolicy;
+
+// Side of the square windows SSIM is computed over, and their spacing.
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STRIDE: u32 = 4;
+
+// Peak signal-to-noise ratio between two images of the same size, in dB,
+// over all four RGBA channels. Identical images give infinity.
+pub fn psnr(a: &image::RgbaImage, b: &image::RgbaImage) -> f64 {
+	let sum = a.as_raw().iter()
+		.zip(b.as_raw().iter())
+		.map(|(x, y)| (*x as f64 - *y as f64).powi(2))
+		.sum::<f64>();
+	let mse = sum / a.as_raw().len() as f64;
+	if mse == 0. {
+		f64::INFINITY
+	} else {
+		10. * (255f64.powi(2) / mse).log10()
+	}
+}
+
+// Luma of the pixels of `img` composited over black (Rec. 601 weights).
+fn luma(img: &image::RgbaImage) -> Vec<f64> {
+	img.pixels()
+		.map(|p| (0.299 * p.0[0] as f64 + 0.587 * p.0[1] as f64 + 0.114 * p.0[2] as f64) * p.0[3] as f64 / 255.)
+		.collect()
+}
+
+// Mean structural similarity between two images of the same size, from
+// -1 to 1 (identical).
+//
+// It is computed on luma, over 8x8 windows spaced 4 pixels apart (or the
+// whole image, when smaller), with the usual constants of Wang et al.
+pub fn ssim(a: &image::RgbaImage, b: &image::RgbaImage) -> f64 {
+	let (width, height) = a.dimensions();
+	let (la, lb) = (luma(a), luma(b));
+	let (c1, c2) = ((0.01f64 * 255.).powi(2), (0.03f64 * 255.).powi(2));
+	let window = SSIM_WINDOW.min(width).min(height);
+	let starts = |len: u32| (0..=len - window).step_by(SSIM_STRIDE as usize);
+	let mut total = 0.;
+	let mut windows = 0;
+	for y in starts(height) {
+		for x in starts(width) {
+			let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0., 0., 0., 0., 0.);
+			for row in y..y + window {
+				for col in x..x + window {
+					let ind = (row * width + col) as usize;
+					let (va, vb) = (la[ind], lb[ind]);
+					sa += va;
+					sb += vb;
+					saa += va * va;
+					sbb += vb * vb;
+					sab += va * vb;
+				}
+			}
+			let n = (window * window) as f64;
+			let (ma, mb) = (sa / n, sb / n);
+			let (var_a, var_b, cov) = (saa / n - ma * ma, sbb / n - mb * mb, sab / n - ma * mb);
+			total += ((2. * ma * mb + c1) * (2. * cov + c2)) /
+				((ma * ma + mb * mb + c1) * (var_a + var_b + c2));
+			windows += 1;
+		}
+	}
+	total / windows as f64
+}
+
+// Lowest acceptable quality of an encoded image, as compared to its source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QualityFloor {
+	// Minimum PSNR, in dB (see `psnr`).
+	Psnr(f64),
+	// Minimum SSIM (see `ssim`).
+	Ssim(f64),
+}
+
+impl QualityFloor {
+	// Quality of `rendered` against `source`, in the measure of this floor.
+	pub fn measure(&self, source: &image::RgbaImage, rendered: &image::RgbaImage) -> f64 {
+		match *self {
+			QualityFloor::Psnr(_) => psnr(source, rendered),
+			QualityFloor::Ssim(_) => ssim(source, rendered),
+		}
+	}
+
+	pub fn is_met(&self, quality: f64) -> bool {
+		match *self {
+			QualityFloor::Psnr(floor) | QualityFloor::Ssim(floor) => quality >= floor,
+		}
+	}
+
+	// Human-readable form of a `quality` in the measure of this floor.
+	pub fn describe(&self, quality: f64) -> String {
+		match *self {
+			QualityFloor::Psnr(_) => format!("{:.2} dB PSNR", quality),
+			QualityFloor::Ssim(_) => format!("SSIM {:.4}", quality),
+		}
+	}
+}
+
+impl<P: Palette + Default> super::QuadtreeNode<P> {
+	// Quality, in the measure of `floor`, of the tree rendered at the size of
+	// `source` against it, gradients interpolated in `blending`.
+	pub fn quality_against(
+		&self,
+		palette: &P,
+		source: &image::RgbaImage,
+		blending: Blending,
+		floor: QualityFloor
+	) -> Result<f64, DrawError> {
+		let mut rendered = image::RgbaImage::new(source.width(), source.height());
+		self.to_image(&mut rendered, palette, None, None, true, blending)?;
+		Ok(floor.measure(source, &rendered))
+	}
+
+	// Mounts `image` (see `mount`) into the coarsest tree, among the criteria
+	// of the same kind as `split`, whose rendering reaches `floor` against
+	// `source`, after trimming with the `trim` policy, if any. Trees are
+	// rendered at the size of `source`, with gradients interpolated in
+	// `blending`.
+	//
+	// Returns the criterion the tree was mounted with and the quality it
+	// reached. Like `mount_within`, this bisects the levels of
+	// `SplitCriterion::at_level`. `edges` is handed to `mount`.
+	#[allow(clippy::too_many_arguments)]
+	pub fn mount_to_quality(
+		&mut self,
+		image: &[u32],
+		palette: &P,
+		split: SplitCriterion,
+		edges: Option<&EdgeMap>,
+		trim: Option<TrimPolicy>,
+		source: &image::RgbaImage,
+		blending: Blending,
+		floor: QualityFloor
+	) -> Result<(SplitCriterion, f64), EncodeError> {
+		let attempt = |level: usize| -> Result<(Self, f64), EncodeError> {
+			let mut tree: Self = Default::default();
+			match tree.mount(image, palette, None, None, split.at_level(level), true, edges) {
+				Ok(_) => (),
+				Err(MountError::InvalidSize) => return Err(EncodeError::InvalidImage),
+				Err(MountError::ColorOutOfRange) => return Err(EncodeError::PixelOutOfRange)
+			}
+			if let Some(ref policy) = trim {
+				tree.trim(palette, policy);
+			}
+			let quality = match tree.quality_against(palette, source, blending, floor) {
+				Ok(q) => q,
+				Err(DrawError::NonSquare) | Err(DrawError::NonPowerOfTwo) => return Err(EncodeError::InvalidImage),
+				Err(DrawError::ColorOutOfRange) => return Err(EncodeError::ColorOutOfRange)
+			};
+			Ok((tree, quality))
+		};
+		let (mut low, mut high) = (0, split::MAX_LEVEL);
+		let mut best = attempt(high)?;
+		if !floor.is_met(best.1) {
+			return Err(EncodeError::QualityUnreachable);
+		}
+		let coarsest = attempt(low)?;
+		if floor.is_met(coarsest.1) {
+			best = coarsest;
+			high = low;
+		} else {
+			while high - low > 1 {
+				let mid = (low + high) / 2;
+				let candidate = attempt(mid)?;
+				if floor.is_met(candidate.1) {
+					best = candidate;
+					high = mid;
+				} else {
+					low = mid;
+				}
+			}
+		}
+		*self = best.0;
+		Ok((split.at_level(high), best.1))
+	}
+}
+
+impl std::str::FromStr for QualityFloor {
+	type Err = UnknownOption;
+
+	// Accepts `psnr:N` (dB) and `ssim:N`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let err = || UnknownOption::QualityFloor(s.to_string());
+		let (measure, floor) = s.split_once(':').ok_or_else(err)?;
+		let floor = floor.parse::<f64>().map_err(|_| err())?;
+		match measure {
+			"psnr" => Ok(QualityFloor::Psnr(floor)),
+			"ssim" => Ok(QualityFloor::Ssim(floor)),
+			_ => Err(err()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::node::QuadtreeNode;
+	use crate::node::quantization::palette::DynamicPaletteView;
+
+	#[test]
+	fn mount_to_quality_rejects_invalid_images() {
+		let palette = DynamicPaletteView::from(vec![image::Rgba([0, 0, 0, 255]), image::Rgba([255; 4])]);
+		let source = image::RgbaImage::from_fn(2, 2, |x, y| palette.colors[((x + y) % 2) as usize]);
+		let mut tree = QuadtreeNode::<DynamicPaletteView>::default();
+		let mut to_quality = |image: &[u32], source: &image::RgbaImage| tree.mount_to_quality(
+			image, &palette, SplitCriterion::default(), None, None, source, Blending::Srgb, QualityFloor::Psnr(30.)
+		).map(|(_, quality)| quality);
+		assert!(matches!(to_quality(&[0; 8], &source), Err(EncodeError::InvalidImage)));
+		assert!(matches!(to_quality(&[0, 1, 2, 1], &source), Err(EncodeError::PixelOutOfRange)));
+		assert!(matches!(to_quality(&[0, 1, 1, 0], &image::RgbaImage::new(2, 1)), Err(EncodeError::InvalidImage)));
+		assert_eq!(to_quality(&[0, 1, 1, 0], &source).unwrap(), f64::INFINITY);
+	}
+}
@@ -0,0 +1,191 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::gamma::Blending;
+use super::palette::Color;
+use super::super::error::UnknownOption;
+
+// Alpha threshold of `AlphaMode::Binary` unless told otherwise.
+pub const DEFAULT_ALPHA_THRESHOLD: u8 = 128;
+
+// The color every fully transparent pixel is turned into: since nothing of
+// it shows, its RGB values mean nothing and would only split up the palette
+// and the quadtree.
+pub const TRANSPARENT: Color = image::Rgba([0; 4]);
+
+// How the alpha channel of pixels is treated before quantization.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlphaMode {
+	// Alpha is kept as is (fully transparent pixels still become `TRANSPARENT`).
+	#[default]
+	Full,
+	// Pixels with an alpha below the threshold become `TRANSPARENT` and all
+	// others become opaque, as suits sprites with hard edges.
+	Binary(u8),
+}
+
+impl AlphaMode {
+	pub fn apply(&self, c: &Color) -> Color {
+		let alpha = match *self {
+			AlphaMode::Full => c.0[3],
+			AlphaMode::Binary(threshold) => if c.0[3] < threshold { 0 } else { 255 },
+		};
+		if alpha == 0 {
+			TRANSPARENT
+		} else {
+			image::Rgba([c.0[0], c.0[1], c.0[2], alpha])
+		}
+	}
+
+	pub fn apply_to_image(&self, img: &image::RgbaImage) -> image::RgbaImage {
+		let mut ret = img.clone();
+		for pix in ret.pixels_mut() {
+			*pix = self.apply(pix);
+		}
+		ret
+	}
+}
+
+// Color channels scaled by alpha, followed by alpha itself.
+//
+// Averages and differences of translucent colors only make sense in this
+// form: a nearly transparent pixel barely contributes its color.
+pub fn premultiply(c: &Color) -> [f32; 4] {
+	let a = c.0[3] as f32 / 255.;
+	[c.0[0] as f32 * a, c.0[1] as f32 * a, c.0[2] as f32 * a, c.0[3] as f32]
+}
+
+// Inverse of `premultiply`, rounding and clamping to bytes.
+pub fn unpremultiply(c: &[f32; 4]) -> Color {
+	let alpha = c[3].round().clamp(0., 255.);
+	if alpha == 0. {
+		return TRANSPARENT;
+	}
+	let chan = |v: f32| (v * 255. / alpha).round().clamp(0., 255.) as u8;
+	image::Rgba([chan(c[0]), chan(c[1]), chan(c[2]), alpha as u8])
+}
+
+// Weighted average of colors, computed on premultiplied values in the
+// space given by `blending`.
+//
+// For opaque colors blended in sRGB, this is the plain (truncated) average
+// of each channel.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PremultipliedSum {
+	// Color channels (expanded by `blending`) multiplied by alpha and
+	// weight, then alpha times weight.
+	sums: [u64; 4],
+	count: u64,
+	blending: Blending,
+}
+
+impl PremultipliedSum {
+	pub fn new(blending: Blending) -> Self {
+		PremultipliedSum { blending, ..Default::default() }
+	}
+
+	pub fn add(&mut self, c: &Color, weight: u64) {
+		let alpha = c.0[3] as u64;
+		for chan in 0..3 {
+			self.sums[chan] += self.blending.expand(c.0[chan]) * alpha * weight;
+		}
+		self.sums[3] += alpha * weight;
+		self.count += weight;
+	}
+
+	pub fn merge(&mut self, other: &PremultipliedSum) {
+		for chan in 0..4 {
+			self.sums[chan] += other.sums[chan];
+		}
+		self.count += other.count;
+	}
+
+	pub fn count(&self) -> u64 {
+		self.count
+	}
+
+	// Must not be called before anything was added.
+	pub fn average(&self) -> Color {
+		if self.sums[3] == 0 {
+			return TRANSPARENT;
+		}
+		image::Rgba([
+			self.blending.contract(self.sums[0] / self.sums[3]),
+			self.blending.contract(self.sums[1] / self.sums[3]),
+			self.blending.contract(self.sums[2] / self.sums[3]),
+			(self.sums[3] / self.count) as u8,
+		])
+	}
+}
+
+impl std::str::FromStr for AlphaMode {
+	type Err = UnknownOption;
+
+	// Accepts `binary:N` to pick the threshold of `Binary`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"full" => Ok(AlphaMode::Full),
+			"binary" => Ok(AlphaMode::Binary(DEFAULT_ALPHA_THRESHOLD)),
+			_ => match s.strip_prefix("binary:").map(str::parse::<u8>) {
+				Some(Ok(threshold)) => Ok(AlphaMode::Binary(threshold)),
+				_ => Err(UnknownOption::AlphaMode(s.to_string())),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn binary_mode_thresholds_alpha() {
+		let mode: AlphaMode = "binary:100".parse().unwrap();
+		assert_eq!(mode, AlphaMode::Binary(100));
+		assert_eq!(mode.apply(&image::Rgba([10, 20, 30, 99])), TRANSPARENT);
+		assert_eq!(mode.apply(&image::Rgba([10, 20, 30, 100])), image::Rgba([10, 20, 30, 255]));
+		assert_eq!("binary".parse::<AlphaMode>().unwrap(), AlphaMode::Binary(DEFAULT_ALPHA_THRESHOLD));
+		assert!("binary:256".parse::<AlphaMode>().is_err());
+		assert!("half".parse::<AlphaMode>().is_err());
+	}
+
+	#[test]
+	fn full_mode_only_clears_transparent_pixels() {
+		assert_eq!(AlphaMode::Full.apply(&image::Rgba([10, 20, 30, 0])), TRANSPARENT);
+		assert_eq!(AlphaMode::Full.apply(&image::Rgba([10, 20, 30, 1])), image::Rgba([10, 20, 30, 1]));
+	}
+
+	#[test]
+	fn premultiplication_round_trips() {
+		for c in [image::Rgba([255, 128, 0, 255]), image::Rgba([200, 100, 50, 128]), TRANSPARENT] {
+			assert_eq!(unpremultiply(&premultiply(&c)), c);
+		}
+		assert_eq!(unpremultiply(&premultiply(&image::Rgba([255, 0, 0, 0]))), TRANSPARENT);
+	}
+
+	// A transparent pixel lowers the alpha of the average without pulling
+	// its color towards its own meaningless RGB values.
+	#[test]
+	fn averages_ignore_the_color_of_transparent_pixels() {
+		let mut sum = PremultipliedSum::new(Blending::Srgb);
+		sum.add(&image::Rgba([200, 100, 50, 255]), 1);
+		sum.add(&TRANSPARENT, 1);
+		assert_eq!(sum.average(), image::Rgba([200, 100, 50, 127]));
+		let mut other = PremultipliedSum::new(Blending::Srgb);
+		other.add(&image::Rgba([0, 0, 0, 255]), 2);
+		sum.merge(&other);
+		assert_eq!(sum.count(), 4);
+		assert_eq!(sum.average(), image::Rgba([66, 33, 16, 191]));
+	}
+}
@@ -0,0 +1,170 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::palette::*;
+use super::super::error::UnknownOption;
+
+// Standard palettes for targets with a fixed set of displayable colors,
+// such as e-ink panels or retro hardware.
+//
+// Encoding against one of these doesn't involve `generate_palette` at all:
+// pixels are mapped straight onto the fixed colors. Each palette has its own
+// `GenericPalette*` type, so `with_builtin_palette!` is used to pick it at
+// runtime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuiltinPalette {
+	// Black and white (1 bit).
+	Mono,
+	// Grayscale ramp of four shades (2 bits).
+	Gray2,
+	// Grayscale ramp of sixteen shades (4 bits).
+	Gray4,
+	// Grayscale ramp of 256 shades (8 bits).
+	Gray8,
+	// The sixteen colors of the IBM CGA text modes (4 bits).
+	Cga,
+	// The 64 colors an IBM EGA card can display (6 bits).
+	Ega,
+	// The 216 "web-safe" colors, six levels per channel (8 bits).
+	WebSafe,
+	// The four green shades of the original Game Boy screen (2 bits).
+	GameBoy,
+}
+
+fn rgb(r: u8, g: u8, b: u8) -> Color {
+	image::Rgba([r, g, b, 255])
+}
+
+// Copies `colors` into the fixed-size array of a palette; entries past them
+// are left transparent black.
+fn fill<const N: usize>(slots: &mut [Color; N], colors: impl Iterator<Item = Color>) {
+	for (slot, c) in slots.iter_mut().zip(colors) {
+		*slot = c;
+	}
+}
+
+fn gray_ramp<const N: usize>(slots: &mut [Color; N]) {
+	fill(slots, (0..N).map(|i| {
+		let v = (i * 255 / (N - 1)) as u8;
+		rgb(v, v, v)
+	}));
+}
+
+pub fn mono() -> GenericPalette1 {
+	let mut palette = GenericPalette1::default();
+	gray_ramp(&mut palette.colors);
+	palette
+}
+
+pub fn gray2() -> GenericPalette2 {
+	let mut palette = GenericPalette2::default();
+	gray_ramp(&mut palette.colors);
+	palette
+}
+
+pub fn gray4() -> GenericPalette4 {
+	let mut palette = GenericPalette4::default();
+	gray_ramp(&mut palette.colors);
+	palette
+}
+
+pub fn gray8() -> GenericPalette8 {
+	let mut palette = GenericPalette8::default();
+	gray_ramp(&mut palette.colors);
+	palette
+}
+
+// In the order of the CGA color numbers, with the usual brown for color 6.
+pub fn cga() -> GenericPalette4 {
+	let mut palette = GenericPalette4::default();
+	fill(&mut palette.colors, (0..16u8).map(|i| {
+		let intensity = if i & 8 != 0 { 0x55 } else { 0 };
+		let chan = |bit: u8| if i & bit != 0 { 0xaa + intensity } else { intensity };
+		if i == 6 {
+			rgb(0xaa, 0x55, 0)
+		} else {
+			rgb(chan(4), chan(2), chan(1))
+		}
+	}));
+	palette
+}
+
+// In the order of the EGA color numbers, whose bits are `rgbRGB`
+// (lowercase bits adding 0x55 to a channel, uppercase ones 0xaa).
+pub fn ega() -> GenericPalette6 {
+	let mut palette = GenericPalette6::default();
+	fill(&mut palette.colors, (0..64u8).map(|i| {
+		let chan = |high: u8, low: u8| (i >> high & 1) * 0xaa + (i >> low & 1) * 0x55;
+		rgb(chan(2, 5), chan(1, 4), chan(0, 3))
+	}));
+	palette
+}
+
+// Red varies slowest and blue fastest; the last 40 entries are unused.
+pub fn web_safe() -> GenericPalette8 {
+	let mut palette = GenericPalette8::default();
+	fill(&mut palette.colors, (0..216u32).map(|i| {
+		rgb((i / 36 * 51) as u8, (i / 6 % 6 * 51) as u8, (i % 6 * 51) as u8)
+	}));
+	palette
+}
+
+// From darkest to lightest.
+pub fn game_boy() -> GenericPalette2 {
+	let mut palette = GenericPalette2::default();
+	fill(&mut palette.colors, [
+		rgb(0x0f, 0x38, 0x0f),
+		rgb(0x30, 0x62, 0x30),
+		rgb(0x8b, 0xac, 0x0f),
+		rgb(0x9b, 0xbc, 0x0f),
+	].into_iter());
+	palette
+}
+
+// Evaluates `$body` with `$palette` bound to the palette `$builtin` names,
+// which has a different type for each `BuiltinPalette`.
+#[macro_export]
+macro_rules! with_builtin_palette {
+	($builtin:expr, $palette:ident => $body:expr) => {{
+		use $crate::node::quantization::builtin;
+		match $builtin {
+			builtin::BuiltinPalette::Mono => { let $palette = builtin::mono(); $body },
+			builtin::BuiltinPalette::Gray2 => { let $palette = builtin::gray2(); $body },
+			builtin::BuiltinPalette::Gray4 => { let $palette = builtin::gray4(); $body },
+			builtin::BuiltinPalette::Gray8 => { let $palette = builtin::gray8(); $body },
+			builtin::BuiltinPalette::Cga => { let $palette = builtin::cga(); $body },
+			builtin::BuiltinPalette::Ega => { let $palette = builtin::ega(); $body },
+			builtin::BuiltinPalette::WebSafe => { let $palette = builtin::web_safe(); $body },
+			builtin::BuiltinPalette::GameBoy => { let $palette = builtin::game_boy(); $body },
+		}
+	}};
+}
+
+impl std::str::FromStr for BuiltinPalette {
+	type Err = UnknownOption;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"mono" | "bw" | "1bit" => Ok(BuiltinPalette::Mono),
+			"gray2" | "grey2" => Ok(BuiltinPalette::Gray2),
+			"gray4" | "grey4" => Ok(BuiltinPalette::Gray4),
+			"gray8" | "grey8" | "gray" | "grey" => Ok(BuiltinPalette::Gray8),
+			"cga" => Ok(BuiltinPalette::Cga),
+			"ega" => Ok(BuiltinPalette::Ega),
+			"web-safe" | "websafe" | "web" => Ok(BuiltinPalette::WebSafe),
+			"gameboy" | "game-boy" | "gb" => Ok(BuiltinPalette::GameBoy),
+			_ => Err(UnknownOption::BuiltinPalette(s.to_string())),
+		}
+	}
+}
@@ -0,0 +1,294 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::alpha::{premultiply, unpremultiply};
+use super::palette::Color;
+use super::PaletteLookup;
+use super::super::error::UnknownOption;
+
+// Side of the blocks used by `Dither::TreeAware` unless told otherwise.
+pub const DEFAULT_TREE_BLOCK: u32 = 4;
+
+// Ways of hiding the banding caused by a small palette.
+//
+// Dithering trades flat areas for fine patterns, and fine patterns cost
+// quadtree nodes: the error diffusion and ordered variants can make the
+// tree split down to single pixels. `TreeAware` only dithers between
+// blocks of the given (power of two) side, so no leaf gets smaller than
+// a block because of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dither {
+	// Every pixel gets its nearest palette color.
+	#[default]
+	None,
+	// Error diffusion to four neighbours (7/16, 3/16, 5/16, 1/16).
+	FloydSteinberg,
+	// Error diffusion of 3/4 of the error to six neighbours; keeps more contrast.
+	Atkinson,
+	// Ordered dithering with an 8x8 Bayer threshold matrix.
+	Bayer,
+	// Floyd–Steinberg on the average colors of square blocks of pixels.
+	TreeAware(u32),
+}
+
+// Neighbour offsets and weights for error diffusion.
+const FLOYD_STEINBERG: [(isize, isize, f32); 4] = [
+	(1, 0, 7. / 16.), (-1, 1, 3. / 16.), (0, 1, 5. / 16.), (1, 1, 1. / 16.),
+];
+const ATKINSON: [(isize, isize, f32); 6] = [
+	(1, 0, 1. / 8.), (2, 0, 1. / 8.), (-1, 1, 1. / 8.),
+	(0, 1, 1. / 8.), (1, 1, 1. / 8.), (0, 2, 1. / 8.),
+];
+
+fn to_color(c: &[f32; 4]) -> Color {
+	image::Rgba(c.map(|v| v.round().clamp(0., 255.) as u8))
+}
+
+fn to_floats(c: &Color) -> [f32; 4] {
+	c.0.map(|v| v as f32)
+}
+
+// Error diffusion over a `width` by `height` buffer of premultiplied colors,
+// so that the error of a nearly transparent pixel hardly tints its neighbours.
+fn diffuse(
+	mut buf: Vec<[f32; 4]>,
+	width: usize,
+	height: usize,
+	colors: &[Color],
+	lookup: &mut PaletteLookup,
+	kernel: &[(isize, isize, f32)]
+) -> Vec<u32> {
+	let mut out = Vec::with_capacity(buf.len());
+	for y in 0..height {
+		for x in 0..width {
+			let wanted = buf[y * width + x];
+			let ind = lookup.nearest(&unpremultiply(&wanted));
+			out.push(ind);
+			let got = premultiply(&colors[ind as usize]);
+			for (dx, dy, weight) in kernel.iter() {
+				let (nx, ny) = (x as isize + dx, y as isize + dy);
+				if nx < 0 || nx >= width as isize || ny >= height as isize {
+					continue;
+				}
+				let target = &mut buf[ny as usize * width + nx as usize];
+				for chan in 0..4 {
+					target[chan] += (wanted[chan] - got[chan]) * weight;
+				}
+			}
+		}
+	}
+	out
+}
+
+// Threshold for the cell `(x, y)` of an 8x8 Bayer matrix, in `0..64`.
+fn bayer(x: u32, y: u32) -> u32 {
+	let mut v = 0;
+	for bit in 0..3 {
+		let (xb, yb) = ((x >> bit) & 1, (y >> bit) & 1);
+		v |= ((xb ^ yb) << 1 | yb) << (2 * (2 - bit));
+	}
+	v
+}
+
+// Average distance between a palette color and its nearest other palette
+// color, which is how far ordered dithering may push a pixel.
+fn palette_spread(colors: &[Color]) -> f32 {
+	if colors.len() < 2 {
+		return 0.;
+	}
+	let floats = colors.iter().map(to_floats).collect::<Vec<_>>();
+	floats.iter().enumerate()
+		.map(|(ind, a)| floats.iter().enumerate()
+			.filter(|(other, _)| *other != ind)
+			.map(|(_, b)| (0..3).map(|chan| (a[chan] - b[chan]).powi(2)).sum::<f32>())
+			.fold(f32::MAX, f32::min)
+			.sqrt())
+		.sum::<f32>() / floats.len() as f32
+}
+
+// Quantizes every pixel of `img` on its own with `quantize`, which is given
+// the pixel coordinates.
+#[cfg(not(feature = "parallel"))]
+fn map_pixels(
+	img: &image::RgbaImage,
+	lookup: &mut PaletteLookup,
+	quantize: impl Fn(&mut PaletteLookup, u32, u32, &Color) -> u32
+) -> Vec<u32> {
+	img.enumerate_pixels()
+		.map(|(x, y, pix)| quantize(lookup, x, y, pix))
+		.collect()
+}
+
+// Same as above, but rows are spread over threads, each with its own copy
+// of `lookup`; lookups give the same answers whatever their cache holds.
+#[cfg(feature = "parallel")]
+fn map_pixels(
+	img: &image::RgbaImage,
+	lookup: &mut PaletteLookup,
+	quantize: impl Fn(&mut PaletteLookup, u32, u32, &Color) -> u32 + Sync
+) -> Vec<u32> {
+	use rayon::prelude::*;
+
+	let width = img.width() as usize;
+	if width == 0 {
+		return Vec::new();
+	}
+	img.as_raw()
+		.par_chunks(width * 4)
+		.enumerate()
+		.map_init(|| lookup.clone(), |lookup, (y, row)| row.chunks_exact(4)
+			.enumerate()
+			.map(|(x, pix)| quantize(lookup, x as u32, y as u32, &image::Rgba([pix[0], pix[1], pix[2], pix[3]])))
+			.collect::<Vec<_>>())
+		.collect::<Vec<_>>()
+		.concat()
+}
+
+// Quantizes `img` onto `colors` (the palette behind `lookup`) with dithering.
+//
+// With the `parallel` feature, `None` and `Bayer` run on all cores; error
+// diffusion is sequential by nature.
+pub fn dither(
+	img: &image::RgbaImage,
+	colors: &[Color],
+	lookup: &mut PaletteLookup,
+	method: Dither
+) -> Vec<u32> {
+	let (width, height) = (img.width() as usize, img.height() as usize);
+	match method {
+		Dither::None => map_pixels(img, lookup, |lookup, _, _, pix| lookup.nearest(pix)),
+		Dither::FloydSteinberg =>
+			diffuse(img.pixels().map(premultiply).collect(), width, height, colors, lookup, &FLOYD_STEINBERG),
+		Dither::Atkinson =>
+			diffuse(img.pixels().map(premultiply).collect(), width, height, colors, lookup, &ATKINSON),
+		Dither::Bayer => {
+			let spread = palette_spread(colors);
+			map_pixels(img, lookup, |lookup, x, y, pix| {
+				let offset = ((bayer(x % 8, y % 8) as f32 + 0.5) / 64. - 0.5) * spread;
+				let mut c = to_floats(pix);
+				for v in c.iter_mut().take(3) {
+					*v += offset;
+				}
+				lookup.nearest(&to_color(&c))
+			})
+		},
+		Dither::TreeAware(block) => {
+			let block = std::cmp::min(
+				std::cmp::max(block, 1).next_power_of_two(),
+				std::cmp::max(1, std::cmp::min(img.width(), img.height()))
+			) as usize;
+			let (bw, bh) = (width.div_ceil(block), height.div_ceil(block));
+			let mut means = vec![([0f32; 4], 0f32); bw * bh];
+			for (x, y, pix) in img.enumerate_pixels() {
+				let mean = &mut means[(y as usize / block) * bw + x as usize / block];
+				for (sum, v) in mean.0.iter_mut().zip(premultiply(pix)) {
+					*sum += v;
+				}
+				mean.1 += 1.;
+			}
+			let means = means.into_iter()
+				.map(|(sum, count)| sum.map(|v| v / count))
+				.collect();
+			let blocks = diffuse(means, bw, bh, colors, lookup, &FLOYD_STEINBERG);
+			(0..height)
+				.flat_map(|y| (0..width).map(move |x| (x, y)))
+				.map(|(x, y)| blocks[(y / block) * bw + x / block])
+				.collect()
+		},
+	}
+}
+
+impl std::str::FromStr for Dither {
+	type Err = UnknownOption;
+
+	// Accepts `tree:N` to pick the block side of `TreeAware`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"none" => Ok(Dither::None),
+			"floyd-steinberg" | "floyd_steinberg" | "fs" => Ok(Dither::FloydSteinberg),
+			"atkinson" => Ok(Dither::Atkinson),
+			"bayer" | "ordered" => Ok(Dither::Bayer),
+			"tree" => Ok(Dither::TreeAware(DEFAULT_TREE_BLOCK)),
+			_ => match s.strip_prefix("tree:").map(str::parse::<u32>) {
+				Some(Ok(block)) if block > 0 => Ok(Dither::TreeAware(block)),
+				_ => Err(UnknownOption::Dither(s.to_string())),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::metric::ColorMetric;
+	use super::super::palette::DynamicPaletteView;
+
+	fn black_and_white() -> (Vec<Color>, PaletteLookup) {
+		let colors = vec![image::Rgba([0, 0, 0, 255]), image::Rgba([255, 255, 255, 255])];
+		let lookup = PaletteLookup::new(&DynamicPaletteView::from(colors.clone()), ColorMetric::Srgb);
+		(colors, lookup)
+	}
+
+	fn gray(side: u32, level: u8) -> image::RgbaImage {
+		image::RgbaImage::from_pixel(side, side, image::Rgba([level, level, level, 255]))
+	}
+
+	#[test]
+	fn bayer_matrix_holds_every_threshold_once() {
+		let mut thresholds = (0..64).map(|n| bayer(n % 8, n / 8)).collect::<Vec<_>>();
+		thresholds.sort_unstable();
+		assert_eq!(thresholds, (0..64).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn no_dithering_picks_the_nearest_color() {
+		let (colors, mut lookup) = black_and_white();
+		assert_eq!(dither(&gray(8, 100), &colors, &mut lookup, Dither::None), vec![0; 64]);
+		assert_eq!(dither(&gray(8, 150), &colors, &mut lookup, Dither::None), vec![1; 64]);
+	}
+
+	// Every method but `None` renders mid-gray as about as many white pixels
+	// as black ones.
+	#[test]
+	fn dithering_preserves_average_brightness() {
+		let (colors, mut lookup) = black_and_white();
+		for method in [Dither::FloydSteinberg, Dither::Atkinson, Dither::Bayer, Dither::TreeAware(2)] {
+			let white = dither(&gray(32, 128), &colors, &mut lookup, method).iter().sum::<u32>();
+			assert!((480..=544).contains(&white), "{:?}: {} white pixels", method, white);
+		}
+	}
+
+	#[test]
+	fn tree_aware_dithering_keeps_blocks_uniform() {
+		let (colors, mut lookup) = black_and_white();
+		let img = image::RgbaImage::from_fn(30, 18, |x, y| {
+			let level = (x * 8 + y * 3) as u8;
+			image::Rgba([level, level, level, 255])
+		});
+		// Block sides are rounded up to a power of two.
+		let out = dither(&img, &colors, &mut lookup, Dither::TreeAware(3));
+		for (n, ind) in out.iter().enumerate() {
+			let (x, y) = (n % 30, n / 30);
+			assert_eq!(*ind, out[(y / 4 * 4) * 30 + x / 4 * 4], "pixel ({}, {})", x, y);
+		}
+	}
+
+	#[test]
+	fn tree_block_must_be_positive() {
+		assert_eq!("tree".parse::<Dither>().unwrap(), Dither::TreeAware(DEFAULT_TREE_BLOCK));
+		assert_eq!("tree:8".parse::<Dither>().unwrap(), Dither::TreeAware(8));
+		assert!("tree:0".parse::<Dither>().is_err());
+		assert!("tree:x".parse::<Dither>().is_err());
+	}
+}
@@ -0,0 +1,163 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::super::error::UnknownOption;
+
+use std::sync::OnceLock;
+
+// Largest value of linear light in the 16-bit fixed point form of `linear16`.
+const LINEAR_MAX: u16 = u16::MAX;
+
+fn srgb_decode(c: f64) -> f64 {
+	if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn srgb_encode(v: f64) -> f64 {
+	if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1. / 2.4) - 0.055 }
+}
+
+fn linear_table() -> &'static [f32; 256] {
+	static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+	TABLE.get_or_init(|| {
+		let mut table = [0.; 256];
+		for (ind, v) in table.iter_mut().enumerate() {
+			*v = srgb_decode(ind as f64 / 255.) as f32;
+		}
+		table
+	})
+}
+
+fn linear16_table() -> &'static [u16; 256] {
+	static TABLE: OnceLock<[u16; 256]> = OnceLock::new();
+	TABLE.get_or_init(|| {
+		let mut table = [0; 256];
+		for (ind, v) in table.iter_mut().enumerate() {
+			*v = (srgb_decode(ind as f64 / 255.) * LINEAR_MAX as f64).round() as u16;
+		}
+		table
+	})
+}
+
+// One entry per 16-bit linear value, so that converting back is a single
+// lookup; `srgb16(linear16(c)) == c` for every byte `c`.
+fn srgb16_table() -> &'static [u8] {
+	static TABLE: OnceLock<Vec<u8>> = OnceLock::new();
+	TABLE.get_or_init(|| {
+		(0..=LINEAR_MAX)
+			.map(|v| (srgb_encode(v as f64 / LINEAR_MAX as f64) * 255.).round() as u8)
+			.collect()
+	})
+}
+
+// sRGB byte to linear light, from 0 to 1.
+pub fn linear(c: u8) -> f32 {
+	linear_table()[c as usize]
+}
+
+// sRGB byte to linear light, from 0 to `u16::MAX`.
+pub fn linear16(c: u8) -> u16 {
+	linear16_table()[c as usize]
+}
+
+// Linear light from 0 to `u16::MAX` to the nearest sRGB byte.
+pub fn srgb16(v: u16) -> u8 {
+	srgb16_table()[v as usize]
+}
+
+// Linear light from 0 to 1 to the nearest sRGB byte.
+pub fn srgb(v: f32) -> u8 {
+	srgb16((v.clamp(0., 1.) * LINEAR_MAX as f32).round() as u16)
+}
+
+// Space in which colors are averaged and interpolated.
+//
+// Pixel bytes are gamma-encoded sRGB, so averaging them directly darkens
+// mixtures of light and dark colors; doing it on linear light gives the
+// color the eye sees when looking at the mixture from afar. Alpha is
+// always averaged as is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Blending {
+	// Directly on the sRGB bytes, as QIM version 1 files are rendered.
+	#[default]
+	Srgb,
+	// On linear light, converting through lookup tables.
+	Linear,
+}
+
+impl Blending {
+	// Color channel byte to the integer form sums are made in.
+	pub fn expand(&self, c: u8) -> u64 {
+		match *self {
+			Blending::Srgb => c as u64,
+			Blending::Linear => linear16(c) as u64,
+		}
+	}
+
+	// Inverse of `expand`, for averages of expanded values.
+	pub fn contract(&self, v: u64) -> u8 {
+		match *self {
+			Blending::Srgb => v as u8,
+			Blending::Linear => srgb16(v as u16),
+		}
+	}
+}
+
+impl std::str::FromStr for Blending {
+	type Err = UnknownOption;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"srgb" | "gamma" => Ok(Blending::Srgb),
+			"linear" => Ok(Blending::Linear),
+			_ => Err(UnknownOption::Blending(s.to_string())),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bytes_survive_linear_round_trips() {
+		for c in 0..=255 {
+			assert_eq!(srgb16(linear16(c)), c);
+			assert_eq!(srgb(linear(c)), c);
+			for blending in [Blending::Srgb, Blending::Linear] {
+				assert_eq!(blending.contract(blending.expand(c)), c);
+			}
+		}
+	}
+
+	#[test]
+	fn linear_light_follows_the_srgb_curve() {
+		assert_eq!(linear(0), 0.);
+		assert_eq!(linear(255), 1.);
+		assert_eq!(linear16(255), LINEAR_MAX);
+		assert!((linear(128) - 0.2158605).abs() < 1e-6);
+		assert_eq!(srgb(0.5), 188);
+		assert_eq!(srgb(-1.), 0);
+		assert_eq!(srgb(2.), 255);
+	}
+
+	// Half black and half white averages to mid-gray in linear light, which
+	// is far brighter than the byte average.
+	#[test]
+	fn linear_blending_brightens_mixtures() {
+		let blending = Blending::Linear;
+		let mean = (blending.expand(0) + blending.expand(255)) / 2;
+		assert_eq!(blending.contract(mean), 188);
+		assert_eq!(Blending::Srgb.contract((Blending::Srgb.expand(0) + Blending::Srgb.expand(255)) / 2), 127);
+	}
+}
@@ -0,0 +1,93 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+
+use super::metric::MetricColor;
+
+// Spatial hash over the three color coordinates of prepared colors.
+//
+// Space is cut into cubes of side `side`; any two colors less than `side`
+// apart along every color axis are in the same or in adjacent cubes, so
+// the items near a color are found by looking at 27 cubes only.
+#[derive(Debug)]
+pub struct ColorGrid {
+	side: f32,
+	cells: HashMap<[i32; 3], Vec<usize>>,
+}
+
+impl ColorGrid {
+	pub fn new(side: f32) -> Self {
+		ColorGrid { side, cells: HashMap::new() }
+	}
+
+	fn cell(&self, c: &MetricColor) -> [i32; 3] {
+		[
+			(c[0] / self.side).floor() as i32,
+			(c[1] / self.side).floor() as i32,
+			(c[2] / self.side).floor() as i32,
+		]
+	}
+
+	pub fn insert(&mut self, c: &MetricColor, item: usize) {
+		let cell = self.cell(c);
+		self.cells.entry(cell).or_default().push(item);
+	}
+
+	// Items inserted at colors that may be less than `side` away from `c`
+	// along every color axis (and possibly a few farther ones).
+	pub fn near(&self, c: &MetricColor) -> impl Iterator<Item = usize> + '_ {
+		let [x, y, z] = self.cell(c);
+		(-1..=1)
+			.flat_map(move |dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| [x + dx, y + dy, z + dz])))
+			.filter_map(|cell| self.cells.get(&cell))
+			.flat_map(|items| items.iter().copied())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn near_finds_every_close_item() {
+		let side = 10.;
+		let mut grid = ColorGrid::new(side);
+		let mut colors = vec![];
+		for x in 0..12 {
+			for y in 0..12 {
+				for z in 0..12 {
+					let c = [x as f32 * 4.5 - 20., y as f32 * 3.7, z as f32 * 6.1 - 33., 255.];
+					grid.insert(&c, colors.len());
+					colors.push(c);
+				}
+			}
+		}
+		for c in [[0.; 4], [-20., 0., -33., 255.], [9.99, 10., 10.01, 0.], [27.3, 18.8, -1.2, 255.]] {
+			let near = grid.near(&c).collect::<Vec<_>>();
+			for (item, other) in colors.iter().enumerate() {
+				let close = (0..3).all(|axis| (c[axis] - other[axis]).abs() < side);
+				assert!(!close || near.contains(&item), "{:?} misses {:?}", c, other);
+			}
+		}
+	}
+
+	#[test]
+	fn near_skips_far_cells() {
+		let mut grid = ColorGrid::new(10.);
+		grid.insert(&[0., 0., 0., 255.], 0);
+		grid.insert(&[25., 0., 0., 255.], 1);
+		assert_eq!(grid.near(&[5., 5., 5., 255.]).collect::<Vec<_>>(), vec![0]);
+	}
+}
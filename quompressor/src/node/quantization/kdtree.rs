@@ -0,0 +1,151 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::metric::{ColorMetric, MetricColor};
+
+// Node of a k-d tree, stored in an arena and addressed by index.
+#[derive(Clone, Debug)]
+struct KdNode {
+	// Index of the color (in the list the tree was built from) held by this node.
+	color: u32,
+	// Coordinate along which the children are split.
+	axis: usize,
+	left: Option<usize>,
+	right: Option<usize>,
+}
+
+// Spatial index over the colors of a palette, for finding the nearest one.
+//
+// Only valid for metrics whose distance is the squared Euclidean distance
+// between prepared colors (see `ColorMetric::is_euclidean`). The answers
+// are exactly those of a linear scan, ties included: the lowest color
+// index wins among equally distant colors.
+#[derive(Clone, Debug)]
+pub struct KdTree {
+	colors: Vec<MetricColor>,
+	nodes: Vec<KdNode>,
+	root: Option<usize>,
+}
+
+impl KdTree {
+	pub fn new(colors: Vec<MetricColor>) -> Self {
+		let mut tree = KdTree { colors, nodes: Vec::new(), root: None };
+		let mut indices = (0..tree.colors.len() as u32).collect::<Vec<_>>();
+		tree.root = tree.build(&mut indices);
+		tree
+	}
+
+	fn build(&mut self, indices: &mut [u32]) -> Option<usize> {
+		if indices.is_empty() {
+			return None;
+		}
+		// Split along the coordinate with the widest spread.
+		let axis = (0..4).map(|axis| {
+			let (min, max) = indices.iter()
+				.map(|ind| self.colors[*ind as usize][axis])
+				.fold((f32::MAX, f32::MIN), |(min, max), v| (min.min(v), max.max(v)));
+			(axis, max - min)
+		})
+		.max_by(|a, b| a.1.total_cmp(&b.1))
+		.unwrap().0;
+		indices.sort_by(|a, b| self.colors[*a as usize][axis]
+			.total_cmp(&self.colors[*b as usize][axis])
+			.then(a.cmp(b)));
+		let mid = indices.len() / 2;
+		let color = indices[mid];
+		let (lower, upper) = indices.split_at_mut(mid);
+		let left = self.build(lower);
+		let right = self.build(&mut upper[1..]);
+		self.nodes.push(KdNode { color, axis, left, right });
+		Some(self.nodes.len() - 1)
+	}
+
+	// Returns the index of the color nearest to `c`, or `None` if the tree is empty.
+	pub fn nearest(&self, c: &MetricColor, metric: ColorMetric) -> Option<u32> {
+		let mut best = None;
+		if let Some(root) = self.root {
+			self.search(root, c, metric, &mut best);
+		}
+		best.map(|b: (f32, u32)| b.1)
+	}
+
+	fn search(&self, node: usize, c: &MetricColor, metric: ColorMetric, best: &mut Option<(f32, u32)>) {
+		let node = &self.nodes[node];
+		let point = &self.colors[node.color as usize];
+		let dist = metric.prepared_distance(c, point);
+		let better = match *best {
+			None => true,
+			Some((bd, bi)) => dist < bd || (dist == bd && node.color < bi),
+		};
+		if better {
+			*best = Some((dist, node.color));
+		}
+		let delta = c[node.axis] - point[node.axis];
+		let (near, far) = if delta < 0. { (node.left, node.right) } else { (node.right, node.left) };
+		if let Some(near) = near {
+			self.search(near, c, metric, best);
+		}
+		if let Some(far) = far {
+			// Colors on the other side are at least `delta` away along the axis;
+			// equally distant ones are still visited for the sake of ties.
+			if best.map(|b| delta * delta <= b.0).unwrap_or(true) {
+				self.search(far, c, metric, best);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn random_colors(count: usize, state: &mut u32) -> Vec<image::Rgba<u8>> {
+		let mut random = || {
+			*state ^= *state << 13;
+			*state ^= *state >> 17;
+			*state ^= *state << 5;
+			*state
+		};
+		// Few distinct channel values, so that ties are frequent.
+		(0..count).map(|_| image::Rgba([
+			(random() % 8 * 36) as u8, (random() % 8 * 36) as u8, (random() % 8 * 36) as u8, [0, 128, 255][random() as usize % 3],
+		])).collect()
+	}
+
+	fn linear_scan(colors: &[MetricColor], c: &MetricColor, metric: ColorMetric) -> Option<u32> {
+		colors.iter().enumerate()
+			.min_by(|a, b| metric.prepared_distance(c, a.1).total_cmp(&metric.prepared_distance(c, b.1)).then(a.0.cmp(&b.0)))
+			.map(|(ind, _)| ind as u32)
+	}
+
+	#[test]
+	fn nearest_matches_a_linear_scan() {
+		let mut state = 0x9e3779b9u32;
+		for metric in [ColorMetric::Srgb, ColorMetric::CieLab, ColorMetric::OkLab] {
+			for palette_len in [1, 2, 7, 64, 300] {
+				let colors = random_colors(palette_len, &mut state).iter().map(|c| metric.prepare(c)).collect::<Vec<_>>();
+				let tree = KdTree::new(colors.clone());
+				for c in random_colors(200, &mut state) {
+					let c = metric.prepare(&c);
+					assert_eq!(tree.nearest(&c, metric), linear_scan(&colors, &c, metric), "{:?}, {} colors", metric, palette_len);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn empty_tree_has_no_nearest_color() {
+		assert_eq!(KdTree::new(vec![]).nearest(&[0.; 4], ColorMetric::Srgb), None);
+	}
+}
@@ -0,0 +1,129 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+
+use super::alpha::{PremultipliedSum, TRANSPARENT};
+use super::gamma::Blending;
+use super::metric::{ColorMetric, MetricColor};
+use super::palette::Color;
+use super::{color_budget, ranked_with_transparent, PaletteGenerator};
+
+// Median cut color quantizer.
+//
+// The histogram of the image is split recursively: the box of colors with
+// the widest channel range is cut in two at the (pixel-weighted) median of
+// that channel, until there are `max_colors` boxes or no box can be cut.
+// Each box becomes one palette color, the weighted (premultiplied) average
+// of its members.
+//
+// Channels are those of the coordinate space of `metric`, so that boxes are
+// cut where colors actually look the most different.
+#[derive(Clone, Copy, Debug)]
+pub struct MedianCutGenerator {
+	pub max_colors: usize,
+	pub metric: ColorMetric,
+	pub blending: Blending,
+}
+
+// A histogram entry: a color, its coordinates for the metric and its pixel count.
+type Entry = (Color, MetricColor, u64);
+
+// A set of histogram entries, `entries[start..end]`.
+struct ColorBox {
+	start: usize,
+	end: usize,
+	count: u64,
+}
+
+impl ColorBox {
+	// Returns the channel with the widest range in the box and that range.
+	fn widest_channel(&self, entries: &[Entry]) -> (usize, f32) {
+		(0..4).map(|chan| {
+			let (min, max) = entries[self.start..self.end].iter()
+				.fold((f32::MAX, f32::MIN), |(min, max), e| (min.min(e.1[chan]), max.max(e.1[chan])));
+			(chan, max - min)
+		})
+		.max_by(|a, b| a.1.total_cmp(&b.1))
+		.unwrap()
+	}
+
+	fn average(&self, entries: &[Entry], blending: Blending) -> Color {
+		let mut sum = PremultipliedSum::new(blending);
+		for e in entries[self.start..self.end].iter() {
+			sum.add(&e.0, e.2);
+		}
+		sum.average()
+	}
+}
+
+impl PaletteGenerator for MedianCutGenerator {
+	fn colors_of(&self, pixels: &mut dyn Iterator<Item = Color>) -> Vec<Color> {
+		let mut histogram = HashMap::new();
+		let mut transparent = 0;
+		for pixel in pixels {
+			if pixel == TRANSPARENT {
+				transparent += 1;
+			} else {
+				*histogram.entry(pixel).or_insert(0u64) += 1;
+			}
+		}
+		let mut entries = histogram.into_iter()
+			.map(|(col, count)| (col, self.metric.prepare(&col), count))
+			.collect::<Vec<Entry>>();
+		// Start from a fixed order so that cuts don't depend on hashing.
+		entries.sort_by_key(|e| e.0.0);
+		if entries.is_empty() {
+			return ranked_with_transparent(Vec::new(), transparent, Some(self.max_colors));
+		}
+		let mut boxes = vec![ColorBox {
+			start: 0,
+			end: entries.len(),
+			count: entries.iter().map(|e| e.2).sum(),
+		}];
+		while boxes.len() < color_budget(self.max_colors, transparent) {
+			// Pick the box with the widest range among those that can still be cut.
+			let candidate = boxes.iter()
+				.enumerate()
+				.filter(|(_, b)| b.end - b.start > 1)
+				.map(|(ind, b)| (b.widest_channel(&entries), ind))
+				.max_by(|a, b| a.0.1.total_cmp(&b.0.1));
+			let ((chan, range), ind) = match candidate {
+				Some(c) => c,
+				None => break,
+			};
+			if range <= 0. {
+				break;
+			}
+			let (start, end, count) = (boxes[ind].start, boxes[ind].end, boxes[ind].count);
+			entries[start..end].sort_by(|a, b| a.1[chan].total_cmp(&b.1[chan]));
+			// Weighted median, keeping at least one entry on each side.
+			let mut acc = 0;
+			let mut split = start + 1;
+			for (offset, e) in entries[start..end - 1].iter().enumerate() {
+				acc += e.2;
+				split = start + offset + 1;
+				if acc * 2 >= count {
+					break;
+				}
+			}
+			let low_count = entries[start..split].iter().map(|e| e.2).sum();
+			boxes[ind] = ColorBox { start, end: split, count: low_count };
+			boxes.push(ColorBox { start: split, end, count: count - low_count });
+		}
+		boxes.sort_by_key(|b| std::cmp::Reverse(b.count));
+		let rank = boxes.iter().map(|b| (b.average(&entries, self.blending), b.count)).collect();
+		ranked_with_transparent(rank, transparent, Some(self.max_colors))
+	}
+}
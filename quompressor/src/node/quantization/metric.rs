@@ -0,0 +1,251 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::gamma;
+use super::palette::Color;
+use super::super::error::UnknownOption;
+
+// A color converted into the coordinate space of a `ColorMetric`
+// (three color coordinates followed by the alpha byte).
+pub type MetricColor = [f32; 4];
+
+// Ways of measuring how different two colors look.
+//
+// Every metric reports squared distances scaled to roughly match the range
+// of squared differences between sRGB bytes, so that thresholds such as the
+// `dedup` one keep about the same meaning whichever metric is used. Alpha
+// always counts as its raw byte difference.
+//
+// The color coordinates are premultiplied by alpha, so that the less
+// opaque two colors are, the less their hues matter; all fully transparent
+// colors are the same.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorMetric {
+	// Euclidean distance on raw sRGB bytes. Cheap, but merges dark shades
+	// that look different and separates bright ones that look alike.
+	#[default]
+	Srgb,
+	// Euclidean distance in CIELAB (CIE76), L* scaled from 0..100 to 0..255.
+	CieLab,
+	// Euclidean distance in OKLab, scaled by 255.
+	OkLab,
+	// CIEDE2000 color difference, scaled like `CieLab`.
+	Ciede2000,
+}
+
+fn to_linear(c: &Color) -> [f32; 3] {
+	[gamma::linear(c.0[0]), gamma::linear(c.0[1]), gamma::linear(c.0[2])]
+}
+
+// Coefficients are kept exactly as published by Björn Ottosson.
+#[allow(clippy::excessive_precision)]
+fn to_oklab(c: &Color) -> [f32; 3] {
+	let [r, g, b] = to_linear(c);
+	let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+	let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+	let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+	[
+		0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+		1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+		0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+	]
+}
+
+// CIELAB under a D65 white point; L* goes from 0 to 100.
+fn to_cielab(c: &Color) -> [f32; 3] {
+	let [r, g, b] = to_linear(c);
+	let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+	let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+	let z = (0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883;
+	let f = |t: f32| if t > 216. / 24389. { t.cbrt() } else { (24389. / 27. * t + 16.) / 116. };
+	let (fx, fy, fz) = (f(x), f(y), f(z));
+	[116. * fy - 16., 500. * (fx - fy), 200. * (fy - fz)]
+}
+
+// CIEDE2000 difference between two CIELAB colors (Sharma, Wu & Dalal, 2005).
+fn ciede2000(lab1: &[f32], lab2: &[f32]) -> f32 {
+	let (l1, a1, b1) = (lab1[0] as f64, lab1[1] as f64, lab1[2] as f64);
+	let (l2, a2, b2) = (lab2[0] as f64, lab2[1] as f64, lab2[2] as f64);
+	let c_bar = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) / 2.;
+	let c_bar7 = c_bar.powi(7);
+	let g = 0.5 * (1. - (c_bar7 / (c_bar7 + 25f64.powi(7))).sqrt());
+	let (a1p, a2p) = ((1. + g) * a1, (1. + g) * a2);
+	let (c1p, c2p) = ((a1p * a1p + b1 * b1).sqrt(), (a2p * a2p + b2 * b2).sqrt());
+	let hue = |b: f64, a: f64| if b == 0. && a == 0. { 0. } else { b.atan2(a).to_degrees().rem_euclid(360.) };
+	let (h1p, h2p) = (hue(b1, a1p), hue(b2, a2p));
+
+	let dlp = l2 - l1;
+	let dcp = c2p - c1p;
+	let dhp = if c1p * c2p == 0. {
+		0.
+	} else if (h2p - h1p).abs() <= 180. {
+		h2p - h1p
+	} else if h2p - h1p > 180. {
+		h2p - h1p - 360.
+	} else {
+		h2p - h1p + 360.
+	};
+	let dhp_big = 2. * (c1p * c2p).sqrt() * (dhp.to_radians() / 2.).sin();
+
+	let lp_bar = (l1 + l2) / 2.;
+	let cp_bar = (c1p + c2p) / 2.;
+	let hp_bar = if c1p * c2p == 0. {
+		h1p + h2p
+	} else if (h1p - h2p).abs() <= 180. {
+		(h1p + h2p) / 2.
+	} else if h1p + h2p < 360. {
+		(h1p + h2p + 360.) / 2.
+	} else {
+		(h1p + h2p - 360.) / 2.
+	};
+	let t = 1. - 0.17 * (hp_bar - 30.).to_radians().cos()
+		+ 0.24 * (2. * hp_bar).to_radians().cos()
+		+ 0.32 * (3. * hp_bar + 6.).to_radians().cos()
+		- 0.20 * (4. * hp_bar - 63.).to_radians().cos();
+	let d_theta = 30. * (-((hp_bar - 275.) / 25.).powi(2)).exp();
+	let cp_bar7 = cp_bar.powi(7);
+	let r_c = 2. * (cp_bar7 / (cp_bar7 + 25f64.powi(7))).sqrt();
+	let s_l = 1. + (0.015 * (lp_bar - 50.).powi(2)) / (20. + (lp_bar - 50.).powi(2)).sqrt();
+	let s_c = 1. + 0.045 * cp_bar;
+	let s_h = 1. + 0.015 * cp_bar * t;
+	let r_t = -(2. * d_theta).to_radians().sin() * r_c;
+
+	((dlp / s_l).powi(2) + (dcp / s_c).powi(2) + (dhp_big / s_h).powi(2)
+		+ r_t * (dcp / s_c) * (dhp_big / s_h)).sqrt() as f32
+}
+
+impl ColorMetric {
+	// Converts a color into the coordinates this metric measures distances in.
+	//
+	// Distances are always computed between converted colors, so that each
+	// color only has to be converted once however often it is compared.
+	pub fn prepare(&self, c: &Color) -> MetricColor {
+		let opacity = c.0[3] as f32 / 255.;
+		let [x, y, z] = match *self {
+			ColorMetric::Srgb => [c.0[0] as f32, c.0[1] as f32, c.0[2] as f32],
+			ColorMetric::CieLab => to_cielab(c).map(|v| v * 2.55),
+			ColorMetric::OkLab => to_oklab(c).map(|v| v * 255.),
+			ColorMetric::Ciede2000 => to_cielab(c),
+		};
+		[x * opacity, y * opacity, z * opacity, c.0[3] as f32]
+	}
+
+	// Range of each of the three color coordinates of prepared colors, wide
+	// enough for every sRGB color.
+	pub fn bounds(&self) -> [(f32, f32); 3] {
+		match *self {
+			ColorMetric::Srgb => [(0., 255.); 3],
+			ColorMetric::CieLab => [(0., 255.), (-280., 280.), (-280., 280.)],
+			ColorMetric::OkLab => [(0., 255.), (-82., 82.), (-82., 82.)],
+			ColorMetric::Ciede2000 => [(0., 100.), (-110., 110.), (-110., 110.)],
+		}
+	}
+
+	// Whether `prepared_distance` is the plain squared Euclidean distance
+	// between prepared colors, which spatial indexes such as `KdTree` rely on.
+	pub fn is_euclidean(&self) -> bool {
+		!matches!(*self, ColorMetric::Ciede2000)
+	}
+
+	// Squared distance between the color parts of two prepared colors.
+	fn color_term(&self, a: &MetricColor, b: &MetricColor) -> f32 {
+		match *self {
+			ColorMetric::Ciede2000 => (ciede2000(a, b) * 2.55).powi(2),
+			_ => (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2),
+		}
+	}
+
+	// Squared distance between two colors obtained from `prepare`.
+	pub fn prepared_distance(&self, a: &MetricColor, b: &MetricColor) -> f32 {
+		self.color_term(a, b) + (a[3] - b[3]).powi(2)
+	}
+
+	// Same as `prepared_distance`, but alpha only weighs a quarter as much,
+	// since it is less important when merging colors into the palette.
+	pub fn prepared_dedup_distance(&self, a: &MetricColor, b: &MetricColor) -> f32 {
+		self.color_term(a, b) + ((a[3] - b[3]).abs() / 4.).floor().powi(2)
+	}
+}
+
+impl std::str::FromStr for ColorMetric {
+	type Err = UnknownOption;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"srgb" | "rgb" => Ok(ColorMetric::Srgb),
+			"cielab" | "lab" | "cie76" => Ok(ColorMetric::CieLab),
+			"oklab" => Ok(ColorMetric::OkLab),
+			"ciede2000" | "de2000" => Ok(ColorMetric::Ciede2000),
+			_ => Err(UnknownOption::ColorMetric(s.to_string())),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn assert_close(got: &[f32], expected: &[f32], tolerance: f32) {
+		for (g, e) in got.iter().zip(expected) {
+			assert!((g - e).abs() <= tolerance, "{:?} != {:?}", got, expected);
+		}
+	}
+
+	// Pairs from the test data of Sharma, Wu & Dalal, including the ones
+	// whose mean hue wraps around.
+	#[test]
+	fn ciede2000_matches_reference_pairs() {
+		let pairs: [([f32; 3], [f32; 3], f32); 6] = [
+			([50., 2.6772, -79.7751], [50., 0., -82.7485], 2.0425),
+			([50., 3.1571, -77.2803], [50., 0., -82.7485], 2.8615),
+			([50., 0., 0.], [50., -1., 2.], 2.3669),
+			([50., 2.49, -0.001], [50., -2.49, 0.0009], 7.1792),
+			([50., 2.5, 0.], [73., 25., -18.], 27.1492),
+			([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+		];
+		for (a, b, expected) in pairs.iter() {
+			assert_close(&[ciede2000(a, b)], &[*expected], 1e-3);
+			assert_close(&[ciede2000(b, a)], &[*expected], 1e-3);
+		}
+	}
+
+	#[test]
+	fn conversions_match_reference_values() {
+		assert_close(&to_oklab(&image::Rgba([255, 255, 255, 255])), &[1., 0., 0.], 1e-3);
+		assert_close(&to_oklab(&image::Rgba([255, 0, 0, 255])), &[0.62796, 0.22486, 0.12585], 1e-3);
+		assert_close(&to_oklab(&image::Rgba([0, 255, 0, 255])), &[0.86644, -0.23389, 0.1795], 1e-3);
+		assert_close(&to_oklab(&image::Rgba([0, 0, 255, 255])), &[0.45201, -0.03246, -0.31153], 1e-3);
+		assert_close(&to_cielab(&image::Rgba([255, 255, 255, 255])), &[100., 0., 0.], 1e-2);
+		assert_close(&to_cielab(&image::Rgba([255, 0, 0, 255])), &[53.24, 80.09, 67.2], 5e-2);
+	}
+
+	#[test]
+	fn transparent_colors_are_all_the_same() {
+		for metric in [ColorMetric::Srgb, ColorMetric::CieLab, ColorMetric::OkLab, ColorMetric::Ciede2000] {
+			let a = metric.prepare(&image::Rgba([255, 0, 0, 0]));
+			let b = metric.prepare(&image::Rgba([0, 0, 255, 0]));
+			assert_eq!(metric.prepared_distance(&a, &b), 0., "{:?}", metric);
+		}
+	}
+
+	#[test]
+	fn srgb_distance_is_squared_byte_difference() {
+		let metric = ColorMetric::Srgb;
+		let a = metric.prepare(&image::Rgba([0, 0, 0, 255]));
+		let b = metric.prepare(&image::Rgba([255, 255, 255, 255]));
+		assert_eq!(metric.prepared_distance(&a, &b), 3. * 255f32.powi(2));
+		let c = metric.prepare(&image::Rgba([0, 0, 0, 235]));
+		assert_eq!(metric.prepared_dedup_distance(&a, &c), 25.);
+	}
+}
@@ -12,108 +12,344 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod alpha;
+pub mod builtin;
+pub mod dither;
+pub mod gamma;
+pub(crate) mod grid;
+pub(crate) mod kdtree;
+pub mod median_cut;
+pub mod metric;
+pub mod octree;
 pub mod palette;
+pub mod palette_file;
+
+use super::error::UnknownOption;
+use metric::ColorMetric;
 
 use std::collections::HashMap;
 
-type BigColor = image::Rgba<isize>;
+// Strategy for choosing the colors of a palette from an image.
+//
+// Implementors return the colors ordered from most to least important,
+// so that palettes which get cut short keep the colors that matter most.
+//
+// Pixels are taken from an iterator rather than from an image so that
+// a single palette can be computed over a whole set of images. They have
+// already gone through `alpha::AlphaMode::apply`, so every fully transparent
+// pixel is `alpha::TRANSPARENT`; implementors keep those out of their color
+// reduction and give them a single entry of their own (see `color_budget`
+// and `ranked_with_transparent`).
+pub trait PaletteGenerator {
+    fn colors_of(&self, pixels: &mut dyn Iterator<Item = palette::Color>) -> Vec<palette::Color>;
+}
 
-fn abs_sub(a: u8, b: u8) -> u8 {
-	(a as i16 - b as i16).abs() as u8
+// Number of colors left to a generator's color reduction once the
+// transparent entry, if there are `transparent` pixels for it, is set aside.
+pub fn color_budget(max_colors: usize, transparent: u64) -> usize {
+    std::cmp::max(std::cmp::max(max_colors, 1) - (transparent > 0) as usize, 1)
+}
+
+// Turns colors sorted by decreasing pixel count into palette colors, with
+// `alpha::TRANSPARENT` ranked among them if there were `transparent`
+// fully transparent pixels.
+//
+// `color_budget` never goes below one color, so a single color palette
+// could get the transparent entry on top of it; the least used of the two
+// is then left out to stay within `max_colors`.
+pub fn ranked_with_transparent(
+    mut rank: Vec<(palette::Color, u64)>,
+    transparent: u64,
+    max_colors: Option<usize>
+) -> Vec<palette::Color> {
+    if transparent > 0 {
+        let pos = rank.iter().position(|cc| cc.1 < transparent).unwrap_or(rank.len());
+        rank.insert(pos, (alpha::TRANSPARENT, transparent));
+    }
+    if let Some(max_colors) = max_colors {
+        rank.truncate(std::cmp::max(max_colors, 1));
+    }
+    rank.into_iter().map(|cc| cc.0).collect()
 }
 
-fn vec4_len_squared(a: u8, b: u8, c: u8, d: u8) -> u32 {
-	(a as u32 * a as u32) +
-	(b as u32 * b as u32) +
-	(c as u32 * c as u32) +
-	(d as u32 * d as u32)
+// Cuts a list of (color, pixel count) pairs, sorted by decreasing count,
+// down to `max_colors` entries.
+//
+// The first `max_colors` colors are kept. Every other one is merged into
+// the kept color nearest to it, which becomes the pixel-weighted
+// (premultiplied) average of everything merged into it.
+pub fn merge_excess_colors(
+    rank: Vec<(palette::Color, u64)>,
+    max_colors: usize,
+    metric: ColorMetric,
+    blending: gamma::Blending
+) -> Vec<(palette::Color, u64)> {
+    let max_colors = std::cmp::max(max_colors, 1);
+    if rank.len() <= max_colors {
+        return rank;
+    }
+    let kept = rank[..max_colors].iter()
+        .map(|cc| metric.prepare(&cc.0))
+        .collect::<Vec<_>>();
+    let mut sums = rank[..max_colors].iter()
+        .map(|cc| {
+            let mut sum = alpha::PremultipliedSum::new(blending);
+            sum.add(&cc.0, cc.1);
+            sum
+        })
+        .collect::<Vec<_>>();
+    for (col, count) in rank[max_colors..].iter() {
+        let prepared = metric.prepare(col);
+        let nearest = kept.iter()
+            .enumerate()
+            .map(|(ind, k)| (metric.prepared_distance(&prepared, k), ind))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap().1;
+        sums[nearest].add(col, *count);
+    }
+    let mut merged = sums.into_iter()
+        .map(|sum| (sum.average(), sum.count()))
+        .collect::<Vec<_>>();
+    merged.sort_by_key(|cc| std::cmp::Reverse(cc.1));
+    merged
 }
 
-fn color_distance(a: &palette::Color, b: &palette::Color) -> u32 {
-	vec4_len_squared(
-		abs_sub(a.0[0], b.0[0]),
-		abs_sub(a.0[1], b.0[1]),
-		abs_sub(a.0[2], b.0[2]),
-		abs_sub(a.0[3], b.0[3]),
-	)
+// The original greedy strategy: every unique color joins the first bucket
+// whose representative is closer than `threshold` (as measured by `metric`),
+// and each bucket becomes one palette color (the premultiplied average of
+// its members).
+//
+// Colors are visited from the most to the least used, so that the most used
+// colors become the representatives. Candidate buckets are looked up in a
+// `grid::ColorGrid`, which keeps the work roughly linear in the number of
+// unique colors; CIEDE2000 distances can't be bounded by such a grid, so
+// with that metric every bucket is compared instead.
+//
+// If there are more buckets than `max_colors`, the least used ones are
+// merged into the others (see `merge_excess_colors`).
+#[derive(Clone, Copy, Debug)]
+pub struct DedupGenerator {
+    pub threshold: u32,
+    pub metric: ColorMetric,
+    pub max_colors: Option<usize>,
+    pub blending: gamma::Blending,
 }
 
-fn dedup_distance(a: &palette::Color, b: &palette::Color) -> u32 {
-	vec4_len_squared(
-		abs_sub(a.0[0], b.0[0]),
-		abs_sub(a.0[1], b.0[1]),
-		abs_sub(a.0[2], b.0[2]),
-		abs_sub(a.0[3], b.0[3]) / 4, // alpha is less important
-	)
+impl PaletteGenerator for DedupGenerator {
+    fn colors_of(&self, pixels: &mut dyn Iterator<Item = palette::Color>) -> Vec<palette::Color> {
+        let mut successes = HashMap::new();
+        let mut transparent = 0;
+        for pixel in pixels {
+            if pixel == alpha::TRANSPARENT {
+                transparent += 1;
+            } else {
+                *successes.entry(pixel).or_insert(0u64) += 1;
+            }
+        }
+        let mut successes = successes.into_iter().collect::<Vec<_>>();
+        successes.sort_by_key(|cc| (std::cmp::Reverse(cc.1), cc.0.0));
+        // A bucket within `threshold` differs by less than its square root along
+        // every color axis, hence the side of the grid cells.
+        let mut grid = match self.metric.is_euclidean() {
+            true => Some(grid::ColorGrid::new((self.threshold as f32).sqrt().ceil().max(1.))),
+            false => None,
+        };
+        // Each bucket is kept along with its first color converted for the metric.
+        let mut similars: Vec<(metric::MetricColor, Vec<(palette::Color, u64)>)> = Vec::new();
+        for (col, count) in successes.into_iter() {
+            let prepared = self.metric.prepare(&col);
+            let is_similar = |ind: &usize| self.metric
+                .prepared_dedup_distance(&similars[*ind].0, &prepared) < self.threshold as f32;
+            // Lowest index, as if the buckets were compared in order.
+            let found = match grid {
+                Some(ref grid) => grid.near(&prepared).filter(is_similar).min(),
+                None => (0..similars.len()).find(is_similar),
+            };
+            match found {
+                Some(ind) => similars[ind].1.push((col, count)),
+                None => {
+                    if let Some(ref mut grid) = grid {
+                        grid.insert(&prepared, similars.len());
+                    }
+                    similars.push((prepared, vec![(col, count)]));
+                }
+            }
+        }
+        let mut rank = Vec::new();
+        rank.extend(similars.into_iter().map(|(_, cat)| {
+            let mut sum = alpha::PremultipliedSum::new(self.blending);
+            for (col, count) in cat.iter() {
+                sum.add(col, *count);
+            }
+            (sum.average(), sum.count())
+        }));
+        rank.sort_by_key(|cc: &(palette::Color, u64)| std::cmp::Reverse(cc.1));
+        if let Some(max_colors) = self.max_colors {
+            rank = merge_excess_colors(rank, color_budget(max_colors, transparent), self.metric, self.blending);
+        }
+        ranked_with_transparent(rank, transparent, self.max_colors)
+    }
 }
 
-fn color_add_big(a: BigColor, b: BigColor) -> BigColor {
-	image::Rgba::<isize>([
-		a.0[0] + b.0[0],
-		a.0[1] + b.0[1],
-		a.0[2] + b.0[2],
-		a.0[3] + b.0[3],
-	])
+// Number of colors the fixed-size strategies aim for unless told otherwise.
+pub const DEFAULT_MAX_COLORS: usize = 256;
+
+// Palette generation strategies that can be picked by name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PaletteAlgorithm {
+    // Greedy deduplication driven by a distance threshold (`DedupGenerator`).
+    #[default]
+    Dedup,
+    // Memory-bounded octree reduction (`octree::OctreeGenerator`).
+    Octree,
+    // Recursive median cut of the color histogram (`median_cut::MedianCutGenerator`).
+    MedianCut,
 }
 
-fn color_mul(a: &palette::Color, b: &isize) -> BigColor {
-	image::Rgba::<isize>([
-		a.0[0] as isize * b,
-		a.0[1] as isize * b,
-		a.0[2] as isize * b,
-		a.0[3] as isize * b,
-	])
+impl PaletteAlgorithm {
+    // Instantiates the strategy. `dedup_thres` only matters to `Dedup`.
+    // `max_colors` caps the palette size; without it, `Dedup` keeps every
+    // bucket and the fixed-size strategies aim for `DEFAULT_MAX_COLORS`.
+    // The octree subdivides the cube of `metric` coordinates (scaled to a
+    // byte each), which for `Srgb` are the color channels themselves.
+    // `blending` is the space colors are averaged in.
+    pub fn generator(
+        &self,
+        dedup_thres: u32,
+        max_colors: Option<usize>,
+        metric: ColorMetric,
+        blending: gamma::Blending
+    ) -> Box<dyn PaletteGenerator> {
+        let fixed_colors = max_colors.unwrap_or(DEFAULT_MAX_COLORS);
+        match *self {
+            PaletteAlgorithm::Dedup => Box::new(DedupGenerator {
+                threshold: dedup_thres,
+                metric,
+                max_colors,
+                blending
+            }),
+            PaletteAlgorithm::Octree => Box::new(octree::OctreeGenerator {
+                max_colors: fixed_colors,
+                metric,
+                blending
+            }),
+            PaletteAlgorithm::MedianCut => Box::new(median_cut::MedianCutGenerator {
+                max_colors: fixed_colors,
+                metric,
+                blending
+            }),
+        }
+    }
 }
 
-fn color_div(a: BigColor, b: isize) -> palette::Color {
-	image::Rgba::<u8>([
-		(a.0[0] / b) as u8,
-		(a.0[1] / b) as u8,
-		(a.0[2] / b) as u8,
-		(a.0[3] / b) as u8,
-	])
+impl std::str::FromStr for PaletteAlgorithm {
+    type Err = UnknownOption;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dedup" => Ok(PaletteAlgorithm::Dedup),
+            "octree" => Ok(PaletteAlgorithm::Octree),
+            "median-cut" | "median_cut" | "mediancut" => Ok(PaletteAlgorithm::MedianCut),
+            _ => Err(UnknownOption::PaletteAlgorithm(s.to_string())),
+        }
+    }
 }
 
-// Selects a palette of a given size and type through a process similar to
-// (but not quite the same as) finding the most commonly used colors in the image.
+// Selects a palette of a given type for `img` using the given strategy.
 //
-// `dedup_thres` indicates the (squared) limit for how "distant" colors can be
-// while still being quantized as one color
+// With `DedupGenerator`, this is a process similar to (but not quite the same
+// as) finding the most commonly used colors in the image; its `threshold`
+// indicates the (squared) limit for how "distant" colors can be while still
+// being quantized as one color
+//
+// Pixels go through `alpha` first; it should be the same mode as the one
+// of the `QuantizeOptions` later used with the palette.
 pub fn generate_palette<P: palette::DynamicPalette>(
     img: &image::RgbaImage,
-    dedup_thres: u32
+    generator: &dyn PaletteGenerator,
+    alpha: alpha::AlphaMode
 ) -> P {
-    let mut successes = HashMap::new();
-    for pixel in img.pixels() {
-        *successes.entry(*pixel).or_insert(0isize) += 1;
-    }
-    let mut similars: Vec<Vec<(palette::Color, isize)>> = Vec::new();
-    for (col, count) in successes.into_iter() {
-        let mut found = false;
-        for comp in similars.iter_mut() {
-            if dedup_distance(&comp[0].0, &col) < dedup_thres {
-                comp.push((col, count));
-                found = true;
-                break;
-            }
+    P::from(generator.colors_of(&mut img.pixels().map(|pix| alpha.apply(pix))))
+}
+
+// Selects one palette shared by all of `imgs`, as if they were a single image.
+pub fn generate_batch_palette<P: palette::DynamicPalette>(
+    imgs: &[image::RgbaImage],
+    generator: &dyn PaletteGenerator,
+    alpha: alpha::AlphaMode
+) -> P {
+    P::from(generator.colors_of(&mut imgs.iter().flat_map(|img| img.pixels()).map(|pix| alpha.apply(pix))))
+}
+
+// Settings for mapping pixels onto palette entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QuantizeOptions {
+    // How "nearest" is measured; should be the metric the palette was generated with.
+    pub metric: ColorMetric,
+    // How the rounding error of each pixel is spread to its neighbours.
+    pub dither: dither::Dither,
+    // How pixel alpha is treated; applied after the blur of `from_image`.
+    pub alpha: alpha::AlphaMode,
+}
+
+// Palettes up to this size are searched linearly, which beats a k-d tree
+// when there are only a handful of colors.
+const LINEAR_SEARCH_LIMIT: usize = 16;
+
+// Finds the palette entry nearest to a color, remembering past answers.
+//
+// Large palettes are searched through a k-d tree when the metric allows it;
+// the answers are the same as those of a linear scan either way.
+#[derive(Clone)]
+pub struct PaletteLookup {
+    colors: Vec<metric::MetricColor>,
+    metric: ColorMetric,
+    index: Option<kdtree::KdTree>,
+    cache: HashMap<palette::Color, u32>,
+}
+
+impl PaletteLookup {
+    pub fn new<P: palette::Palette>(palette: &P, metric: ColorMetric) -> Self {
+        let mut lookup = Self::linear(palette, metric);
+        if metric.is_euclidean() && lookup.colors.len() > LINEAR_SEARCH_LIMIT {
+            lookup.index = Some(kdtree::KdTree::new(lookup.colors.clone()));
         }
-        if !found {
-            similars.push(vec![(col, count)]);
+        lookup
+    }
+
+    // Same as `new`, but always scans every palette entry.
+    pub fn linear<P: palette::Palette>(palette: &P, metric: ColorMetric) -> Self {
+        let colors = palette_colors(palette).iter()
+            .map(|c| metric.prepare(c))
+            .collect();
+        PaletteLookup { colors, metric, index: None, cache: HashMap::new() }
+    }
+
+    pub fn nearest(&mut self, c: &palette::Color) -> u32 {
+        if let Some(ind) = self.cache.get(c) {
+            return *ind;
         }
+        let prepared = self.metric.prepare(c);
+        let ind = match self.index {
+            Some(ref index) => index.nearest(&prepared, self.metric).unwrap(),
+            None => self.colors.iter()
+                .enumerate()
+                .map(|(ind, col)| (self.metric.prepared_distance(&prepared, col), ind as u32))
+                .min_by(|a, b| a.0.total_cmp(&b.0))
+                .unwrap().1,
+        };
+        self.cache.insert(*c, ind);
+        ind
     }
-    let mut rank = Vec::new();
-    rank.extend(similars.into_iter().map(|cat| {
-        let total = cat.iter().map(|cc| cc.1).sum();
-        let col = color_div(
-            cat.iter()
-                .map(|cc| color_mul(&cc.0, &cc.1))
-                .fold(image::Rgba::<isize>([0; 4]), color_add_big),
-            total
-        );
-        (col, total)
-    }));
-    rank.sort_by_key(|cc: &(palette::Color, isize)| -cc.1);
-    P::from(rank.iter().map(|x| x.0).collect())
+}
+
+// Lists the colors a pixel may be mapped to: the palette's slice if it has
+// one, or else every color number its width allows.
+pub fn palette_colors<P: palette::Palette>(palette: &P) -> Vec<palette::Color> {
+    palette.get_slice().map(|x| x.to_owned())
+        .unwrap_or_else(|| (0..1 << palette.width())
+            .map(|n| palette.to_rgba(n).unwrap())
+            .collect::<Vec<_>>())
 }
 
 // Process an image given a palette so as to convert it to a "rectangle"
@@ -122,28 +358,89 @@ pub fn generate_palette<P: palette::DynamicPalette>(
 //
 // For the efficiency of the quadtree, the image may be Gaussian-blurred
 // before quantization; the extent to which this is done is controlled by `blur`
+//
+// "Closely" is in the sense of `options.metric`. Unless `options.dither`
+// is `Dither::None`, a pixel may instead get a slightly farther color so
+// that its neighbourhood averages out to the original colors.
 pub fn quantize_to_palette<P: palette::Palette>(
     img: &image::RgbaImage,
-    palette: &P
+    palette: &P,
+    options: &QuantizeOptions
 ) -> Vec<u32> {
-    let palette_colors = palette.get_slice().map(|x| x.to_owned())
-        .unwrap_or_else(|| (0..1 << palette.width())
-            .map(|n| palette.to_rgba(n as u32).unwrap())
-            .collect::<Vec<_>>());
-    let mut quant_cache = HashMap::new();
-    img.pixels()
-        .map(|pix| {
-            match quant_cache.get(pix) {
-                Some(c) => *c,
-                None => {
-                    let c = palette_colors.iter()
-                        .enumerate()
-                        .map(|(ind, col)| (color_distance(pix, col), ind as u32))
-                        .min().unwrap().1;
-                    quant_cache.insert(pix, c);
-                    c
+    let mut lookup = PaletteLookup::new(palette, options.metric);
+    let img = options.alpha.apply_to_image(img);
+    dither::dither(&img, &palette_colors(palette), &mut lookup, options.dither)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use palette::DynamicPaletteView;
+
+    // Many shades of every hue, a few translucent pixels and a transparent corner.
+    fn colorful() -> image::RgbaImage {
+        image::RgbaImage::from_fn(64, 64, |x, y| match (x, y) {
+            (0..=7, 0..=7) => alpha::TRANSPARENT,
+            _ if (x + y) % 13 == 0 => image::Rgba([(x * 4) as u8, 0, (y * 4) as u8, 100]),
+            _ => image::Rgba([(x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8, 255]),
+        })
+    }
+
+    #[test]
+    fn max_colors_caps_every_generator() {
+        let img = colorful();
+        for algo in [PaletteAlgorithm::Dedup, PaletteAlgorithm::Octree, PaletteAlgorithm::MedianCut] {
+            for max_colors in [1, 2, 16, 200] {
+                let generator = algo.generator(64, Some(max_colors), ColorMetric::Srgb, gamma::Blending::Srgb);
+                let colors = generator.colors_of(&mut img.pixels().copied());
+                assert!(!colors.is_empty() && colors.len() <= max_colors, "{:?}: {} colors for {}", algo, colors.len(), max_colors);
+                if max_colors > 1 {
+                    assert!(colors.contains(&alpha::TRANSPARENT), "{:?}, {} colors", algo, max_colors);
                 }
             }
-        })
-        .collect::<Vec<_>>()
+        }
+    }
+
+    #[test]
+    fn dedup_without_max_colors_keeps_every_bucket() {
+        let img = colorful();
+        let generator = PaletteAlgorithm::Dedup.generator(0, None, ColorMetric::Srgb, gamma::Blending::Srgb);
+        let colors = generator.colors_of(&mut img.pixels().copied());
+        let unique = img.pixels().collect::<std::collections::HashSet<_>>();
+        assert_eq!(colors.len(), unique.len());
+        // The transparent corner is the most used color.
+        assert_eq!(colors[0], alpha::TRANSPARENT);
+    }
+
+    #[test]
+    fn excess_colors_merge_into_the_nearest_kept_one() {
+        let rank = vec![
+            (image::Rgba([0, 0, 0, 255]), 6),
+            (image::Rgba([200, 200, 200, 255]), 4),
+            (image::Rgba([250, 250, 250, 255]), 4),
+            (image::Rgba([30, 0, 0, 255]), 3),
+        ];
+        let merged = merge_excess_colors(rank, 2, ColorMetric::Srgb, gamma::Blending::Srgb);
+        assert_eq!(merged, vec![
+            (image::Rgba([10, 0, 0, 255]), 9),
+            (image::Rgba([225, 225, 225, 255]), 8),
+        ]);
+        assert_eq!(color_budget(0, 0), 1);
+        assert_eq!(color_budget(16, 1), 15);
+        assert_eq!(color_budget(1, 1), 1);
+    }
+
+    #[test]
+    fn indexed_lookup_matches_a_linear_scan() {
+        let img = colorful();
+        for metric in [ColorMetric::Srgb, ColorMetric::OkLab, ColorMetric::Ciede2000] {
+            let generator = PaletteAlgorithm::MedianCut.generator(0, Some(100), metric, gamma::Blending::Srgb);
+            let palette = DynamicPaletteView::from(generator.colors_of(&mut img.pixels().copied()));
+            let (mut indexed, mut linear) = (PaletteLookup::new(&palette, metric), PaletteLookup::linear(&palette, metric));
+            for pix in img.pixels().step_by(7) {
+                let shifted = image::Rgba([pix.0[0] ^ 5, pix.0[1], pix.0[2] / 2, pix.0[3]]);
+                assert_eq!(indexed.nearest(&shifted), linear.nearest(&shifted), "{:?}, {:?}", metric, shifted);
+            }
+        }
+    }
 }
@@ -0,0 +1,193 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::alpha::{PremultipliedSum, TRANSPARENT};
+use super::gamma::Blending;
+use super::metric::ColorMetric;
+use super::palette::Color;
+use super::{color_budget, ranked_with_transparent, PaletteGenerator};
+
+// Depth at which octree nodes are always leaves (one level per bit of a channel).
+const MAX_DEPTH: usize = 8;
+
+// Node of the color octree, stored in an arena and addressed by index.
+//
+// Each level splits the color cube in eight along one bit of every channel.
+// Alpha doesn't take part in the subdivision; colors are simply averaged
+// (premultiplied) in the leaves.
+#[derive(Clone, Debug, Default)]
+struct OctreeNode {
+	children: [Option<usize>; 8],
+	leaf: bool,
+	sum: PremultipliedSum,
+}
+
+// Octree color quantizer.
+//
+// Pixels are fed into the tree one by one; whenever the number of leaves
+// exceeds `max_colors`, the deepest branch is folded into a single leaf.
+// Memory use is therefore bounded by `max_colors` rather than by the number
+// of unique colors in the image, which makes this well suited to very
+// large photos.
+//
+// The cube is the one of the coordinates of `metric`, each mapped onto a
+// byte, so that colors which look alike share branches.
+#[derive(Clone, Copy, Debug)]
+pub struct OctreeGenerator {
+	pub max_colors: usize,
+	pub metric: ColorMetric,
+	pub blending: Blending,
+}
+
+struct Octree {
+	nodes: Vec<OctreeNode>,
+	// Indices of arena slots freed by reductions, reused before growing `nodes`.
+	free: Vec<usize>,
+	// Branch nodes per depth, candidates for reduction.
+	reducible: [Vec<usize>; MAX_DEPTH],
+	leaves: usize,
+	blending: Blending,
+}
+
+// Position of a color in the octree cube: its `metric` coordinates scaled
+// from their bounds to a byte each. With `ColorMetric::Srgb`, these are the
+// color channels themselves.
+fn cube_position(c: &Color, metric: ColorMetric) -> [u8; 3] {
+	if metric == ColorMetric::Srgb {
+		return [c.0[0], c.0[1], c.0[2]];
+	}
+	let coords = metric.prepare(c);
+	let bounds = metric.bounds();
+	[0, 1, 2].map(|chan| {
+		let (min, max) = bounds[chan];
+		((coords[chan] - min) / (max - min) * 256.).clamp(0., 255.) as u8
+	})
+}
+
+fn child_index(pos: &[u8; 3], depth: usize) -> usize {
+	let shift = 7 - depth;
+	(((pos[0] >> shift) & 1) << 2 |
+		((pos[1] >> shift) & 1) << 1 |
+		((pos[2] >> shift) & 1)) as usize
+}
+
+impl Octree {
+	fn new(blending: Blending) -> Self {
+		let mut reducible: [Vec<usize>; MAX_DEPTH] = Default::default();
+		reducible[0].push(0);
+		Octree {
+			nodes: vec![OctreeNode { sum: PremultipliedSum::new(blending), ..Default::default() }],
+			free: Vec::new(),
+			reducible,
+			leaves: 0,
+			blending,
+		}
+	}
+
+	fn alloc(&mut self, depth: usize) -> usize {
+		let node = OctreeNode {
+			leaf: depth == MAX_DEPTH,
+			sum: PremultipliedSum::new(self.blending),
+			..Default::default()
+		};
+		let ind = match self.free.pop() {
+			Some(ind) => {
+				self.nodes[ind] = node;
+				ind
+			},
+			None => {
+				self.nodes.push(node);
+				self.nodes.len() - 1
+			}
+		};
+		if depth == MAX_DEPTH {
+			self.leaves += 1;
+		} else {
+			self.reducible[depth].push(ind);
+		}
+		ind
+	}
+
+	fn insert(&mut self, c: &Color, pos: &[u8; 3]) {
+		let mut curr = 0;
+		let mut depth = 0;
+		while !self.nodes[curr].leaf {
+			let sect = child_index(pos, depth);
+			curr = match self.nodes[curr].children[sect] {
+				Some(ind) => ind,
+				None => {
+					let ind = self.alloc(depth + 1);
+					self.nodes[curr].children[sect] = Some(ind);
+					ind
+				}
+			};
+			depth += 1;
+		}
+		self.nodes[curr].sum.add(c, 1);
+	}
+
+	// Folds the most recently created branch of the deepest level into a leaf.
+	fn reduce(&mut self) {
+		let depth = match (0..MAX_DEPTH).rev().find(|d| !self.reducible[*d].is_empty()) {
+			Some(d) => d,
+			None => return,
+		};
+		let ind = self.reducible[depth].pop().unwrap();
+		let mut merged = 0;
+		for sect in 0..8 {
+			if let Some(child) = self.nodes[ind].children[sect].take() {
+				let sum = self.nodes[child].sum;
+				self.nodes[ind].sum.merge(&sum);
+				self.free.push(child);
+				merged += 1;
+			}
+		}
+		self.nodes[ind].leaf = true;
+		self.leaves = self.leaves + 1 - merged;
+	}
+
+	// Collects the average color and pixel count of every leaf reachable from `ind`.
+	fn collect(&self, ind: usize, out: &mut Vec<(Color, u64)>) {
+		let node = &self.nodes[ind];
+		// Leaves are only ever created on the path of a pixel, so they can't be empty.
+		if node.leaf {
+			out.push((node.sum.average(), node.sum.count()));
+			return;
+		}
+		for child in node.children.iter().flatten() {
+			self.collect(*child, out);
+		}
+	}
+}
+
+impl PaletteGenerator for OctreeGenerator {
+	fn colors_of(&self, pixels: &mut dyn Iterator<Item = Color>) -> Vec<Color> {
+		let mut tree = Octree::new(self.blending);
+		let mut transparent = 0;
+		for pixel in pixels {
+			if pixel == TRANSPARENT {
+				transparent += 1;
+				continue;
+			}
+			tree.insert(&pixel, &cube_position(&pixel, self.metric));
+			while tree.leaves > color_budget(self.max_colors, transparent) {
+				tree.reduce();
+			}
+		}
+		let mut rank = Vec::new();
+		tree.collect(0, &mut rank);
+		rank.sort_by_key(|cc| std::cmp::Reverse(cc.1));
+		ranked_with_transparent(rank, transparent, Some(self.max_colors))
+	}
+}
@@ -16,7 +16,7 @@ pub type Color = image::Rgba<u8>;
 
 // Trait for types that describe how to convert from an arbitrary number
 // of a fixed size to four bytes of RGBA
-pub trait Palette: Default {
+pub trait Palette: Default + Send + Sync {
     // The bit width of each palette color's number
 
     // Must be `1 <= WIDTH <= 32`, because 0 bits wouldn't really be a palette
@@ -24,10 +24,14 @@ pub trait Palette: Default {
     fn width(&self) -> u8;
     // Uses an instance of the implementing type to convert a number
     // representing a palette entry into an RGBA value.
-    // If `c` is outside the range of the palette, an `Err` should be returned
+    // If `c` is outside the range of the palette, an `Err` should be returned;
+    // callers report it as their own `ColorOutOfRange` error.
+    #[allow(clippy::result_unit_err)]
     fn to_rgba(&self, c: u32) -> Result<Color, ()>;
     // Returns a reference to the slice listing the colors in the palette,
-    // only if that oss applicable and possible given the way the colors are stored
+    // only if that oss applicable and possible given the way the colors are stored.
+    // The slice may be shorter than `1 << width()`; missing entries are
+    // transparent black.
     fn get_slice(&self) -> Option<&[Color]>;
 }
 
@@ -45,6 +49,7 @@ macro_rules! generic_palette_struct {
 	(@inner $i:ident $n:expr, $e:expr) => {
 		#[doc = $e]
 		#[derive(Debug)]
+		#[allow(dead_code)]
 		pub struct $i {
 			pub colors: [Color; 1 << $n],
 		}
@@ -73,30 +78,30 @@ generic_palette_struct!(GenericPalette2 2, "two");
 generic_palette_struct!(GenericPalette3 3, "three");
 generic_palette_struct!(GenericPalette4 4, "four");
 generic_palette_struct!(GenericPalette5 5, "five");
+generic_palette_struct!(GenericPalette6 6, "six");
+generic_palette_struct!(GenericPalette7 7, "seven");
+generic_palette_struct!(GenericPalette8 8, "eight");
 
 // Used internally to generate a different sort of struct that implements `Palette`.
 macro_rules! palette_view_struct {
 	(@inner $i:ident $n:expr, $e:expr) => {
 		#[doc = $e]
 		#[derive(Debug)]
+		#[allow(dead_code)]
 		pub struct $i {
 			pub colors: Box<[Color]>,
 		}
 		impl Palette for $i {
 			fn width(&self) -> u8 { $n }
 			fn to_rgba(&self, c: u32) -> Result<Color, ()> {
-				if c > 1 << $n {
+				if c >= 1 << $n {
 					Err(())
 				} else {
 					Ok(*(self.colors.get(c as usize).unwrap_or(&image::Rgba([0; 4]))))
 				}
 			}
 			fn get_slice(&self) -> Option<&[Color]> {
-				if self.colors.len() >= 1 << $n {
-					Some(&self.colors[..1 << $n])
-				} else {
-					None
-				}
+				Some(&self.colors[..std::cmp::min(self.colors.len(), 1 << $n)])
 			}
 		}
 		impl Default for $i {
@@ -127,26 +132,28 @@ palette_view_struct!(PaletteView7 7, "seven");
 palette_view_struct!(PaletteView8 8, "eight");
 
 // A list of colors forming a palette, of a width determined at runtime.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct DynamicPaletteView {
 	pub colors: Box<[Color]>
 }
 
 impl Palette for DynamicPaletteView {
+	// Smallest width able to number every color (and at least 1), so that
+	// no color is ever left out of the palette.
 	fn width(&self) -> u8 {
-		(31 - (self.colors.len() as u32).leading_zeros()) as u8
+		std::cmp::max(1, self.colors.len().next_power_of_two().trailing_zeros()) as u8
 	}
 	fn to_rgba(&self, c: u32) -> Result<Color, ()> {
-		Ok(*(self.colors.get(c as usize).unwrap_or(&image::Rgba([0; 4]))))
+		if c >= 1 << self.width() {
+			Err(())
+		} else {
+			Ok(*(self.colors.get(c as usize).unwrap_or(&image::Rgba([0; 4]))))
+		}
 	}
+	// Only the actual colors; the `1 << width()` slots past them are
+	// implicitly transparent black.
 	fn get_slice(&self) -> Option<&[Color]> {
-		Some(&self.colors[..1 << self.width()])
-	}
-}
-
-impl Default for DynamicPaletteView {
-	fn default() -> Self {
-		DynamicPaletteView { colors: Default::default() }
+		Some(&self.colors)
 	}
 }
 
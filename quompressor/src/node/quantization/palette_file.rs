@@ -0,0 +1,307 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::palette::Color;
+use super::super::error::{DecodeError, EncodeError};
+
+// Magic bytes and version at the start of a palette file (see `doc/QPAL_spec.md`).
+const PALETTE_FILE_MAGIC: &[u8] = b"QuadPL\x01";
+
+// Identifies a palette by its colors, so that QIM files can refer to an
+// external palette file instead of embedding the colors.
+//
+// This is the 64-bit FNV-1a hash of the RGBA bytes of the colors, in order.
+// It guards against decoding with the wrong palette, not against forgery.
+pub fn palette_hash(colors: &[Color]) -> u64 {
+	colors.iter()
+		.flat_map(|c| c.0.iter())
+		.fold(0xcbf29ce484222325, |h, byte| (h ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// Serializes a list of colors into the palette file format.
+pub fn to_palette_file(colors: &[Color]) -> Vec<u8> {
+	let mut ret = Vec::with_capacity(PALETTE_FILE_MAGIC.len() + 4 + 4 * colors.len());
+	ret.extend_from_slice(PALETTE_FILE_MAGIC);
+	ret.extend_from_slice(&(colors.len() as u32).to_be_bytes());
+	for c in colors.iter() {
+		ret.extend_from_slice(&c.0);
+	}
+	ret
+}
+
+// Reads the list of colors back from palette file data.
+pub fn from_palette_file(source: &[u8]) -> Result<Vec<Color>, DecodeError> {
+	if !source.starts_with(PALETTE_FILE_MAGIC) {
+		return Err(DecodeError::MissingHeader);
+	}
+	let body = &source[PALETTE_FILE_MAGIC.len()..];
+	if body.len() < 4 {
+		return Err(DecodeError::InsufficientData);
+	}
+	let count = u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize;
+	let colors = &body[4..];
+	if colors.len() < 4 * count {
+		return Err(DecodeError::InsufficientData);
+	}
+	Ok(colors.chunks_exact(4)
+		.take(count)
+		.map(|c| image::Rgba([c[0], c[1], c[2], c[3]]))
+		.collect())
+}
+
+// Palette file formats colors can be read from and written to.
+//
+// Only `Qpal` keeps alpha. GIMP and JASC palettes are read as opaque colors;
+// Adobe color tables may mark one entry as fully transparent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteFormat {
+	// quompressor's own palette files (see `doc/QPAL_spec.md`).
+	Qpal,
+	// GIMP `.gpl` text palettes.
+	Gimp,
+	// Adobe `.act` color tables: up to 256 RGB triplets.
+	AdobeColorTable,
+	// JASC (Paint Shop Pro) `.pal` text palettes.
+	Jasc,
+}
+
+// Longest palette an Adobe color table can hold.
+const ACT_MAX_COLORS: usize = 256;
+// Size of an Adobe color table without the trailing count and transparency index.
+const ACT_TABLE_LEN: usize = 3 * ACT_MAX_COLORS;
+
+impl PaletteFormat {
+	// Guesses the format of a palette file from the extension of its path,
+	// defaulting to `Qpal`.
+	pub fn from_path(path: &str) -> Self {
+		let ext = path.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase());
+		match ext.as_deref() {
+			Some("gpl") => PaletteFormat::Gimp,
+			Some("act") => PaletteFormat::AdobeColorTable,
+			Some("pal") => PaletteFormat::Jasc,
+			_ => PaletteFormat::Qpal,
+		}
+	}
+
+	// Reads the colors of a palette file, of which there must be at least one.
+	pub fn read(&self, source: &[u8]) -> Result<Vec<Color>, DecodeError> {
+		let colors = match *self {
+			PaletteFormat::Qpal => from_palette_file(source),
+			PaletteFormat::Gimp => from_gpl(source),
+			PaletteFormat::AdobeColorTable => from_act(source),
+			PaletteFormat::Jasc => from_jasc(source),
+		}?;
+		if colors.is_empty() {
+			return Err(DecodeError::EmptyPalette);
+		}
+		Ok(colors)
+	}
+
+	pub fn write(&self, colors: &[Color]) -> Result<Vec<u8>, EncodeError> {
+		match *self {
+			PaletteFormat::Qpal => Ok(to_palette_file(colors)),
+			PaletteFormat::Gimp => Ok(to_gpl(colors)),
+			PaletteFormat::AdobeColorTable => to_act(colors),
+			PaletteFormat::Jasc => Ok(to_jasc(colors)),
+		}
+	}
+}
+
+// Reads an "R G B" triplet from the start of a line of a text palette;
+// anything after it (such as a color name) is ignored.
+fn parse_rgb(line: &str) -> Result<Color, DecodeError> {
+	let mut chans = line.split_whitespace().map(str::parse::<u8>);
+	match (chans.next(), chans.next(), chans.next()) {
+		(Some(Ok(r)), Some(Ok(g)), Some(Ok(b))) => Ok(image::Rgba([r, g, b, 255])),
+		_ => Err(DecodeError::MalformedPalette),
+	}
+}
+
+fn from_gpl(source: &[u8]) -> Result<Vec<Color>, DecodeError> {
+	let text = String::from_utf8_lossy(source);
+	let mut lines = text.lines();
+	if lines.next().map(str::trim) != Some("GIMP Palette") {
+		return Err(DecodeError::MissingHeader);
+	}
+	lines.map(str::trim)
+		.filter(|l| !l.is_empty() && !l.starts_with('#'))
+		.filter(|l| !l.starts_with("Name:") && !l.starts_with("Columns:"))
+		.map(parse_rgb)
+		.collect()
+}
+
+fn to_gpl(colors: &[Color]) -> Vec<u8> {
+	let mut ret = String::from("GIMP Palette\nName: quompressor\nColumns: 16\n#\n");
+	for c in colors.iter() {
+		ret += &format!(
+			"{:3} {:3} {:3}\t#{:02x}{:02x}{:02x}\n",
+			c.0[0], c.0[1], c.0[2], c.0[0], c.0[1], c.0[2]
+		);
+	}
+	ret.into_bytes()
+}
+
+// An Adobe color table is 256 RGB triplets, optionally followed by the
+// number of colors in use and the index of the transparent color
+// (both 16-bit big-endian; 0xffff for no transparent color).
+fn from_act(source: &[u8]) -> Result<Vec<Color>, DecodeError> {
+	if source.len() < ACT_TABLE_LEN {
+		return Err(DecodeError::InsufficientData);
+	}
+	let (count, transparent) = match source.get(ACT_TABLE_LEN..ACT_TABLE_LEN + 4) {
+		Some(tail) => (
+			std::cmp::min(u16::from_be_bytes([tail[0], tail[1]]) as usize, ACT_MAX_COLORS),
+			u16::from_be_bytes([tail[2], tail[3]]) as usize
+		),
+		None => (ACT_MAX_COLORS, usize::MAX),
+	};
+	Ok(source[..3 * count].chunks_exact(3)
+		.enumerate()
+		.map(|(ind, c)| image::Rgba([c[0], c[1], c[2], if ind == transparent { 0 } else { 255 }]))
+		.collect())
+}
+
+fn to_act(colors: &[Color]) -> Result<Vec<u8>, EncodeError> {
+	if colors.len() > ACT_MAX_COLORS {
+		return Err(EncodeError::TooManyColors);
+	}
+	let mut ret = Vec::with_capacity(ACT_TABLE_LEN + 4);
+	for c in colors.iter() {
+		ret.extend_from_slice(&c.0[..3]);
+	}
+	ret.resize(ACT_TABLE_LEN, 0);
+	let transparent = colors.iter().position(|c| c.0[3] == 0).unwrap_or(0xffff);
+	ret.extend_from_slice(&(colors.len() as u16).to_be_bytes());
+	ret.extend_from_slice(&(transparent as u16).to_be_bytes());
+	Ok(ret)
+}
+
+// JASC palettes are "JASC-PAL", a "0100" version line, the number of
+// colors and then one "R G B" line per color.
+fn from_jasc(source: &[u8]) -> Result<Vec<Color>, DecodeError> {
+	let text = String::from_utf8_lossy(source);
+	let mut lines = text.lines().map(str::trim);
+	if lines.next() != Some("JASC-PAL") || lines.next().is_none() {
+		return Err(DecodeError::MissingHeader);
+	}
+	let count = match lines.next().map(str::parse::<usize>) {
+		Some(Ok(n)) => n,
+		_ => return Err(DecodeError::MissingHeader),
+	};
+	let colors = lines.filter(|l| !l.is_empty())
+		.take(count)
+		.map(parse_rgb)
+		.collect::<Result<Vec<_>, _>>()?;
+	if colors.len() < count {
+		return Err(DecodeError::InsufficientData);
+	}
+	Ok(colors)
+}
+
+fn to_jasc(colors: &[Color]) -> Vec<u8> {
+	let mut ret = format!("JASC-PAL\r\n0100\r\n{}\r\n", colors.len());
+	for c in colors.iter() {
+		ret += &format!("{} {} {}\r\n", c.0[0], c.0[1], c.0[2]);
+	}
+	ret.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn opaque_colors() -> Vec<Color> {
+		(0..40u8).map(|n| image::Rgba([n * 6, 255 - n, n.wrapping_mul(97), 255])).collect()
+	}
+
+	#[test]
+	fn every_format_round_trips() {
+		let colors = opaque_colors();
+		for format in [PaletteFormat::Qpal, PaletteFormat::Gimp, PaletteFormat::AdobeColorTable, PaletteFormat::Jasc] {
+			let data = format.write(&colors).unwrap();
+			assert_eq!(format.read(&data).unwrap(), colors, "{:?}", format);
+		}
+	}
+
+	#[test]
+	fn transparency_survives_where_the_format_has_it() {
+		let mut colors = opaque_colors();
+		colors[3] = image::Rgba([0; 4]);
+		colors[5] = image::Rgba([10, 20, 30, 128]);
+		let qpal = PaletteFormat::Qpal.write(&colors).unwrap();
+		assert_eq!(PaletteFormat::Qpal.read(&qpal).unwrap(), colors);
+		let act = PaletteFormat::AdobeColorTable.write(&colors).unwrap();
+		let read = PaletteFormat::AdobeColorTable.read(&act).unwrap();
+		assert_eq!(read[3], image::Rgba([0; 4]));
+		assert_eq!(read[5], image::Rgba([10, 20, 30, 255]));
+		let gpl = PaletteFormat::Gimp.write(&colors).unwrap();
+		assert_eq!(PaletteFormat::Gimp.read(&gpl).unwrap()[3], image::Rgba([0, 0, 0, 255]));
+	}
+
+	#[test]
+	fn foreign_palettes_are_read() {
+		let gpl = b"GIMP Palette\nName: test\nColumns: 4\n# comment\n\n255 0 0 Red\n  0 128 255\tSky\n";
+		assert_eq!(PaletteFormat::Gimp.read(gpl).unwrap(), vec![image::Rgba([255, 0, 0, 255]), image::Rgba([0, 128, 255, 255])]);
+		let jasc = b"JASC-PAL\r\n0100\r\n2\r\n1 2 3\r\n4 5 6\r\n";
+		assert_eq!(PaletteFormat::Jasc.read(jasc).unwrap(), vec![image::Rgba([1, 2, 3, 255]), image::Rgba([4, 5, 6, 255])]);
+		// Color tables without the trailing count hold all 256 colors.
+		let act = (0..ACT_TABLE_LEN).map(|n| (n / 3) as u8).collect::<Vec<_>>();
+		let colors = PaletteFormat::AdobeColorTable.read(&act).unwrap();
+		assert_eq!(colors.len(), ACT_MAX_COLORS);
+		assert_eq!(colors[200], image::Rgba([200, 200, 200, 255]));
+	}
+
+	#[test]
+	fn broken_palettes_are_rejected() {
+		let qpal = to_palette_file(&opaque_colors());
+		assert!(matches!(PaletteFormat::Qpal.read(&qpal[..qpal.len() - 1]), Err(DecodeError::InsufficientData)));
+		assert!(matches!(PaletteFormat::Qpal.read(b"QuadPL\x01\x00"), Err(DecodeError::InsufficientData)));
+		assert!(matches!(PaletteFormat::Qpal.read(b"GIMP Palette\n"), Err(DecodeError::MissingHeader)));
+		assert!(matches!(PaletteFormat::Gimp.read(b"GIMP Palette\n1 2\n"), Err(DecodeError::MalformedPalette)));
+		assert!(matches!(PaletteFormat::Gimp.read(b"1 2 3\n"), Err(DecodeError::MissingHeader)));
+		assert!(matches!(PaletteFormat::AdobeColorTable.read(&[0; 100]), Err(DecodeError::InsufficientData)));
+		assert!(matches!(PaletteFormat::AdobeColorTable.write(&[image::Rgba([0; 4]); 257]), Err(EncodeError::TooManyColors)));
+	}
+
+	#[test]
+	fn formats_follow_extensions() {
+		assert_eq!(PaletteFormat::from_path("a/b.GPL"), PaletteFormat::Gimp);
+		assert_eq!(PaletteFormat::from_path("b.act"), PaletteFormat::AdobeColorTable);
+		assert_eq!(PaletteFormat::from_path("b.pal"), PaletteFormat::Jasc);
+		assert_eq!(PaletteFormat::from_path("b.qpal"), PaletteFormat::Qpal);
+		assert_eq!(PaletteFormat::from_path("palette"), PaletteFormat::Qpal);
+	}
+
+	// The hash is part of the QIM format, so it must never change.
+	#[test]
+	fn palette_hash_is_fnv1a() {
+		assert_eq!(palette_hash(&[]), 0xcbf29ce484222325);
+		assert_ne!(palette_hash(&[image::Rgba([1, 2, 3, 4])]), palette_hash(&[image::Rgba([4, 3, 2, 1])]));
+	}
+
+	#[test]
+	fn empty_palettes_are_rejected() {
+		let mut act = vec![0; ACT_TABLE_LEN];
+		act.extend_from_slice(&[0, 0, 0xff, 0xff]);
+		let empty = [
+			(PaletteFormat::Qpal, to_palette_file(&[])),
+			(PaletteFormat::Gimp, b"GIMP Palette\nName: empty\nColumns: 16\n#\n".to_vec()),
+			(PaletteFormat::AdobeColorTable, act),
+			(PaletteFormat::Jasc, b"JASC-PAL\r\n0100\r\n0\r\n".to_vec()),
+		];
+		for (format, data) in empty.iter() {
+			assert!(matches!(format.read(data), Err(DecodeError::EmptyPalette)), "{:?}", format);
+		}
+	}
+}
@@ -0,0 +1,241 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use super::error::{DrawError, UnknownOption};
+use super::quantization::alpha::PremultipliedSum;
+use super::quantization::gamma::Blending;
+use super::quantization::metric::MetricColor;
+use super::quantization::palette::{Color, Palette};
+use super::quantization::{PaletteLookup, QuantizeOptions};
+
+// Statistic of the source pixels of a leaf whose nearest palette color
+// `QuadtreeNode::refit` gives the leaf.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Refit {
+	// Premultiplied average, in the blending space.
+	#[default]
+	Mean,
+	// Median of each channel, which ignores a few outlying pixels.
+	Median,
+}
+
+impl Refit {
+	fn representative(&self, pixels: &mut [Color], blending: Blending) -> Color {
+		match *self {
+			Refit::Mean => {
+				let mut sum = PremultipliedSum::new(blending);
+				for pixel in pixels.iter() {
+					sum.add(pixel, 1);
+				}
+				sum.average()
+			},
+			Refit::Median => {
+				let mid = pixels.len() / 2;
+				image::Rgba([0, 1, 2, 3].map(|chan| {
+					pixels.select_nth_unstable_by_key(mid, |p| p.0[chan]);
+					pixels[mid].0[chan]
+				}))
+			},
+		}
+	}
+}
+
+// What `refit_square` fits leaves with: the pixels of the source, also
+// prepared for the metric, and the prepared palette colors.
+struct Fit<'a> {
+	pixels: Vec<Color>,
+	prepared: Vec<MetricColor>,
+	colors: Vec<MetricColor>,
+	refit: Refit,
+	quantize: &'a QuantizeOptions,
+	blending: Blending,
+}
+
+impl<P: Palette + Default> super::QuadtreeNode<P> {
+	// Recolors every leaf with the palette color nearest to the `refit`
+	// statistic of the pixels of `source` it covers, `source` being the
+	// image the tree was mounted from (unblurred). The most common palette
+	// color of a square may be a poor stand-in for a slowly varying area;
+	// this lowers the error without touching the shape of the tree, hence
+	// the size of its encoding.
+	//
+	// Pixels go through `quantize.alpha` and are matched with
+	// `quantize.metric`, as when the tree was mounted; dithering doesn't
+	// apply. Means are taken in `blending`. A leaf only takes the new color
+	// if it lowers the sum of the squared `quantize.metric` distances
+	// between the leaf and its pixels. Branches and the corners of gradient
+	// leaves keep their colors.
+	pub fn refit(
+		&mut self,
+		source: &image::RgbaImage,
+		palette: &P,
+		refit: Refit,
+		quantize: &QuantizeOptions,
+		blending: Blending
+	) -> Result<(), DrawError> {
+		if source.width() != source.height() {
+			return Err(DrawError::NonSquare);
+		}
+		if !source.width().is_power_of_two() {
+			return Err(DrawError::NonPowerOfTwo);
+		}
+		let pixels = source.pixels().map(|p| quantize.alpha.apply(p)).collect::<Vec<_>>();
+		let fit = Fit {
+			prepared: pixels.iter().map(|p| quantize.metric.prepare(p)).collect(),
+			colors: (0..1u32 << palette.width())
+				.map(|n| palette.to_rgba(n).map(|c| quantize.metric.prepare(&c)))
+				.collect::<Result<_, _>>()
+				.map_err(|_| DrawError::ColorOutOfRange)?,
+			pixels,
+			refit,
+			quantize,
+			blending,
+		};
+		let mut lookup = PaletteLookup::new(palette, quantize.metric);
+		let size = source.width() as usize;
+		self.refit_square(&fit, size, size, (0, 0), &mut lookup)
+	}
+
+	// Refits the leaves of the `size`-wide square at `start_pos`.
+	fn refit_square(
+		&mut self,
+		fit: &Fit,
+		row_len: usize,
+		size: usize,
+		start_pos: (usize, usize),
+		lookup: &mut PaletteLookup
+	) -> Result<(), DrawError> {
+		// Leaves smaller than a pixel of `source` keep their color.
+		if size == 0 {
+			return Ok(());
+		}
+		let rows = || (start_pos.1..start_pos.1 + size)
+			.map(|row| (row * row_len + start_pos.0)..(row * row_len + start_pos.0 + size));
+		match self.sections {
+			Some(ref mut sects) => for (sect_ind, section) in sects.iter_mut().enumerate() {
+				section.refit_square(
+					fit,
+					row_len,
+					size / 2,
+					(
+						start_pos.0 + (sect_ind & 1) * (size / 2),
+						start_pos.1 + (sect_ind >> 1) * (size / 2),
+					),
+					lookup
+				)?;
+			},
+			None => {
+				let mut square = rows()
+					.flat_map(|range| fit.pixels[range].iter())
+					.copied()
+					.collect::<Vec<_>>();
+				let candidate = lookup.nearest(&fit.refit.representative(&mut square, fit.blending));
+				let error = |color: u32| -> Result<f64, DrawError> {
+					let color = fit.colors.get(color as usize).ok_or(DrawError::ColorOutOfRange)?;
+					Ok(rows()
+						.flat_map(|range| fit.prepared[range].iter())
+						.map(|p| fit.quantize.metric.prepared_distance(p, color) as f64)
+						.sum())
+				};
+				if candidate != self.color && error(candidate)? < error(self.color)? {
+					self.color = candidate;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+impl std::str::FromStr for Refit {
+	type Err = UnknownOption;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"mean" | "average" => Ok(Refit::Mean),
+			"median" => Ok(Refit::Median),
+			_ => Err(UnknownOption::Refit(s.to_string())),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::node::QuadtreeNode;
+	use crate::node::quantization::palette::DynamicPaletteView;
+
+	// The channel-wise median of red, green, blue and black is black, which
+	// is further from them than the gray the leaf already has.
+	#[test]
+	fn refit_keeps_a_better_color() {
+		let palette = DynamicPaletteView::from(vec![image::Rgba([0, 0, 0, 255]), image::Rgba([85, 85, 85, 255])]);
+		let source = image::RgbaImage::from_vec(2, 2, vec![
+			255, 0, 0, 255, 0, 255, 0, 255,
+			0, 0, 255, 255, 0, 0, 0, 255,
+		]).unwrap();
+		let mut tree = QuadtreeNode::<DynamicPaletteView> { color: 1, ..Default::default() };
+		tree.refit(&source, &palette, Refit::Median, &Default::default(), Blending::Srgb).unwrap();
+		assert_eq!(tree.color, 1);
+		let mut tree = QuadtreeNode::<DynamicPaletteView> { color: 0, ..Default::default() };
+		tree.refit(&source, &palette, Refit::Mean, &Default::default(), Blending::Srgb).unwrap();
+		assert_eq!(tree.color, 1);
+	}
+
+	fn grays() -> DynamicPaletteView {
+		DynamicPaletteView::from([0, 100, 140, 255].map(|v| image::Rgba([v, v, v, 255])).to_vec())
+	}
+
+	// Three pixels of gray 100 and a white one: the mean is nearest to gray
+	// 140, while the median ignores the white pixel.
+	#[test]
+	fn median_ignores_outliers() {
+		let source = image::RgbaImage::from_fn(2, 2, |x, y| match (x, y) {
+			(1, 1) => image::Rgba([255; 4]),
+			_ => image::Rgba([100, 100, 100, 255]),
+		});
+		for (refit, color) in [(Refit::Mean, 2), (Refit::Median, 1)] {
+			let mut tree = QuadtreeNode::<DynamicPaletteView>::default();
+			tree.refit(&source, &grays(), refit, &Default::default(), Blending::Srgb).unwrap();
+			assert_eq!(tree.color, color, "{:?}", refit);
+		}
+	}
+
+	#[test]
+	fn branches_and_gradient_corners_keep_their_colors() {
+		let source = image::RgbaImage::from_pixel(4, 4, image::Rgba([255; 4]));
+		let leaf = |color| QuadtreeNode::<DynamicPaletteView> { color, ..Default::default() };
+		let gradient = QuadtreeNode { gradient: Some([0, 1, 2, 3]), ..leaf(0) };
+		let mut tree = QuadtreeNode { color: 1, sections: Some(Box::new([leaf(0), leaf(3), gradient, leaf(1)])), ..Default::default() };
+		tree.refit(&source, &grays(), Refit::Mean, &Default::default(), Blending::Srgb).unwrap();
+		assert_eq!(tree.color, 1);
+		let sects = tree.sections.unwrap();
+		assert_eq!(sects.iter().map(|s| s.color).collect::<Vec<_>>(), vec![3, 3, 3, 3]);
+		assert_eq!(sects[2].gradient, Some([0, 1, 2, 3]));
+	}
+
+	#[test]
+	fn mismatched_sources_are_rejected() {
+		let mut tree = QuadtreeNode::<DynamicPaletteView>::default();
+		let options = Default::default();
+		let wide = image::RgbaImage::new(4, 2);
+		assert!(matches!(tree.refit(&wide, &grays(), Refit::Mean, &options, Blending::Srgb), Err(DrawError::NonSquare)));
+		let odd = image::RgbaImage::new(3, 3);
+		assert!(matches!(tree.refit(&odd, &grays(), Refit::Mean, &options, Blending::Srgb), Err(DrawError::NonPowerOfTwo)));
+		tree.color = 9;
+		let square = image::RgbaImage::new(2, 2);
+		assert!(matches!(tree.refit(&square, &grays(), Refit::Mean, &options, Blending::Srgb), Err(DrawError::ColorOutOfRange)));
+		assert!("mode".parse::<Refit>().is_err());
+	}
+}
@@ -0,0 +1,315 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use super::edges::EdgeMap;
+use super::error::UnknownOption;
+use super::quantization::metric::{ColorMetric, MetricColor};
+use super::quantization::palette::Palette;
+
+// How much color error a leaf may hide before its square gets split.
+//
+// Limits are distances in the units of a `ColorMetric`, that is roughly sRGB
+// byte differences, not squared ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorLimit {
+	// Root mean square distance between the pixels and the leaf color.
+	Rms(f32),
+	// Largest distance between any pixel and the leaf color.
+	MaxDeviation(f32),
+}
+
+// Rule deciding whether `QuadtreeNode::mount` splits a square.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SplitCriterion {
+	// Split unless the most common color covers at least this fraction
+	// (out of 16384) of the square.
+	Majority(usize),
+	// Split when the pixels stray further from the leaf color than the
+	// limit, as measured by the metric.
+	Error(ErrorLimit, ColorMetric),
+}
+
+impl Default for SplitCriterion {
+	fn default() -> Self {
+		// (16384 * s) / (s + 1) with s = 63
+		SplitCriterion::Majority(16128)
+	}
+}
+
+// Number of steps between the coarsest and the finest `SplitCriterion` of
+// a kind (see `SplitCriterion::at_level`).
+pub const MAX_LEVEL: usize = 16384;
+
+impl SplitCriterion {
+	// The criterion of the same kind at `level`, from 0 for the coarsest
+	// trees to `MAX_LEVEL` for the finest; trees only get finer with the level.
+	//
+	// Error limits go from 1024, which no pixel can stray from, down to 0
+	// in steps of 1/16.
+	pub fn at_level(&self, level: usize) -> Self {
+		let level = level.min(MAX_LEVEL);
+		match *self {
+			SplitCriterion::Majority(_) => SplitCriterion::Majority(level),
+			SplitCriterion::Error(limit, metric) => {
+				let max = (MAX_LEVEL - level) as f32 / 16.;
+				SplitCriterion::Error(match limit {
+					ErrorLimit::Rms(_) => ErrorLimit::Rms(max),
+					ErrorLimit::MaxDeviation(_) => ErrorLimit::MaxDeviation(max),
+				}, metric)
+			},
+		}
+	}
+}
+
+// Palette histogram of a square: palette indices and their pixel counts,
+// by increasing index.
+pub(crate) type Histogram = Vec<(u32, usize)>;
+
+// Adds up two histograms.
+pub(crate) fn merge_histograms(a: &Histogram, b: &Histogram) -> Histogram {
+	let mut merged = Vec::with_capacity(a.len() + b.len());
+	let (mut a, mut b) = (a.iter().peekable(), b.iter().peekable());
+	loop {
+		let next = match (a.peek(), b.peek()) {
+			(Some(x), Some(y)) if x.0 == y.0 => {
+				let sum = (x.0, x.1 + y.1);
+				a.next();
+				b.next();
+				sum
+			},
+			(Some(x), Some(y)) if x.0 < y.0 => *a.next().unwrap(),
+			(Some(_), Some(_)) | (None, Some(_)) => *b.next().unwrap(),
+			(Some(_), None) => *a.next().unwrap(),
+			(None, None) => return merged,
+		};
+		merged.push(next);
+	}
+}
+
+// The most common entry of a histogram, the lowest index winning ties.
+pub(crate) fn most_common(histogram: &Histogram) -> (u32, usize) {
+	histogram.iter()
+		.fold((0, 0), |best, e| if e.1 > best.1 { *e } else { best })
+}
+
+// A `SplitCriterion` along with the palette colors it measures errors on,
+// and the weight of edges (see `EdgeMap`).
+pub(crate) struct SplitJudge {
+	criterion: SplitCriterion,
+	prepared: Vec<MetricColor>,
+	edge_weight: f32,
+}
+
+impl SplitJudge {
+	pub fn new<P: Palette>(criterion: SplitCriterion, palette: &P, edge_weight: f32) -> Self {
+		let prepared = match criterion {
+			SplitCriterion::Majority(_) => Vec::new(),
+			SplitCriterion::Error(_, metric) => (0..1u32 << palette.width())
+				.map(|n| metric.prepare(&palette.to_rgba(n).unwrap()))
+				.collect(),
+		};
+		SplitJudge { criterion, prepared, edge_weight }
+	}
+
+	// The criterion for a square of `pixels` pixels whose edge strengths add
+	// up to `edges`: the error it tolerates shrinks along edges.
+	fn criterion_for(&self, edges: f64, pixels: usize) -> SplitCriterion {
+		let tolerance = EdgeMap::tolerance(self.edge_weight, edges / pixels as f64);
+		match self.criterion {
+			SplitCriterion::Majority(sensitivity) =>
+				SplitCriterion::Majority(16384 - ((16384 - sensitivity.min(16384)) as f64 * tolerance) as usize),
+			SplitCriterion::Error(limit, metric) => SplitCriterion::Error(match limit {
+				ErrorLimit::Rms(max) => ErrorLimit::Rms((max as f64 * tolerance) as f32),
+				ErrorLimit::MaxDeviation(max) => ErrorLimit::MaxDeviation((max as f64 * tolerance) as f32),
+			}, metric),
+		}
+	}
+
+	// Whether pixels whose squared distances to their reconstruction are
+	// given by `dist` (one per histogram entry) exceed `limit`.
+	//
+	// Squared distances are added up from the most common color down (the
+	// lowest index first among equally common ones), as squares used to be
+	// counted, so that the rounding of the sum doesn't depend on the order
+	// histograms are kept in.
+	fn exceeds(limit: ErrorLimit, histogram: &Histogram, pixels: usize, dist: impl Fn(u32) -> f32) -> bool {
+		match limit {
+			ErrorLimit::Rms(max) => {
+				let mut by_count = histogram.iter().collect::<Vec<_>>();
+				by_count.sort_unstable_by_key(|(color, count)| (std::cmp::Reverse(*count), *color));
+				by_count.into_iter()
+					.map(|(color, count)| dist(*color) as f64 * *count as f64)
+					.sum::<f64>() > (max as f64).powi(2) * pixels as f64
+			},
+			ErrorLimit::MaxDeviation(max) => histogram.iter()
+				.any(|(color, _)| dist(*color) > max.powi(2)),
+		}
+	}
+
+	// Whether a square of `pixels` pixels with this histogram needs
+	// subsections, its own color being `leaf`, the most common one, and its
+	// edge strengths adding up to `edges`.
+	pub fn must_split(&self, histogram: &Histogram, leaf: (u32, usize), pixels: usize, edges: f64) -> bool {
+		match self.criterion_for(edges, pixels) {
+			SplitCriterion::Majority(sensitivity) => leaf.1 < (sensitivity * pixels) / 16384,
+			SplitCriterion::Error(limit, metric) => {
+				let leaf = &self.prepared[leaf.0 as usize];
+				Self::exceeds(limit, histogram, pixels, |c| metric.prepared_distance(&self.prepared[c as usize], leaf))
+			},
+		}
+	}
+
+	// Whether a square that must be split is close enough to a gradient
+	// between the four `corners` colors to stop there.
+	pub fn gradient_fits(&self, histogram: &Histogram, pixels: usize, edges: f64, corners: &[u32; 4]) -> bool {
+		match self.criterion_for(edges, pixels) {
+			// The four most common colors must each cover a quarter of the
+			// majority fraction, and together the whole of it.
+			SplitCriterion::Majority(sensitivity) => {
+				let mut counts = histogram.iter().map(|e| e.1).collect::<Vec<_>>();
+				counts.sort_unstable_by(|a, b| b.cmp(a));
+				counts.into_iter()
+					.take(4)
+					.map(|count| if count > (sensitivity * pixels) / 65536 { count } else { 0 })
+					.sum::<usize>() > (sensitivity * pixels) / 16384
+			},
+			// Every pixel is measured against the nearest corner color.
+			SplitCriterion::Error(limit, metric) => !Self::exceeds(limit, histogram, pixels, |c| corners.iter()
+				.map(|corner| metric.prepared_distance(&self.prepared[c as usize], &self.prepared[*corner as usize]))
+				.fold(f32::MAX, f32::min)),
+		}
+	}
+}
+
+impl std::str::FromStr for ErrorLimit {
+	type Err = UnknownOption;
+
+	// Accepts `rms:N` and `max:N`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let err = || UnknownOption::ErrorLimit(s.to_string());
+		let (measure, max) = s.split_once(':').ok_or_else(err)?;
+		let max = match max.parse::<f32>() {
+			Ok(m) if m >= 0. => m,
+			_ => return Err(err()),
+		};
+		match measure {
+			"rms" => Ok(ErrorLimit::Rms(max)),
+			"max" => Ok(ErrorLimit::MaxDeviation(max)),
+			_ => Err(err()),
+		}
+	}
+}
+
+impl std::fmt::Display for ErrorLimit {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match *self {
+			ErrorLimit::Rms(max) => write!(f, "rms:{}", max),
+			ErrorLimit::MaxDeviation(max) => write!(f, "max:{}", max),
+		}
+	}
+}
+
+impl std::fmt::Display for SplitCriterion {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match *self {
+			SplitCriterion::Majority(sensitivity) => write!(f, "sensitivity {}/16384", sensitivity),
+			SplitCriterion::Error(limit, metric) => write!(f, "max error {} ({:?})", limit, metric),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::node::quantization::palette::DynamicPaletteView;
+
+	// Black and a red 10 `Srgb` units away from it.
+	fn two_reds() -> DynamicPaletteView {
+		DynamicPaletteView::from(vec![image::Rgba([0, 0, 0, 255]), image::Rgba([10, 0, 0, 255])])
+	}
+
+	#[test]
+	fn error_limits_parse_and_print() {
+		assert_eq!("rms:4".parse::<ErrorLimit>().unwrap(), ErrorLimit::Rms(4.));
+		assert_eq!("max:2.5".parse::<ErrorLimit>().unwrap(), ErrorLimit::MaxDeviation(2.5));
+		for limit in [ErrorLimit::Rms(0.), ErrorLimit::MaxDeviation(17.25)] {
+			assert_eq!(limit.to_string().parse::<ErrorLimit>().unwrap(), limit);
+		}
+		for bad in ["rms", "rms:", "rms:-1", "rms:nan", "mse:4", "max:x", ":3"] {
+			assert!(bad.parse::<ErrorLimit>().is_err(), "{}", bad);
+		}
+	}
+
+	#[test]
+	fn histograms_merge_by_index() {
+		let merged = merge_histograms(&vec![(0, 2), (3, 1), (7, 4)], &vec![(1, 5), (3, 2), (9, 1)]);
+		assert_eq!(merged, vec![(0, 2), (1, 5), (3, 3), (7, 4), (9, 1)]);
+		assert_eq!(most_common(&merged), (1, 5));
+		assert_eq!(most_common(&vec![(2, 3), (5, 3)]), (2, 3));
+	}
+
+	#[test]
+	fn majority_splits_below_the_sensitivity() {
+		let palette = two_reds();
+		let histogram = vec![(0, 3), (1, 1)];
+		let judge = SplitJudge::new(SplitCriterion::Majority(12288), &palette, 0.);
+		assert!(!judge.must_split(&histogram, (0, 3), 4, 0.));
+		let judge = SplitJudge::new(SplitCriterion::Majority(16384), &palette, 0.);
+		assert!(judge.must_split(&histogram, (0, 3), 4, 0.));
+	}
+
+	// One pixel of four is 10 away from the leaf color: the root mean square
+	// error is 5 and the worst one 10.
+	#[test]
+	fn error_limits_are_inclusive() {
+		let palette = two_reds();
+		let histogram = vec![(0, 3), (1, 1)];
+		let splits = |limit| SplitJudge::new(SplitCriterion::Error(limit, ColorMetric::Srgb), &palette, 0.)
+			.must_split(&histogram, (0, 3), 4, 0.);
+		assert!(!splits(ErrorLimit::Rms(5.)));
+		assert!(splits(ErrorLimit::Rms(4.9)));
+		assert!(!splits(ErrorLimit::MaxDeviation(10.)));
+		assert!(splits(ErrorLimit::MaxDeviation(9.9)));
+	}
+
+	#[test]
+	fn edges_lower_the_tolerated_error() {
+		let palette = two_reds();
+		let histogram = vec![(0, 3), (1, 1)];
+		let judge = SplitJudge::new(SplitCriterion::Error(ErrorLimit::Rms(9.), ColorMetric::Srgb), &palette, 1.);
+		assert!(!judge.must_split(&histogram, (0, 3), 4, 0.));
+		assert!(judge.must_split(&histogram, (0, 3), 4, 4.));
+	}
+
+	#[test]
+	fn gradients_fit_squares_of_their_corner_colors() {
+		let palette = two_reds();
+		let histogram = vec![(0, 2), (1, 2)];
+		let judge = SplitJudge::new(SplitCriterion::Error(ErrorLimit::MaxDeviation(1.), ColorMetric::Srgb), &palette, 0.);
+		assert!(judge.must_split(&histogram, (0, 2), 4, 0.));
+		assert!(judge.gradient_fits(&histogram, 4, 0., &[0, 1, 1, 0]));
+		assert!(!judge.gradient_fits(&histogram, 4, 0., &[0, 0, 0, 0]));
+	}
+
+	#[test]
+	fn levels_range_from_coarsest_to_finest() {
+		assert_eq!(SplitCriterion::Majority(7).at_level(5), SplitCriterion::Majority(5));
+		assert_eq!(SplitCriterion::Majority(7).at_level(MAX_LEVEL + 1), SplitCriterion::Majority(MAX_LEVEL));
+		let rms = SplitCriterion::Error(ErrorLimit::Rms(3.), ColorMetric::OkLab);
+		assert_eq!(rms.at_level(0), SplitCriterion::Error(ErrorLimit::Rms(1024.), ColorMetric::OkLab));
+		assert_eq!(rms.at_level(MAX_LEVEL - 8), SplitCriterion::Error(ErrorLimit::Rms(0.5), ColorMetric::OkLab));
+		assert_eq!(rms.at_level(MAX_LEVEL), SplitCriterion::Error(ErrorLimit::Rms(0.), ColorMetric::OkLab));
+	}
+}
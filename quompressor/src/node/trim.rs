@@ -0,0 +1,288 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use super::error::UnknownOption;
+use super::quantization::alpha::PremultipliedSum;
+use super::quantization::gamma::Blending;
+use super::quantization::metric::ColorMetric;
+use super::quantization::palette::Palette;
+use super::quantization::PaletteLookup;
+
+// Which color a group of leaves leaves behind when it is trimmed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Survivor {
+	// The color the parent node already has, the most common one of its
+	// whole square.
+	#[default]
+	Parent,
+	// The most common color among the leaves.
+	Majority,
+	// The palette color nearest to the average of the leaves.
+	Average,
+}
+
+// Rules deciding which groups of four (flat) leaves `trim` folds into their
+// parent.
+//
+// A group is trimmed when its parent is at least `min_depth` levels below
+// the root, a single color is the most common among the four leaves and
+// covers at least `majority` of them, and, with a `max_distance`, every leaf
+// is within that `metric` distance of the surviving color.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrimPolicy {
+	pub min_depth: usize,
+	pub majority: usize,
+	pub max_distance: Option<f32>,
+	pub survivor: Survivor,
+	// How distances are measured and nearest palette colors found.
+	pub metric: ColorMetric,
+	// Space `Survivor::Average` averages colors in.
+	pub blending: Blending,
+}
+
+impl Default for TrimPolicy {
+	// Close to the fixed rule `trim` used to have, which only folded groups
+	// where one color covered two or three leaves, and one pass at a time.
+	fn default() -> Self {
+		TrimPolicy {
+			min_depth: 6,
+			majority: 2,
+			max_distance: None,
+			survivor: Survivor::Parent,
+			metric: ColorMetric::Srgb,
+			blending: Blending::Srgb,
+		}
+	}
+}
+
+impl<P: Palette + Default> super::QuadtreeNode<P> {
+	// "Trims" the tree by folding groups of leaf nodes into their parent,
+	// as `policy` allows.
+	//
+	// This runs to a fixed point: parents left as leaves by trimming are
+	// considered in turn, in the same bottom-up pass.
+	pub fn trim(&mut self, palette: &P, policy: &TrimPolicy) {
+		let mut lookup = PaletteLookup::new(palette, policy.metric);
+		self.trim_at(palette, policy, &mut lookup, 0);
+	}
+
+	fn trim_at(&mut self, palette: &P, policy: &TrimPolicy, lookup: &mut PaletteLookup, depth: usize) {
+		let sections = match self.sections {
+			Some(ref mut sections) => sections,
+			None => return
+		};
+		for section in sections.iter_mut() {
+			section.trim_at(palette, policy, lookup, depth + 1);
+		}
+		// Gradient leaves would lose their gradient.
+		if depth < policy.min_depth || sections.iter().any(|s| s.sections.is_some() || s.gradient.is_some()) {
+			return;
+		}
+		// Most common color, which must not be tied.
+		let mut counts = sections.iter()
+			.fold(Vec::<(u32, usize)>::new(), |mut counts, s| {
+				match counts.iter_mut().find(|c| c.0 == s.color) {
+					Some(c) => c.1 += 1,
+					None => counts.push((s.color, 1))
+				}
+				counts
+			});
+		counts.sort_by_key(|c| (std::cmp::Reverse(c.1), c.0));
+		if counts[0].1 < policy.majority || counts.get(1).is_some_and(|c| c.1 == counts[0].1) {
+			return;
+		}
+		let survivor = match policy.survivor {
+			Survivor::Parent => self.color,
+			Survivor::Majority => counts[0].0,
+			Survivor::Average => {
+				let mut sum = PremultipliedSum::new(policy.blending);
+				for section in sections.iter() {
+					match palette.to_rgba(section.color) {
+						Ok(c) => sum.add(&c, 1),
+						Err(_) => return
+					}
+				}
+				lookup.nearest(&sum.average())
+			}
+		};
+		if let Some(max) = policy.max_distance {
+			let survivor_color = match palette.to_rgba(survivor) {
+				Ok(c) => policy.metric.prepare(&c),
+				Err(_) => return
+			};
+			let within = sections.iter().all(|s| match palette.to_rgba(s.color) {
+				Ok(c) => policy.metric.prepared_distance(&policy.metric.prepare(&c), &survivor_color) <= max.powi(2),
+				Err(_) => false
+			});
+			if !within {
+				return;
+			}
+		}
+		self.color = survivor;
+		self.sections = None;
+	}
+}
+
+impl std::str::FromStr for Survivor {
+	type Err = UnknownOption;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"parent" => Ok(Survivor::Parent),
+			"majority" => Ok(Survivor::Majority),
+			"average" => Ok(Survivor::Average),
+			_ => Err(UnknownOption::TrimPolicy(s.to_string())),
+		}
+	}
+}
+
+impl std::str::FromStr for TrimPolicy {
+	type Err = UnknownOption;
+
+	// Accepts `default`, or a comma-separated list of `depth=N`,
+	// `majority=N`, `distance=N` and `keep=parent|majority|average`, the
+	// settings left out keeping their default value. `metric` and
+	// `blending` are left to the caller.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let err = || UnknownOption::TrimPolicy(s.to_string());
+		let mut policy = TrimPolicy::default();
+		if s == "default" {
+			return Ok(policy);
+		}
+		for setting in s.split(',') {
+			let (key, value) = setting.split_once('=').ok_or_else(err)?;
+			match key.trim() {
+				"depth" => policy.min_depth = value.trim().parse().map_err(|_| err())?,
+				"majority" => match value.trim().parse::<usize>() {
+					Ok(m) if (1..=4).contains(&m) => policy.majority = m,
+					_ => return Err(err()),
+				},
+				"distance" => match value.trim().parse::<f32>() {
+					Ok(d) if d >= 0. => policy.max_distance = Some(d),
+					_ => return Err(err()),
+				},
+				"keep" => policy.survivor = value.trim().parse().map_err(|_| err())?,
+				_ => return Err(err()),
+			}
+		}
+		Ok(policy)
+	}
+}
+
+impl std::fmt::Display for Survivor {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match *self {
+			Survivor::Parent => write!(f, "parent"),
+			Survivor::Majority => write!(f, "majority"),
+			Survivor::Average => write!(f, "average"),
+		}
+	}
+}
+
+// Formats the policy the way `from_str` reads it.
+impl std::fmt::Display for TrimPolicy {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "depth={},majority={}", self.min_depth, self.majority)?;
+		if let Some(max) = self.max_distance {
+			write!(f, ",distance={}", max)?;
+		}
+		write!(f, ",keep={}", self.survivor)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::node::QuadtreeNode;
+	use crate::node::quantization::palette::DynamicPaletteView;
+
+	type Node = QuadtreeNode<DynamicPaletteView>;
+
+	fn leaf(color: u32) -> Node {
+		Node { color, ..Default::default() }
+	}
+
+	fn branch(color: u32, sections: [Node; 4]) -> Node {
+		Node { color, sections: Some(Box::new(sections)), ..Default::default() }
+	}
+
+	fn grays() -> DynamicPaletteView {
+		DynamicPaletteView::from((0..4u8).map(|n| image::Rgba([n * 20, n * 20, n * 20, 255])).collect::<Vec<_>>())
+	}
+
+	fn policy(s: &str) -> TrimPolicy {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn policies_parse_and_print() {
+		assert_eq!(policy("default"), TrimPolicy::default());
+		let parsed = policy("depth=4, majority=3,distance=20,keep=average");
+		assert_eq!(parsed, TrimPolicy { min_depth: 4, majority: 3, max_distance: Some(20.), survivor: Survivor::Average, ..Default::default() });
+		assert_eq!(policy(&parsed.to_string()), parsed);
+		assert_eq!(policy(&TrimPolicy::default().to_string()), TrimPolicy::default());
+		for bad in ["", "depth", "depth=x", "majority=0", "majority=5", "distance=-1", "keep=first", "size=2"] {
+			assert!(bad.parse::<TrimPolicy>().is_err(), "{}", bad);
+		}
+	}
+
+	#[test]
+	fn groups_fold_under_a_strict_majority() {
+		let mut tree = branch(2, [leaf(1), leaf(1), leaf(3), leaf(1)]);
+		tree.trim(&grays(), &policy("depth=0,majority=3,keep=majority"));
+		assert!(tree.sections.is_none());
+		assert_eq!(tree.color, 1);
+		// Two pairs of colors have no most common color.
+		let mut tree = branch(2, [leaf(1), leaf(1), leaf(3), leaf(3)]);
+		tree.trim(&grays(), &policy("depth=0,majority=2"));
+		assert!(tree.sections.is_some());
+	}
+
+	#[test]
+	fn shallow_groups_and_gradients_are_kept() {
+		let mut tree = branch(2, [leaf(1), leaf(1), leaf(1), leaf(1)]);
+		tree.trim(&grays(), &policy("depth=1"));
+		assert!(tree.sections.is_some());
+		let mut tree = branch(2, [leaf(1), leaf(1), leaf(1), Node { gradient: Some([0, 1, 2, 3]), ..leaf(1) }]);
+		tree.trim(&grays(), &policy("depth=0"));
+		assert!(tree.sections.is_some());
+	}
+
+	// Folding the lower group leaves its parent a leaf, which then folds too.
+	#[test]
+	fn trimming_runs_to_a_fixed_point() {
+		let mut tree = branch(0, [leaf(0), leaf(0), leaf(2), branch(0, [leaf(0), leaf(0), leaf(0), leaf(1)])]);
+		tree.trim(&grays(), &policy("depth=0,majority=3"));
+		assert!(tree.sections.is_none());
+		assert_eq!(tree.color, 0);
+	}
+
+	#[test]
+	fn survivors_and_distances() {
+		let group = || branch(3, [leaf(0), leaf(0), leaf(0), leaf(2)]);
+		let trimmed = |s: &str| {
+			let mut tree = group();
+			tree.trim(&grays(), &policy(s));
+			tree.sections.is_none().then_some(tree.color)
+		};
+		assert_eq!(trimmed("depth=0,keep=parent"), Some(3));
+		assert_eq!(trimmed("depth=0,keep=majority"), Some(0));
+		// The average is gray 10, nearest to gray 0 (ties going to the lowest index).
+		assert_eq!(trimmed("depth=0,keep=average"), Some(0));
+		// Gray 40 is about 69 away from black.
+		assert_eq!(trimmed("depth=0,keep=majority,distance=70"), Some(0));
+		assert_eq!(trimmed("depth=0,keep=majority,distance=69"), None);
+	}
+}
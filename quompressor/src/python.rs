@@ -0,0 +1,350 @@
+// Copyright 2022 gab
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+// Python FFIs, built with the `python` feature.
+
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple};
+use pyo3::exceptions::{PyRuntimeError, PyTypeError};
+
+use super::*;
+
+// Names `compress` has always taken its positional arguments after `input`
+// and `output` under, in order. All other options are keyword-only.
+const COMPRESS_POSITIONAL: [&str; 7] = ["dedup_", "blur_", "sensitivity_", "trim_", "width_", "to_qim_", "from_qim_"];
+
+// Options of a call, given by keyword (or by position, for the names of
+// `positional`), and taken out one by one as they are read.
+struct Options<'py> {
+	function: &'static str,
+	values: &'py PyDict,
+}
+
+impl<'py> Options<'py> {
+	fn new(
+		py: Python<'py>,
+		function: &'static str,
+		positional: &[&str],
+		args: &'py PyTuple,
+		kwargs: Option<&'py PyDict>
+	) -> PyResult<Self> {
+		if args.len() > positional.len() {
+			return Err(PyTypeError::new_err(format!(
+				"{}() takes at most {} positional options ({} given)", function, positional.len(), args.len()
+			)));
+		}
+		let values = match kwargs {
+			Some(k) => k.copy()?,
+			None => PyDict::new(py)
+		};
+		for (name, value) in positional.iter().zip(args.iter()) {
+			if values.contains(*name)? {
+				return Err(PyTypeError::new_err(format!("{}() got multiple values for `{}`", function, name)));
+			}
+			values.set_item(*name, value)?;
+		}
+		Ok(Options { function, values })
+	}
+
+	// Takes the option `name` out, if it was given and isn't `None`.
+	fn take<T: FromPyObject<'py>>(&self, name: &str) -> PyResult<Option<T>> {
+		let value = match self.values.get_item(name) {
+			Some(v) => v,
+			None => return Ok(None)
+		};
+		self.values.del_item(name)?;
+		if value.is_none() {
+			Ok(None)
+		} else {
+			value.extract().map(Some)
+		}
+	}
+
+	// Same as `take`, for options naming a setting, such as `metric_`.
+	fn parse<T>(&self, name: &str) -> PyResult<Option<T>>
+	where
+		T: std::str::FromStr<Err = UnknownOption>
+	{
+		match self.take::<&str>(name)?.map(str::parse) {
+			None => Ok(None),
+			Some(Ok(parsed)) => Ok(Some(parsed)),
+			Some(Err(e)) => Err(PyRuntimeError::new_err(format!("{}", e)))
+		}
+	}
+
+	// Fails on any option that wasn't taken, which the function doesn't know of.
+	fn finish(self) -> PyResult<()> {
+		match self.values.keys().iter().next() {
+			None => Ok(()),
+			Some(name) => Err(PyTypeError::new_err(format!(
+				"{}() got an unexpected option `{}`", self.function, name
+			)))
+		}
+	}
+}
+
+// Palette generation settings shared by `compress` and `make_palette`.
+fn palette_options(options: &Options) -> PyResult<EncodeOptions> {
+	Ok(EncodeOptions {
+		dedup: options.take::<i32>("dedup_")?.unwrap_or(256) as u32,
+		palette_algo: options.parse("palette_algo_")?.unwrap_or(PaletteAlgorithm::Dedup),
+		metric: options.parse("metric_")?.unwrap_or(ColorMetric::Srgb),
+		max_colors: options.take("max_colors_")?,
+		alpha: options.parse("alpha_")?.unwrap_or(AlphaMode::Full),
+		blending: options.parse("blending_")?.unwrap_or(Blending::Srgb),
+		prefilter: options.parse("prefilter_")?.unwrap_or(PreFilter::None),
+		..Default::default()
+	})
+}
+
+// Settings of `compress`, on top of the palette generation ones.
+fn encode_options(options: &Options) -> PyResult<EncodeOptions> {
+	let settings = palette_options(options)?;
+	let (metric, blending) = (settings.metric, settings.blending);
+	let max_error = options.parse("max_error_")?;
+	let prune = options.take::<f64>("prune_")?;
+	// `trim_` used to count passes; trimming now runs to a fixed point, so
+	// only 0 and 1 are left to turn it off or on.
+	let trim = match (options.parse::<TrimPolicy>("trim_policy_")?, options.take::<i32>("trim_")?) {
+		(Some(policy), _) => Some(policy),
+		(None, None) | (None, Some(0)) => None,
+		(None, Some(1)) => Some(TrimPolicy::default()),
+		(None, Some(_)) => return Err(PyRuntimeError::new_err(
+			"`trim_` no longer counts passes: use 0, 1 or a `trim_policy_`"
+		))
+	}.map(|policy| TrimPolicy { metric, blending, ..policy });
+	Ok(EncodeOptions {
+		blur: options.take::<f64>("blur_")?.map(|b| b as f32),
+		sensitivity: options.take::<i32>("sensitivity_")?.map(|s| ((16384 * s) / (s + 1)) as usize),
+		max_error,
+		edge_weight: options.take::<f64>("edge_weight_")?.unwrap_or(0.) as f32,
+		refit: options.parse("refit_")?,
+		trim,
+		target_size: options.take("target_size_")?,
+		target_quality: options.parse("target_quality_")?,
+		prune,
+		dither: options.parse("dither_")?.unwrap_or(Dither::None),
+		palette_file: options.take("palette_file_")?,
+		reference_palette: options.take("reference_palette_")?.unwrap_or(false),
+		builtin_palette: options.parse("builtin_palette_")?,
+		..settings
+	})
+}
+
+// What `compress_report` wrote, and, when it encoded an image, what with: the
+// split criterion settled on, the size of the QIM data in bytes, and the
+// quality reached when `target_quality_` is set.
+#[pyclass]
+struct CompressionResult {
+	#[pyo3(get)]
+	output: String,
+	#[pyo3(get)]
+	split: Option<String>,
+	#[pyo3(get)]
+	size: Option<usize>,
+	#[pyo3(get)]
+	quality: Option<f64>,
+}
+
+impl CompressionResult {
+	fn encoded(output: String, report: EncodeReport) -> Self {
+		CompressionResult {
+			output,
+			split: Some(report.split.to_string()),
+			size: Some(report.size),
+			quality: report.quality,
+		}
+	}
+}
+
+#[pymethods]
+impl CompressionResult {
+	fn __repr__(&self) -> String {
+		// As Python would write them: `None`, or the bare value.
+		fn or_none<T: std::fmt::Debug>(value: &Option<T>) -> String {
+			value.as_ref().map_or("None".to_string(), |v| format!("{:?}", v))
+		}
+		format!(
+			"CompressionResult(output={:?}, split={}, size={}, quality={})",
+			self.output, or_none(&self.split), or_none(&self.size), or_none(&self.quality)
+		)
+	}
+
+	// Stands for the path written, as `compress` returns it.
+	fn __str__(&self) -> String {
+		self.output.clone()
+	}
+
+	fn __fspath__(&self) -> String {
+		self.output.clone()
+	}
+}
+
+// Converts `input` into `output`, from PNG to QIM with `to_qim_`, from QIM
+// to PNG with `from_qim_`, or from PNG to PNG through a quadtree, and
+// returns the path written.
+//
+// `dedup_`, `blur_`, `sensitivity_`, `trim_`, `width_`, `to_qim_` and
+// `from_qim_` may be given by position, in that order; all other options are
+// keyword-only.
+#[pyfunction(args = "*", kwargs = "**")]
+fn compress(
+	py: Python<'_>,
+	input: String,
+	output: String,
+	args: &PyTuple,
+	kwargs: Option<&PyDict>
+) -> PyResult<String> {
+	run_compress(py, "compress", input, output, args, kwargs).map(|result| result.output)
+}
+
+// Same as `compress`, but returns a `CompressionResult` telling what the
+// output was encoded with.
+#[pyfunction(args = "*", kwargs = "**")]
+fn compress_report(
+	py: Python<'_>,
+	input: String,
+	output: String,
+	args: &PyTuple,
+	kwargs: Option<&PyDict>
+) -> PyResult<CompressionResult> {
+	run_compress(py, "compress_report", input, output, args, kwargs)
+}
+
+fn run_compress(
+	py: Python<'_>,
+	function: &'static str,
+	input: String,
+	output: String,
+	args: &PyTuple,
+	kwargs: Option<&PyDict>
+) -> PyResult<CompressionResult> {
+	let given = Options::new(py, function, &COMPRESS_POSITIONAL, args, kwargs)?;
+	let (width, from_qim, to_qim) = (
+		given.take::<i32>("width_")?.unwrap_or(512) as u32,
+		given.take("from_qim_")?.unwrap_or(false),
+		given.take("to_qim_")?.unwrap_or(false)
+	);
+	let options = encode_options(&given)?;
+	given.finish()?;
+
+	if from_qim && to_qim {
+		return Err(PyRuntimeError::new_err("can not enable `from_qim` and `to_qim` together as they are mutually exclusive parameters"));
+	}
+
+	if from_qim && !to_qim {
+		// `input` is QIM format and `output` is PNG format.
+		// Read QIM file data, recover precomputed quadtree and palette object (not costly) and
+		// write PNG image.
+		let in_file = input.clone();
+		let out_file = output.clone();
+
+		if (input.ends_with(".qim") || input.ends_with(".QIM")) && (output.ends_with(".png") || output.ends_with(".PNG")) {
+			match qim2im(input.as_str(), output.as_str(), width, options.palette_file.as_deref()) {
+				Ok(o) => {
+					return Ok(CompressionResult { output: o, split: None, size: None, quality: None });
+				},
+				Err(e) => {
+					return Err(PyRuntimeError::new_err(e.to_string()));
+				}
+			};
+		}
+		
+		return Err(
+			PyRuntimeError::new_err(
+				format!(
+					"input={}, output={} :  Wrong file extensions for config : from_qim={}, to_qim={}",
+					in_file.as_str(), out_file.as_str(), from_qim, to_qim
+				)
+			)
+		);
+	}
+
+	if !from_qim && to_qim {
+		// `input` is PNG format and `output` is QIM format.
+		// Generate quadtree and palette from input, serialize them into QIM format and write
+		// it on disk for later. Can be interesting to have a DB of precompressed artifacts,
+		// when we do `offline` computation (in a distributed queuing system).
+		let in_file = input.clone();
+		let out_file = output.clone();
+
+		if (input.ends_with(".png") || input.ends_with(".PNG")) && (output.ends_with(".qim") || output.ends_with(".QIM")) {
+			match im2qim_report(input.as_str(), output.as_str(), &options) {
+				Ok(report) => {
+					return Ok(CompressionResult::encoded(output, report))
+				},
+				Err(e) => {
+					return Err(PyRuntimeError::new_err(e.to_string()));
+				}
+			};
+		}
+		return Err(
+			PyRuntimeError::new_err(
+				format!(
+					"input={}, output={} :  Wrong file extensions for config : from_qim={}, to_qim={}",
+					in_file.as_str(), out_file.as_str(), from_qim, to_qim
+				)
+			)
+		);
+	}
+
+	// Else, default case :
+	// `input` is PNG format and `output` is PNG format.
+	// Generate quadtree and palette from input, keep them in mem and write PNG image out of it. 
+	match im2im_report(input.as_str(), output.as_str(), width, &options) {
+		Ok(report) => Ok(CompressionResult::encoded(output, report)),
+		Err(e) => Err(PyRuntimeError::new_err(e.to_string()))
+	}
+}
+
+// Computes one palette shared by all the `inputs` images and writes it to
+// `output`, to be passed as `palette_file_` to `compress`. Takes the palette
+// generation options of `compress`, by keyword.
+#[pyfunction(kwargs = "**")]
+fn make_palette(
+	py: Python<'_>,
+	inputs: Vec<String>,
+	output: String,
+	kwargs: Option<&PyDict>
+) -> PyResult<String> {
+	let given = Options::new(py, "make_palette", &[], PyTuple::empty(py), kwargs)?;
+	let options = palette_options(&given)?;
+	given.finish()?;
+	let inputs = inputs.iter().map(|i| i.as_str()).collect::<Vec<_>>();
+	match generate_shared_palette(&inputs, output.as_str(), &options) {
+		Ok(o) => Ok(o),
+		Err(e) => Err(PyRuntimeError::new_err(e.to_string()))
+	}
+}
+// Extracts the palette of the QIM file `input` into the palette file `output`
+// (`.qpal`, `.gpl`, `.act` or `.pal`).
+#[pyfunction]
+#[pyo3(name = "export_palette")]
+fn py_export_palette(input: String, output: String) -> PyResult<String> {
+	match export_palette(input.as_str(), output.as_str()) {
+		Ok(o) => Ok(o),
+		Err(e) => Err(PyRuntimeError::new_err(e.to_string()))
+	}
+}
+
+#[pymodule]
+fn quompressor(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+	m.add_class::<CompressionResult>()?;
+	m.add_function(wrap_pyfunction!(compress, m)?)?;
+	m.add_function(wrap_pyfunction!(compress_report, m)?)?;
+	m.add_function(wrap_pyfunction!(make_palette, m)?)?;
+	m.add_function(wrap_pyfunction!(py_export_palette, m)?)?;
+	Ok(())
+}